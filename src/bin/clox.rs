@@ -14,4 +14,231 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-fn main() {}
+use std::env::args;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+use rlox::chunk::{self, Function};
+use rlox::compiler;
+use rlox::errors::{ParseError, RloxError};
+use rlox::resolver::Resolver;
+use rlox::scanner::Scanner;
+use rlox::vm::{GcConfig, Vm};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+
+    let mut cmdline: Vec<String> = args().collect();
+
+    // Collect garbage before every allocation, for testing the GC
+    let mut gc_config = GcConfig::default();
+    if let Some(index) = cmdline.iter().position(|arg| arg == "--stress-gc") {
+        cmdline.remove(index);
+        gc_config.stress = true;
+    }
+
+    // Silence a kind of resolver warning, e.g. `--allow unused_variable`
+    let mut resolver = Resolver::new();
+    while let Some(index) = cmdline.iter().position(|arg| arg == "--allow") {
+        cmdline.remove(index);
+        if index == cmdline.len() {
+            return Err(Box::new(RloxError::Cmdline(
+                "Expected a warning code after --allow.".to_string(),
+            )));
+        }
+        resolver.allow(&cmdline.remove(index))?;
+    }
+
+    match cmdline.as_slice() {
+        // No filename, run REPL
+        [_] => run_prompt(&mut resolver, gc_config)?,
+        // Print the compiled bytecode instead of running it
+        [_, flag, filename] if flag == "--disassemble" => {
+            disassemble_file(&mut resolver, filename)?
+        }
+        // Save the compiled bytecode to run later
+        [_, flag, input, output_flag, output] if flag == "--compile" && output_flag == "-o" => {
+            compile_file(&mut resolver, input, output)?
+        }
+        // Filename given
+        [_, filename] if !filename.starts_with("--") => {
+            run_file(&mut resolver, filename, gc_config)?
+        }
+        // Too many or unknown arguments
+        _ => {
+            log::error!("Usage: clox [--stress-gc] [--allow code]... [--disassemble] [script]");
+            log::error!("       clox --compile script.lox -o script.loxc");
+            return Err(Box::new(RloxError::Cmdline(
+                "Invalid arguments.".to_string(),
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Scans, Parses, and compiles input into the top-level script function.
+// Syntax and resolver errors are all reported here, everything else is left to the caller.
+fn compile(resolver: &mut Resolver, filename: &str, input: &str) -> Result<Function, RloxError> {
+    // Lexical analysis
+    let mut scanner = Scanner::new();
+    scanner.scan_tokens(input)?;
+    log::trace!("{:?}", scanner);
+
+    // Syntactic analysis
+    let program = scanner.into_parser().parse();
+
+    // Print all errors we've found from parsing, and stop if there were any
+    let mut errors = program
+        .iter()
+        .filter_map(|res| res.as_ref().err())
+        .peekable();
+    if errors.peek().is_some() {
+        return Err(report_all(filename, input, errors));
+    }
+
+    let program: Result<Vec<_>, RloxError> = program.into_iter().collect();
+    let mut program = program?;
+
+    // Semantic Analysis
+    let resolved = resolver.resolver(&mut program);
+    for warning in resolver.warnings() {
+        eprint!("{}", warning.render(filename, input));
+    }
+    resolved.map_err(|errors| report_all(filename, input, &errors))?;
+
+    // Compile to bytecode
+    compiler::compile(&program)
+}
+
+// Compiles and runs input.
+fn run(vm: &mut Vm, resolver: &mut Resolver, filename: &str, input: &str) -> Result<(), RloxError> {
+    let script = compile(resolver, filename, input)?;
+    vm.interpret(script)
+}
+
+// Reads in either a script, or already compiled bytecode if the file ends in .loxc
+// The script's source is handed back too, so errors can point into it.
+fn load_file(
+    resolver: &mut Resolver,
+    filename: &str,
+) -> Result<(Function, String), Box<dyn Error>> {
+    if Path::new(filename)
+        .extension()
+        .map_or(false, |ext| ext == "loxc")
+    {
+        let bytes = fs::read(filename)?;
+        return match chunk::deserialize(&bytes) {
+            Ok(script) => Ok((script, String::new())),
+            Err(err) => report_and_exit(filename, "", &err),
+        };
+    }
+
+    let file_handle = File::open(filename)?;
+    let buf = io::read_to_string(file_handle)?;
+    match compile(resolver, filename, &buf) {
+        Ok(script) => Ok((script, buf)),
+        Err(err) => report_and_exit(filename, &buf, &err),
+    }
+}
+
+// Reads a file in and prints its bytecode.
+fn disassemble_file(resolver: &mut Resolver, filename: &str) -> Result<(), Box<dyn Error>> {
+    let (script, _) = load_file(resolver, filename)?;
+    print!("{}", chunk::disassemble(&script));
+    Ok(())
+}
+
+// Compiles a script and writes its bytecode out to a file.
+fn compile_file(resolver: &mut Resolver, input: &str, output: &str) -> Result<(), Box<dyn Error>> {
+    let file_handle = File::open(input)?;
+    let buf = io::read_to_string(file_handle)?;
+
+    let script = match compile(resolver, input, &buf) {
+        Ok(script) => script,
+        Err(err) => report_and_exit(input, &buf, &err),
+    };
+    fs::write(output, chunk::serialize(&script))?;
+    Ok(())
+}
+
+// Reads a file in and runs it.
+fn run_file(
+    resolver: &mut Resolver,
+    filename: &str,
+    gc_config: GcConfig,
+) -> Result<(), Box<dyn Error>> {
+    let (script, source) = load_file(resolver, filename)?;
+
+    let mut vm = Vm::with_gc_config(gc_config);
+    if let Err(err) = vm.interpret(script) {
+        report_and_exit(filename, &source, &err);
+    }
+    Ok(())
+}
+
+// Prints every error, and sums them up in one error to stop with
+fn report_all<'a>(
+    filename: &str,
+    input: &str,
+    errors: impl IntoIterator<Item = &'a RloxError>,
+) -> RloxError {
+    let mut count = 0;
+    for err in errors {
+        eprint!("{}", err.render(filename, input));
+        count += 1;
+    }
+
+    RloxError::Parse(
+        ParseError::ParseFailure(format!("aborting due to {count} previous error(s)")),
+        None,
+    )
+}
+
+// Prints an error pointing into the source, then exits with
+// 65 for errors in the script itself, or 70 for errors while running it.
+fn report_and_exit(filename: &str, source: &str, err: &RloxError) -> ! {
+    eprint!("{}", err.render(filename, source));
+    match err {
+        RloxError::Interpret(..) => process::exit(70),
+        _ => process::exit(65),
+    }
+}
+
+// Interactive REPL prompt.
+// Runs code line-by-line.
+fn run_prompt(resolver: &mut Resolver, gc_config: GcConfig) -> Result<(), Box<dyn Error>> {
+    let stdin = io::stdin();
+    let mut vm = Vm::with_gc_config(gc_config);
+
+    loop {
+        let mut buf = String::new();
+
+        // Flush prompt to screen.
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        // Read in user's input
+        let line = stdin.read_line(&mut buf);
+
+        // Break out of the REPL when Control-D is pressed.
+        match line {
+            Ok(0) => break,
+            Ok(1) => println!("\nUse ^D to close REPL."),
+            Ok(_) => (),
+            // Ignore any errors for now.
+            Err(e) => print!("error: {e:?}"),
+        }
+
+        // Run user's input
+        // Don't kill the user's session if they make a mistake.
+        if let Err(err) = run(&mut vm, resolver, "<stdin>", &buf) {
+            eprint!("{}", err.render("<stdin>", &buf));
+        }
+    }
+
+    Ok(())
+}
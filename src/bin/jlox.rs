@@ -18,36 +18,73 @@ use std::env::args;
 use std::error::Error;
 use std::fs::File;
 use std::io::{self, Write};
+use std::panic;
+use std::process;
+use std::thread;
 
 use rlox::errors::{ParseError, RloxError};
 use rlox::interpreter::Interpreter;
 use rlox::resolver::Resolver;
 use rlox::scanner::Scanner;
 
-fn main() -> Result<(), Box<dyn Error>> {
+type BoxError = Box<dyn Error + Send + Sync>;
+
+// Every Lox call recurses through the interpreter, so run it on a thread
+// with room to reach the same call limit as clox, rather than overflowing the
+// native stack.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+const MAX_CALL_DEPTH: usize = 1024;
+
+fn main() -> Result<(), BoxError> {
     env_logger::init();
 
-    let cmdline: Vec<String> = args().collect();
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(jlox)?
+        .join()
+        .unwrap_or_else(|panic| panic::resume_unwind(panic))
+}
+
+fn jlox() -> Result<(), BoxError> {
+    let mut cmdline: Vec<String> = args().collect();
+
+    // Silence a kind of resolver warning, e.g. `--allow unused_variable`
+    let mut resolver = Resolver::new();
+    while let Some(index) = cmdline.iter().position(|arg| arg == "--allow") {
+        cmdline.remove(index);
+        if index == cmdline.len() {
+            return Err(Box::new(RloxError::Cmdline(
+                "Expected a warning code after --allow.".to_string(),
+            )));
+        }
+        resolver.allow(&cmdline.remove(index))?;
+    }
 
     match cmdline.len() {
         // Too many arguments
         len if len > 2 => {
-            log::error!("Usage: jlox [script]");
+            log::error!("Usage: jlox [--allow code]... [script]");
             return Err(Box::new(RloxError::Cmdline(
                 "Too many arguments.".to_string(),
             )));
         }
         // Filename given
-        len if len == 2 => run_file(&cmdline[1])?,
+        2 => run_file(&mut resolver, &cmdline[1])?,
         // No filename, run REPL
-        _ => run_prompt()?,
+        _ => run_prompt(&mut resolver)?,
     }
 
     Ok(())
 }
 
 // Scans, Parses, and evaluates input.
-fn run(interpreter: &mut Interpreter, input: &str) -> Result<(), RloxError> {
+// Syntax and resolver errors are all reported here, everything else is left to the caller.
+fn run(
+    interpreter: &mut Interpreter,
+    resolver: &mut Resolver,
+    filename: &str,
+    input: &str,
+) -> Result<(), RloxError> {
     // TODO: Clean this up.
     // Lexical analysis
     let mut scanner = Scanner::new();
@@ -58,19 +95,13 @@ fn run(interpreter: &mut Interpreter, input: &str) -> Result<(), RloxError> {
     // Parse the input and evaluate expressions
     let program = scanner.into_parser().parse();
 
-    // Print all errors we've found from parsing
-    for res in &program {
-        if let Err(err) = res {
-            log::error!("{}", err);
-        }
-    }
-
-    // Exit if we've found any errors
-    let has_error = program.iter().any(|i| i.is_err());
-    if has_error {
-        return Err(RloxError::Parse(ParseError::ParseFailure(
-            "Exiting.".to_string(),
-        )));
+    // Print all errors we've found from parsing, and stop if there were any
+    let mut errors = program
+        .iter()
+        .filter_map(|res| res.as_ref().err())
+        .peekable();
+    if errors.peek().is_some() {
+        return Err(report_all(filename, input, errors));
     }
 
     // Collect just the successful parses.
@@ -80,7 +111,11 @@ fn run(interpreter: &mut Interpreter, input: &str) -> Result<(), RloxError> {
 
     // Semantic Analysis
 
-    Resolver::new().resolver(&mut program)?;
+    let resolved = resolver.resolver(&mut program);
+    for warning in resolver.warnings() {
+        eprint!("{}", warning.render(filename, input));
+    }
+    resolved.map_err(|errors| report_all(filename, input, &errors))?;
 
     // debugging
     for stmt in &program {
@@ -92,20 +127,52 @@ fn run(interpreter: &mut Interpreter, input: &str) -> Result<(), RloxError> {
 }
 
 // Reads a file in and runs it.
-fn run_file(filename: &str) -> Result<(), Box<dyn Error>> {
+fn run_file(resolver: &mut Resolver, filename: &str) -> Result<(), BoxError> {
     let file_handle = File::open(filename)?;
     let buf = io::read_to_string(file_handle)?;
 
     let mut interpreter = Interpreter::new();
-    run(&mut interpreter, &buf)?;
+    interpreter.set_max_call_depth(MAX_CALL_DEPTH);
+    if let Err(err) = run(&mut interpreter, resolver, filename, &buf) {
+        report_and_exit(filename, &buf, &err);
+    }
     Ok(())
 }
 
+// Prints every error, and sums them up in one error to stop with
+fn report_all<'a>(
+    filename: &str,
+    input: &str,
+    errors: impl IntoIterator<Item = &'a RloxError>,
+) -> RloxError {
+    let mut count = 0;
+    for err in errors {
+        eprint!("{}", err.render(filename, input));
+        count += 1;
+    }
+
+    RloxError::Parse(
+        ParseError::ParseFailure(format!("aborting due to {count} previous error(s)")),
+        None,
+    )
+}
+
+// Prints an error pointing into the source, then exits with
+// 65 for errors in the script itself, or 70 for errors while running it.
+fn report_and_exit(filename: &str, source: &str, err: &RloxError) -> ! {
+    eprint!("{}", err.render(filename, source));
+    match err {
+        RloxError::Interpret(..) => process::exit(70),
+        _ => process::exit(65),
+    }
+}
+
 // Interactive REPL prompt.
 // Runs code line-by-line.
-fn run_prompt() -> Result<(), Box<dyn Error>> {
+fn run_prompt(resolver: &mut Resolver) -> Result<(), BoxError> {
     let stdin = io::stdin();
     let mut interpreter = Interpreter::new();
+    interpreter.set_max_call_depth(MAX_CALL_DEPTH);
 
     loop {
         let mut buf = String::new();
@@ -128,8 +195,8 @@ fn run_prompt() -> Result<(), Box<dyn Error>> {
 
         // Run user's input
         // Don't kill the user's session if they make a mistake.
-        if let Err(err) = run(&mut interpreter, &buf) {
-            log::error!("{err}");
+        if let Err(err) = run(&mut interpreter, resolver, "<stdin>", &buf) {
+            eprint!("{}", err.render("<stdin>", &buf));
         }
     }
 
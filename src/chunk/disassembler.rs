@@ -0,0 +1,289 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Human-readable listings of compiled bytecode.
+//
+// Each instruction is printed on its own line as:
+//   offset  line  opcode  operands
+// where the line is replaced by "|" when it's the same as the previous
+// instruction's. Nested functions are listed after the function that
+// contains them.
+
+use std::fmt::Write;
+
+use super::{Chunk, Constant, Function, OpCode};
+
+// Disassemble a function and every function nested inside of it.
+pub fn disassemble(function: &Function) -> String {
+    let mut out = String::new();
+    disassemble_function(&mut out, function);
+    out
+}
+
+fn disassemble_function(out: &mut String, function: &Function) {
+    let name = match function.name.as_str() {
+        "" => "<script>",
+        name => name,
+    };
+    out.push_str(&disassemble_chunk(&function.chunk, name));
+
+    for constant in &function.chunk.constants {
+        if let Constant::Function(nested) = constant {
+            out.push('\n');
+            disassemble_function(out, nested);
+        }
+    }
+}
+
+// Disassemble a single chunk, without descending into nested functions.
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "== {name} ==").unwrap();
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(&mut out, chunk, offset);
+    }
+
+    out
+}
+
+// Writes the instruction at `offset`, returns the offset of the next one.
+pub fn disassemble_instruction(out: &mut String, chunk: &Chunk, offset: usize) -> usize {
+    write!(out, "{offset:04} ").unwrap();
+    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+        write!(out, "   | ").unwrap();
+    } else {
+        write!(out, "{:4} ", chunk.lines[offset]).unwrap();
+    }
+
+    let op = match OpCode::from_byte(chunk.code[offset]) {
+        Some(op) => op,
+        None => {
+            writeln!(out, "Unknown opcode {}", chunk.code[offset]).unwrap();
+            return offset + 1;
+        }
+    };
+
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => constant_instruction(out, op, chunk, offset),
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => byte_instruction(out, op, chunk, offset),
+        OpCode::Jump
+        | OpCode::JumpIfFalse
+        | OpCode::ForNext
+        | OpCode::ForCheck
+        | OpCode::PushHandler => jump_instruction(out, op, 1, chunk, offset),
+        OpCode::Loop => jump_instruction(out, op, -1, chunk, offset),
+        OpCode::BuildList | OpCode::BuildMap => short_instruction(out, op, chunk, offset),
+        OpCode::Invoke | OpCode::SuperInvoke => invoke_instruction(out, op, chunk, offset),
+        OpCode::Closure => closure_instruction(out, op, chunk, offset),
+        OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Pop
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::CloseUpvalue
+        | OpCode::Return
+        | OpCode::Inherit
+        | OpCode::GetIndex
+        | OpCode::SetIndex
+        | OpCode::GetIterator
+        | OpCode::PopHandler
+        | OpCode::Throw
+        | OpCode::Stringify => {
+            writeln!(out, "{op:?}").unwrap();
+            offset + 1
+        }
+    }
+}
+
+fn constant_instruction(out: &mut String, op: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let index = chunk.read_u16(offset + 1);
+    writeln!(
+        out,
+        "{:<16} {:4} {}",
+        format!("{op:?}"),
+        index,
+        format_constant(chunk, index)
+    )
+    .unwrap();
+    offset + 3
+}
+
+fn byte_instruction(out: &mut String, op: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let operand = chunk.code[offset + 1];
+    writeln!(out, "{:<16} {:4}", format!("{op:?}"), operand).unwrap();
+    offset + 2
+}
+
+fn short_instruction(out: &mut String, op: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let operand = chunk.read_u16(offset + 1);
+    writeln!(out, "{:<16} {:4}", format!("{op:?}"), operand).unwrap();
+    offset + 3
+}
+
+fn jump_instruction(
+    out: &mut String,
+    op: OpCode,
+    sign: isize,
+    chunk: &Chunk,
+    offset: usize,
+) -> usize {
+    let jump = chunk.read_u16(offset + 1) as isize;
+    let target = offset as isize + 3 + sign * jump;
+    writeln!(out, "{:<16} {:4} -> {}", format!("{op:?}"), offset, target).unwrap();
+    offset + 3
+}
+
+fn invoke_instruction(out: &mut String, op: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let index = chunk.read_u16(offset + 1);
+    let argc = chunk.code[offset + 3];
+    writeln!(
+        out,
+        "{:<16} ({} args) {:4} {}",
+        format!("{op:?}"),
+        argc,
+        index,
+        format_constant(chunk, index)
+    )
+    .unwrap();
+    offset + 4
+}
+
+// Closures list what each of their upvalues captures on the lines that follow
+fn closure_instruction(out: &mut String, op: OpCode, chunk: &Chunk, offset: usize) -> usize {
+    let mut next = constant_instruction(out, op, chunk, offset);
+
+    let index = chunk.read_u16(offset + 1);
+    if let Some(Constant::Function(function)) = chunk.constants.get(index as usize) {
+        for _ in 0..function.upvalue_count {
+            let kind = match chunk.code[next] {
+                1 => "local",
+                _ => "upvalue",
+            };
+            let index = chunk.code[next + 1];
+            writeln!(out, "{next:04}    | {:<16} {kind} {index}", "").unwrap();
+            next += 2;
+        }
+    }
+
+    next
+}
+
+fn format_constant(chunk: &Chunk, index: u16) -> String {
+    match chunk.constants.get(index as usize) {
+        Some(Constant::Number(n)) => format!("'{n}'"),
+        Some(Constant::String(s)) => format!("'{s}'"),
+        Some(Constant::Function(function)) => format!("<fn {}>", function.name),
+        None => "<invalid constant>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_instructions() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Constant::Number(1.5)) as u16;
+        chunk.write_op(OpCode::Constant, 1);
+        chunk.write_u16(constant, 1);
+        chunk.write_op(OpCode::JumpIfFalse, 2);
+        chunk.write_u16(1, 2);
+        chunk.write_op(OpCode::Negate, 2);
+        chunk.write_op(OpCode::Loop, 3);
+        chunk.write_u16(10, 3);
+
+        assert_eq!(
+            disassemble_chunk(&chunk, "test"),
+            "== test ==\n\
+             0000    1 Constant            0 '1.5'\n\
+             0003    2 JumpIfFalse         3 -> 7\n\
+             0006    | Negate\n\
+             0007    3 Loop                7 -> 0\n"
+        );
+    }
+
+    #[test]
+    fn listing_nested_functions() {
+        let mut inner = Function {
+            name: "inner".to_string(),
+            ..Function::default()
+        };
+        inner.chunk.write_op(OpCode::Nil, 2);
+        inner.chunk.write_op(OpCode::Return, 2);
+
+        let mut script = Function::default();
+        let constant = script
+            .chunk
+            .add_constant(Constant::Function(std::rc::Rc::new(inner)));
+        script.chunk.write_op(OpCode::Constant, 1);
+        script.chunk.write_u16(constant as u16, 1);
+
+        let listing = disassemble(&script);
+        assert!(listing.starts_with("== <script> ==\n0000    1 Constant            0 <fn inner>\n"));
+        assert!(listing.ends_with("== inner ==\n0000    2 Nil\n0001    | Return\n"));
+    }
+
+    #[test]
+    fn listing_closure_upvalues() {
+        let inner = Function {
+            name: "inner".to_string(),
+            upvalue_count: 2,
+            ..Function::default()
+        };
+
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Constant::Function(std::rc::Rc::new(inner)));
+        chunk.write_op(OpCode::Closure, 1);
+        chunk.write_u16(constant as u16, 1);
+        chunk.write(1, 1);
+        chunk.write(3, 1);
+        chunk.write(0, 1);
+        chunk.write(0, 1);
+        chunk.write_op(OpCode::CloseUpvalue, 2);
+
+        assert_eq!(
+            disassemble_chunk(&chunk, "test"),
+            "== test ==\n\
+             0000    1 Closure             0 <fn inner>\n\
+             0003    |                  local 3\n\
+             0005    |                  upvalue 0\n\
+             0007    2 CloseUpvalue\n"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The bytecode specification for clox.
+//
+// A compiled program is a tree of `Function` prototypes. Each prototype owns a
+// `Chunk`: a flat array of bytes made up of opcodes and their operands, a
+// parallel table of source lines, and a table of constants. Constants are plain
+// data, they're turned into runtime values by the VM when a function is loaded.
+//
+// Operands are encoded big-endian directly after their opcode:
+//   u8:  local slots, upvalue indices, argument counts
+//   u16: constant indices, jump offsets, list and map lengths
+//
+// `Closure` is the one variable-length instruction: its function constant is
+// followed by a pair of u8s for each of the function's upvalues, saying whether
+// it captures a local of the enclosing function (1) or one of the enclosing
+// function's own upvalues (0), and that local's slot or upvalue's index.
+
+mod disassembler;
+mod serialize;
+
+use std::rc::Rc;
+
+pub use disassembler::{disassemble, disassemble_chunk, disassemble_instruction};
+pub use serialize::{deserialize, serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Constant,     // u16 constant
+    Nil,          //
+    True,         //
+    False,        //
+    Pop,          //
+    GetLocal,     // u8 slot
+    SetLocal,     // u8 slot
+    GetGlobal,    // u16 name constant
+    DefineGlobal, // u16 name constant
+    SetGlobal,    // u16 name constant
+    GetUpvalue,   // u8 upvalue index
+    SetUpvalue,   // u8 upvalue index
+    GetProperty,  // u16 name constant
+    SetProperty,  // u16 name constant
+    GetSuper,     // u16 name constant
+    Equal,        //
+    Greater,      //
+    Less,         //
+    Add,          //
+    Subtract,     //
+    Multiply,     //
+    Divide,       //
+    Not,          //
+    Negate,       //
+    Jump,         // u16 forward offset
+    JumpIfFalse,  // u16 forward offset
+    Loop,         // u16 backward offset
+    Call,         // u8 argument count
+    Invoke,       // u16 name constant, u8 argument count
+    SuperInvoke,  // u16 name constant, u8 argument count
+    Closure,      // u16 function constant, (u8 is_local, u8 index) per upvalue
+    CloseUpvalue, //
+    Return,       //
+    Class,        // u16 name constant
+    Inherit,      //
+    Method,       // u16 name constant
+    BuildList,    // u16 element count
+    GetIndex,     //
+    SetIndex,     //
+    BuildMap,     // u16 entry count
+    GetIterator,  //
+    ForNext,      // u16 forward offset
+    ForCheck,     // u16 forward offset
+    PushHandler,  // u16 forward offset to the handler
+    PopHandler,   //
+    Throw,        //
+    Stringify,    //
+}
+
+impl OpCode {
+    // Every opcode, indexed by its byte value.
+    const ALL: [OpCode; 47] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::Less,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Invoke,
+        OpCode::SuperInvoke,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::BuildList,
+        OpCode::GetIndex,
+        OpCode::SetIndex,
+        OpCode::BuildMap,
+        OpCode::GetIterator,
+        OpCode::ForNext,
+        OpCode::ForCheck,
+        OpCode::PushHandler,
+        OpCode::PopHandler,
+        OpCode::Throw,
+        OpCode::Stringify,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        Self::ALL.get(byte as usize).copied()
+    }
+}
+
+// Constants that can be stored in a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(String),
+    Function(Rc<Function>),
+}
+
+// A compiled function prototype.
+// The top-level script is a function with an empty name and no parameters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub arity: u8,
+    pub upvalue_count: u8,
+    pub chunk: Chunk,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub lines: Vec<usize>, // source line of each byte in `code`
+    pub constants: Vec<Constant>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, byte: u8, line: usize) {
+        self.code.push(byte);
+        self.lines.push(line);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, line: usize) {
+        self.write(op as u8, line);
+    }
+
+    pub fn write_u16(&mut self, value: u16, line: usize) {
+        let [high, low] = value.to_be_bytes();
+        self.write(high, line);
+        self.write(low, line);
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    // Returns the index of the new constant.
+    // Strings are de-duplicated, since every global and property name is one.
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        if let Constant::String(_) = constant {
+            if let Some(index) = self.constants.iter().position(|c| *c == constant) {
+                return index;
+            }
+        }
+
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_round_trip() {
+        for (byte, op) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(*op as u8, byte as u8);
+            assert_eq!(OpCode::from_byte(byte as u8), Some(*op));
+        }
+        assert_eq!(OpCode::from_byte(OpCode::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn writing_chunks() {
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::Constant, 1);
+        chunk.write_u16(0x1234, 1);
+        chunk.write_op(OpCode::Return, 2);
+
+        assert_eq!(
+            chunk.code,
+            vec![OpCode::Constant as u8, 0x12, 0x34, OpCode::Return as u8]
+        );
+        assert_eq!(chunk.lines, vec![1, 1, 1, 2]);
+        assert_eq!(chunk.read_u16(1), 0x1234);
+    }
+
+    #[test]
+    fn string_constants_are_shared() {
+        let mut chunk = Chunk::new();
+        let a = chunk.add_constant(Constant::String("a".into()));
+        let b = chunk.add_constant(Constant::Number(1.));
+        let c = chunk.add_constant(Constant::String("a".into()));
+        let d = chunk.add_constant(Constant::Number(1.));
+
+        assert_eq!(a, c);
+        assert_ne!(b, d);
+    }
+}
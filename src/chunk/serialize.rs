@@ -0,0 +1,770 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The `.loxc` file format for compiled scripts.
+//
+// All integers are little-endian.
+//
+//   file     -> magic version function
+//   magic    -> "LOXC"
+//   version  -> u16
+//   function -> string(name) u8(arity) u8(upvalues) u32(len) byte*len lines constants
+//   lines    -> u32(runs) ( u32(line) u32(count) )*runs
+//   constants-> u32(len) constant*len
+//   constant -> 0x00 f64 | 0x01 string | 0x02 function
+//   string   -> u32(len) utf8*len
+//
+// The line table is run-length encoded, since almost every instruction
+// shares its line with the one before it.
+//
+// Loading validates the whole file: besides the structure above, every
+// instruction must be a known opcode with all of its operands present, every
+// constant operand must be in range (and be a string where a name is expected),
+// every jump must land on an instruction, and every upvalue index must be in
+// range of the function it's used in. Every path through a function is also
+// followed, to check that the stack never underflows, local slots are on the
+// stack, handlers are pushed and popped in pairs, and the code never runs off
+// the end.
+
+use std::rc::Rc;
+
+use super::{Chunk, Constant, Function, OpCode};
+use crate::errors::{BytecodeError, Result, RloxError};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 6;
+
+// Deeper nesting than this is rejected rather than risking the native stack
+const MAX_NESTING: usize = 256;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+pub fn serialize(function: &Function) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    write_function(&mut out, function);
+    out
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<Function> {
+    let mut reader = Reader { bytes, offset: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(error(BytecodeError::BadMagic));
+    }
+
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(error(BytecodeError::UnsupportedVersion(version)));
+    }
+
+    let function = reader.function(0)?;
+
+    // The VM calls the script with no arguments, and nothing to capture
+    if function.arity != 0 || function.upvalue_count != 0 {
+        return Err(error(BytecodeError::InvalidScript));
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(error(BytecodeError::TrailingData));
+    }
+
+    Ok(function)
+}
+
+//
+// Writing
+//
+
+fn write_function(out: &mut Vec<u8>, function: &Function) {
+    write_string(out, &function.name);
+    out.push(function.arity);
+    out.push(function.upvalue_count);
+
+    write_u32(out, function.chunk.code.len());
+    out.extend_from_slice(&function.chunk.code);
+
+    // Run-length encode the line table
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for line in &function.chunk.lines {
+        match runs.last_mut() {
+            Some((last, count)) if last == line => *count += 1,
+            _ => runs.push((*line, 1)),
+        }
+    }
+    write_u32(out, runs.len());
+    for (line, count) in runs {
+        write_u32(out, line);
+        write_u32(out, count);
+    }
+
+    write_u32(out, function.chunk.constants.len());
+    for constant in &function.chunk.constants {
+        match constant {
+            Constant::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Constant::String(s) => {
+                out.push(TAG_STRING);
+                write_string(out, s);
+            }
+            Constant::Function(function) => {
+                out.push(TAG_FUNCTION);
+                write_function(out, function);
+            }
+        }
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("chunks are limited to u32::MAX bytes");
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    write_u32(out, string.len());
+    out.extend_from_slice(string.as_bytes());
+}
+
+//
+// Reading
+//
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| error(BytecodeError::Truncated))?;
+
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| error(BytecodeError::InvalidUtf8))
+    }
+
+    fn function(&mut self, depth: usize) -> Result<Function> {
+        if depth > MAX_NESTING {
+            return Err(error(BytecodeError::NestingTooDeep));
+        }
+
+        let name = self.string()?;
+        let arity = self.u8()?;
+        let upvalue_count = self.u8()?;
+
+        let len = self.u32()?;
+        let code = self.take(len)?.to_vec();
+
+        let mut lines = Vec::with_capacity(code.len());
+        let runs = self.u32()?;
+        for _ in 0..runs {
+            let line = self.u32()?;
+            let count = self.u32()?;
+            if lines.len() + count > code.len() {
+                return Err(error(BytecodeError::LineTableMismatch));
+            }
+            lines.extend(std::iter::repeat(line).take(count));
+        }
+        if lines.len() != code.len() {
+            return Err(error(BytecodeError::LineTableMismatch));
+        }
+
+        let len = self.u32()?;
+        let mut constants = vec![];
+        for _ in 0..len {
+            let constant = match self.u8()? {
+                TAG_NUMBER => Constant::Number(self.f64()?),
+                TAG_STRING => Constant::String(self.string()?),
+                TAG_FUNCTION => Constant::Function(Rc::new(self.function(depth + 1)?)),
+                tag => return Err(error(BytecodeError::InvalidConstant(tag))),
+            };
+            constants.push(constant);
+        }
+
+        let chunk = Chunk {
+            code,
+            lines,
+            constants,
+        };
+        let function = Function {
+            name,
+            arity,
+            upvalue_count,
+            chunk,
+        };
+        verify(&function)?;
+
+        Ok(function)
+    }
+}
+
+// Walk every instruction, checking its operands against the chunk
+fn verify(function: &Function) -> Result<()> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let mut offset = 0;
+
+    // The length of the instruction starting at each offset, or 0 inside one
+    let mut sizes = vec![0; code.len()];
+
+    while offset < code.len() {
+        let op = OpCode::from_byte(code[offset])
+            .ok_or_else(|| error(BytecodeError::InvalidOpcode(code[offset], offset)))?;
+
+        let operands = match op {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::ForNext
+            | OpCode::ForCheck
+            | OpCode::PushHandler
+            | OpCode::Loop
+            | OpCode::BuildList
+            | OpCode::BuildMap => 2,
+            OpCode::Invoke | OpCode::SuperInvoke => 3,
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => 1,
+            OpCode::Closure => match closure_function(chunk, offset) {
+                Some(function) => 2 + 2 * function.upvalue_count as usize,
+                None => return Err(error(BytecodeError::InvalidOperand(offset))),
+            },
+            _ => 0,
+        };
+
+        let next = offset + 1 + operands;
+        if next > code.len() {
+            return Err(error(BytecodeError::InvalidOperand(offset)));
+        }
+
+        match op {
+            OpCode::Constant => match chunk.constants.get(chunk.read_u16(offset + 1) as usize) {
+                Some(_) => (),
+                None => return Err(error(BytecodeError::InvalidOperand(offset))),
+            },
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Class
+            | OpCode::GetSuper
+            | OpCode::Method
+            | OpCode::Invoke
+            | OpCode::SuperInvoke => match chunk.constants.get(chunk.read_u16(offset + 1) as usize)
+            {
+                Some(Constant::String(_)) => (),
+                _ => return Err(error(BytecodeError::InvalidOperand(offset))),
+            },
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::ForNext
+            | OpCode::ForCheck
+            | OpCode::PushHandler
+                if next + chunk.read_u16(offset + 1) as usize > code.len() =>
+            {
+                return Err(error(BytecodeError::InvalidOperand(offset)));
+            }
+            OpCode::Loop if chunk.read_u16(offset + 1) as usize > next => {
+                return Err(error(BytecodeError::InvalidOperand(offset)));
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue
+                if code[offset + 1] >= function.upvalue_count =>
+            {
+                return Err(error(BytecodeError::InvalidOperand(offset)));
+            }
+            OpCode::Closure => {
+                // Captured upvalues must be in range of this function
+                for pair in code[offset + 3..next].chunks(2) {
+                    let valid = match pair[0] {
+                        0 => pair[1] < function.upvalue_count,
+                        1 => true,
+                        _ => false,
+                    };
+                    if !valid {
+                        return Err(error(BytecodeError::InvalidOperand(offset)));
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        sizes[offset] = next - offset;
+        offset = next;
+    }
+
+    verify_stack(function, &sizes)
+}
+
+// The stack depth and the handlers in place when an instruction starts.
+// The depth counts the callee's slot, under the function's arguments.
+#[derive(Clone, PartialEq)]
+struct State {
+    depth: usize,
+    handlers: Vec<usize>, // the depth each handler unwinds the stack to
+}
+
+// Follow every path through the code, so the VM can trust that the stack never
+// underflows, locals are in range, and handlers are pushed and popped in pairs.
+// Paths that meet must agree on the state.
+fn verify_stack(function: &Function, sizes: &[usize]) -> Result<()> {
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    let mut pending = vec![];
+
+    if code.is_empty() {
+        return Err(error(BytecodeError::FallsOffEnd));
+    }
+    let entry = State {
+        depth: function.arity as usize + 1,
+        handlers: vec![],
+    };
+    enter(&mut states, &mut pending, sizes, 0, 0, entry)?;
+
+    while let Some(offset) = pending.pop() {
+        let state = states[offset].clone().unwrap();
+        let next = offset + sizes[offset];
+        let op = OpCode::from_byte(code[offset]).unwrap();
+        let jump = || chunk.read_u16(offset + 1) as usize;
+        let byte = || code[offset + 1] as usize;
+        let argc = || code[offset + 3] as usize;
+
+        let (pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Class
+            | OpCode::Closure
+            | OpCode::GetLocal => (0, 1),
+            OpCode::Pop | OpCode::DefineGlobal | OpCode::CloseUpvalue => (1, 0),
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Stringify
+            | OpCode::JumpIfFalse
+            | OpCode::ForCheck => (1, 1),
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method
+            | OpCode::GetIndex => (2, 1),
+            OpCode::SetIndex => (3, 1),
+            OpCode::Call => (byte() + 1, 1),
+            OpCode::Invoke => (argc() + 1, 1),
+            OpCode::SuperInvoke => (argc() + 2, 1),
+            OpCode::BuildList => (jump(), 1),
+            OpCode::BuildMap => (2 * jump(), 1),
+            OpCode::GetIterator => (1, 2),
+            OpCode::ForNext => (2, 3),
+            OpCode::Return | OpCode::Throw => (1, 0),
+            OpCode::Jump | OpCode::Loop | OpCode::PushHandler | OpCode::PopHandler => (0, 0),
+        };
+
+        // Nothing can be popped out from under the innermost handler,
+        // or the function's own slot
+        let floor = state.handlers.last().copied().unwrap_or(1);
+        if state.depth < floor + pops {
+            return Err(error(BytecodeError::InvalidStack(offset)));
+        }
+        let mut handlers = state.handlers.clone();
+        match op {
+            OpCode::PushHandler => handlers.push(state.depth),
+            OpCode::PopHandler => {
+                handlers.pop();
+            }
+            _ => (),
+        }
+        let after = State {
+            depth: state.depth - pops + pushes,
+            handlers,
+        };
+
+        match op {
+            OpCode::GetLocal | OpCode::SetLocal if byte() >= state.depth => {
+                return Err(error(BytecodeError::InvalidOperand(offset)));
+            }
+            OpCode::Closure => {
+                for pair in code[offset + 3..next].chunks(2) {
+                    if pair[0] == 1 && pair[1] as usize >= state.depth {
+                        return Err(error(BytecodeError::InvalidOperand(offset)));
+                    }
+                }
+            }
+            OpCode::Return if !state.handlers.is_empty() => {
+                return Err(error(BytecodeError::InvalidHandler(offset)));
+            }
+            OpCode::PopHandler if state.handlers.is_empty() => {
+                return Err(error(BytecodeError::InvalidHandler(offset)));
+            }
+            _ => (),
+        }
+
+        match op {
+            OpCode::Return | OpCode::Throw => continue,
+            OpCode::Jump => {
+                enter(
+                    &mut states,
+                    &mut pending,
+                    sizes,
+                    offset,
+                    next + jump(),
+                    after,
+                )?;
+                continue;
+            }
+            OpCode::Loop => {
+                enter(
+                    &mut states,
+                    &mut pending,
+                    sizes,
+                    offset,
+                    next - jump(),
+                    after,
+                )?;
+                continue;
+            }
+            OpCode::JumpIfFalse => {
+                let target = next + jump();
+                enter(
+                    &mut states,
+                    &mut pending,
+                    sizes,
+                    offset,
+                    target,
+                    after.clone(),
+                )?;
+            }
+            // A handler is entered with the error pushed where the stack was
+            OpCode::PushHandler => {
+                let handler = State {
+                    depth: state.depth + 1,
+                    handlers: state.handlers.clone(),
+                };
+                enter(
+                    &mut states,
+                    &mut pending,
+                    sizes,
+                    offset,
+                    next + jump(),
+                    handler,
+                )?;
+            }
+            // Lists, maps and strings push the element and skip the ForCheck
+            // that has to follow, the end of the loop pushes nothing
+            OpCode::ForNext => {
+                if code.get(next) != Some(&(OpCode::ForCheck as u8)) {
+                    return Err(error(BytecodeError::InvalidOperand(offset)));
+                }
+                enter(
+                    &mut states,
+                    &mut pending,
+                    sizes,
+                    offset,
+                    next + jump(),
+                    state.clone(),
+                )?;
+                enter(
+                    &mut states,
+                    &mut pending,
+                    sizes,
+                    offset,
+                    next + 3,
+                    after.clone(),
+                )?;
+            }
+            // The iterator returning nil pops it and ends the loop
+            OpCode::ForCheck => {
+                let end = State {
+                    depth: state.depth - 1,
+                    handlers: state.handlers.clone(),
+                };
+                enter(&mut states, &mut pending, sizes, offset, next + jump(), end)?;
+            }
+            _ => (),
+        }
+
+        if next == code.len() {
+            return Err(error(BytecodeError::FallsOffEnd));
+        }
+        enter(&mut states, &mut pending, sizes, offset, next, after)?;
+    }
+
+    Ok(())
+}
+
+// Reach `target` from the instruction at `from`
+fn enter(
+    states: &mut [Option<State>],
+    pending: &mut Vec<usize>,
+    sizes: &[usize],
+    from: usize,
+    target: usize,
+    state: State,
+) -> Result<()> {
+    if sizes.get(target).map_or(true, |&size| size == 0) {
+        return Err(error(BytecodeError::InvalidOperand(from)));
+    }
+
+    match &states[target] {
+        Some(seen) if *seen == state => Ok(()),
+        Some(_) => Err(error(BytecodeError::InvalidStack(from))),
+        None => {
+            states[target] = Some(state);
+            pending.push(target);
+            Ok(())
+        }
+    }
+}
+
+fn closure_function(chunk: &Chunk, offset: usize) -> Option<&Function> {
+    if offset + 3 > chunk.code.len() {
+        return None;
+    }
+
+    match chunk.constants.get(chunk.read_u16(offset + 1) as usize) {
+        Some(Constant::Function(function)) => Some(function),
+        _ => None,
+    }
+}
+
+fn error(err: BytecodeError) -> RloxError {
+    RloxError::Bytecode(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Function {
+        let mut inner = Function {
+            name: "inner".to_string(),
+            arity: 2,
+            upvalue_count: 1,
+            ..Function::default()
+        };
+        inner.chunk.write_op(OpCode::GetUpvalue, 3);
+        inner.chunk.write(0, 3);
+        inner.chunk.write_op(OpCode::Return, 3);
+
+        let mut script = Function::default();
+        let function = script
+            .chunk
+            .add_constant(Constant::Function(Rc::new(inner)));
+        let name = script.chunk.add_constant(Constant::String("inner".into()));
+        let number = script.chunk.add_constant(Constant::Number(1.5));
+        script.chunk.write_op(OpCode::Nil, 1);
+        script.chunk.write_op(OpCode::Closure, 1);
+        script.chunk.write_u16(function as u16, 1);
+        script.chunk.write(1, 1);
+        script.chunk.write(1, 1);
+        script.chunk.write_op(OpCode::DefineGlobal, 1);
+        script.chunk.write_u16(name as u16, 1);
+        script.chunk.write_op(OpCode::Constant, 2);
+        script.chunk.write_u16(number as u16, 2);
+        script.chunk.write_op(OpCode::Pop, 2);
+        script.chunk.write_op(OpCode::Nil, 2);
+        script.chunk.write_op(OpCode::Return, 2);
+        script
+    }
+
+    #[test]
+    fn round_trip() {
+        let script = sample();
+        let bytes = serialize(&script);
+
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(deserialize(&bytes).unwrap(), script);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut bytes = serialize(&sample());
+        bytes[0] = b'X';
+        assert_eq!(
+            deserialize(&bytes),
+            Err(RloxError::Bytecode(BytecodeError::BadMagic))
+        );
+
+        let mut bytes = serialize(&sample());
+        bytes[4] = 99;
+        assert_eq!(
+            deserialize(&bytes),
+            Err(RloxError::Bytecode(BytecodeError::UnsupportedVersion(99)))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_data() {
+        let bytes = serialize(&sample());
+        for len in 0..bytes.len() {
+            assert!(deserialize(&bytes[..len]).is_err());
+        }
+
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(
+            deserialize(&bytes),
+            Err(RloxError::Bytecode(BytecodeError::TrailingData))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_bytecode() {
+        let mut script = Function::default();
+        script.chunk.write(255, 1);
+        assert_eq!(
+            deserialize(&serialize(&script)),
+            Err(RloxError::Bytecode(BytecodeError::InvalidOpcode(255, 0)))
+        );
+
+        // Global names must be string constants
+        let mut script = Function::default();
+        let number = script.chunk.add_constant(Constant::Number(1.));
+        script.chunk.write_op(OpCode::GetGlobal, 1);
+        script.chunk.write_u16(number as u16, 1);
+        assert_eq!(
+            deserialize(&serialize(&script)),
+            Err(RloxError::Bytecode(BytecodeError::InvalidOperand(0)))
+        );
+
+        // Jumps must land inside the chunk
+        let mut script = Function::default();
+        script.chunk.write_op(OpCode::Jump, 1);
+        script.chunk.write_u16(10, 1);
+        assert_eq!(
+            deserialize(&serialize(&script)),
+            Err(RloxError::Bytecode(BytecodeError::InvalidOperand(0)))
+        );
+
+        // Upvalues must exist in the function that uses them
+        let mut script = Function::default();
+        script.chunk.write_op(OpCode::GetUpvalue, 1);
+        script.chunk.write(0, 1);
+        assert_eq!(
+            deserialize(&serialize(&script)),
+            Err(RloxError::Bytecode(BytecodeError::InvalidOperand(0)))
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_stacks() {
+        let check = |code: &[u8], expected: BytecodeError| {
+            let mut script = Function::default();
+            for byte in code {
+                script.chunk.write(*byte, 1);
+            }
+            assert_eq!(
+                deserialize(&serialize(&script)),
+                Err(RloxError::Bytecode(expected))
+            );
+        };
+        let (nil, ret) = (OpCode::Nil as u8, OpCode::Return as u8);
+
+        // The script's own slot can't be popped
+        check(&[OpCode::Pop as u8], BytecodeError::InvalidStack(0));
+        // Locals must be on the stack
+        check(
+            &[OpCode::GetLocal as u8, 1],
+            BytecodeError::InvalidOperand(0),
+        );
+        // Jumps must land on an instruction
+        check(
+            &[
+                nil,
+                OpCode::Jump as u8,
+                0,
+                1,
+                OpCode::GetLocal as u8,
+                0,
+                ret,
+            ],
+            BytecodeError::InvalidOperand(1),
+        );
+        // Both ways past a branch must leave the stack the same
+        check(
+            &[nil, OpCode::JumpIfFalse as u8, 0, 1, nil, ret],
+            BytecodeError::InvalidStack(4),
+        );
+        check(
+            &[OpCode::PopHandler as u8],
+            BytecodeError::InvalidHandler(0),
+        );
+        check(
+            &[OpCode::PushHandler as u8, 0, 2, nil, ret, ret],
+            BytecodeError::InvalidHandler(4),
+        );
+        check(&[nil], BytecodeError::FallsOffEnd);
+    }
+}
@@ -0,0 +1,1246 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Compiles the AST into bytecode for clox.
+//
+// The compiler walks the same AST that jlox interprets, after it's been
+// checked by the resolver. Unlike the interpreter, the compiler tracks local
+// variables itself: every local lives in a stack slot, so it is resolved to
+// a slot index rather than an environment depth.
+//
+// Locals of enclosing functions are reached through upvalues. A function
+// records which variables it captures, and each enclosing function marks the
+// captured locals so they're moved off of the stack when their scope ends.
+//
+// A try statement installs a handler for the VM to unwind to. Jumping out of
+// a try with `break`, `continue` or `return` removes the handler, and runs a
+// copy of the finally block compiled in place before the jump.
+
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, Constant, Function, OpCode};
+use crate::errors::{CompileError, Result, RloxError};
+use crate::parser::ast::{self, Expr, ExprLiteral, Name, Stmt};
+use crate::scanner::{Span, TokenType};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Initializer,
+    Method,
+}
+
+struct Local {
+    name: String,
+    depth: Option<u32>, // None until the local's initializer has run
+    is_captured: bool,  // closed over by a nested function
+}
+
+// A variable captured by the function being compiled: either a local slot of
+// the directly enclosing function, or one of that function's own upvalues.
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+// An enclosing loop, for `break` and `continue` to jump out of.
+struct Loop {
+    scope_depth: u32,      // locals deeper than this belong to the loop's body
+    tries: usize,          // try statements enclosing the loop
+    breaks: Vec<usize>,    // jumps to patch to the end of the loop
+    continues: Vec<usize>, // jumps to patch to the loop's increment
+}
+
+// An enclosing try statement, for jumps to leave.
+struct Try {
+    scope_depth: u32,      // locals deeper than this belong to the try
+    handler: bool,         // whether a handler is installed while in it
+    finally: Option<Stmt>, // run by every jump out of it
+}
+
+// Where a name resolves to, and so which instructions access it.
+enum Variable {
+    Local(u8),
+    Upvalue(u8),
+    Global(u16),
+}
+
+// Book-keeping for the function currently being compiled.
+// Nested function declarations push a new state.
+struct FunctionState {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    loops: Vec<Loop>,
+    tries: Vec<Try>,
+    scope_depth: u32,
+}
+
+impl FunctionState {
+    fn new(name: &str, kind: FunctionKind) -> Self {
+        // Slot 0 holds the function being called, or the instance for methods.
+        let slot_zero = match kind {
+            FunctionKind::Initializer | FunctionKind::Method => "this",
+            _ => "",
+        };
+
+        Self {
+            function: Function {
+                name: name.to_string(),
+                ..Function::default()
+            },
+            kind,
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            loops: vec![],
+            tries: vec![],
+            scope_depth: 0,
+        }
+    }
+}
+
+pub struct Compiler {
+    states: Vec<FunctionState>,
+    line: usize, // line of the last token seen, used for the chunk's line table
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Compile a whole program into the top-level script function.
+pub fn compile(program: &[Stmt]) -> Result<Function> {
+    Compiler::new().compile(program)
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self {
+            states: vec![FunctionState::new("", FunctionKind::Script)],
+            line: 1,
+        }
+    }
+
+    pub fn compile(mut self, program: &[Stmt]) -> Result<Function> {
+        // Errors without a token of their own point at the line being compiled
+        for stmt in program {
+            self.statement(stmt)
+                .map_err(|err| err.at(Span::line(self.line)))?;
+        }
+        self.emit_return();
+
+        Ok(self.states.pop().unwrap().function)
+    }
+
+    //
+    // Statements
+    //
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<()> {
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.expression(expr)?;
+                self.emit_op(OpCode::Pop);
+            }
+            Stmt::Var(ident, init) => {
+                self.line = ident.line();
+                self.declare_variable(ident.lexeme())?;
+                match init {
+                    Some(expr) => self.expression(expr)?,
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.define_variable(ident.lexeme())?;
+            }
+            Stmt::Block(_, body) => {
+                self.begin_scope();
+                for stmt in body {
+                    self.statement(stmt)?;
+                }
+                self.end_scope();
+            }
+            Stmt::If(_, condition, then_branch, else_branch) => {
+                self.expression(condition)?;
+
+                // Skip over the then branch if the condition is false
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch)?;
+
+                // Skip over the else branch when the then branch runs
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump)?;
+                self.emit_op(OpCode::Pop);
+
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch)?;
+                }
+                self.patch_jump(else_jump)?;
+            }
+            Stmt::While(_, condition, body, increment) => {
+                let loop_start = self.chunk().code.len();
+                self.expression(condition)?;
+
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+
+                self.begin_loop(self.state().scope_depth);
+                self.statement(body)?;
+                let Loop {
+                    breaks, continues, ..
+                } = self.state_mut().loops.pop().unwrap();
+
+                // `continue` skips the rest of the body, but still runs the increment
+                for jump in continues {
+                    self.patch_jump(jump)?;
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment)?;
+                    self.emit_op(OpCode::Pop);
+                }
+                self.emit_loop(loop_start)?;
+
+                self.patch_jump(exit_jump)?;
+                self.emit_op(OpCode::Pop);
+
+                // The condition has already been popped when breaking out
+                for jump in breaks {
+                    self.patch_jump(jump)?;
+                }
+            }
+            Stmt::ForIn(keyword, name, iterable, body) => {
+                self.for_in(keyword.line, name, iterable, body)?
+            }
+            Stmt::Break(keyword) | Stmt::Continue(keyword) => {
+                let tries = self
+                    .state()
+                    .loops
+                    .last()
+                    .expect("resolver keeps `break` and `continue` inside loops")
+                    .tries;
+                self.exit_tries(tries)?;
+
+                self.line = keyword.line;
+                self.discard_loop_locals();
+
+                let jump = self.emit_jump(OpCode::Jump);
+                let current = self
+                    .state_mut()
+                    .loops
+                    .last_mut()
+                    .expect("resolver keeps `break` and `continue` inside loops");
+                match stmt {
+                    Stmt::Break(_) => current.breaks.push(jump),
+                    _ => current.continues.push(jump),
+                }
+            }
+            Stmt::Fun(ident, params, body) => {
+                // Functions may refer to themselves, so mark them initialized
+                // before compiling the body.
+                self.line = ident.line();
+                self.declare_variable(ident.lexeme())?;
+                self.mark_initialized();
+                self.function(ident.lexeme(), params, body, FunctionKind::Function)?;
+                self.define_variable(ident.lexeme())?;
+            }
+            Stmt::Return(keyword, expr) => {
+                self.line = keyword.line;
+                match expr {
+                    Some(expr) => self.expression(expr)?,
+                    None => self.emit_implicit_value(),
+                }
+
+                // The value waits in a hidden local while finally blocks run
+                if !self.state().tries.is_empty() {
+                    self.declare_variable("(return)")?;
+                    self.mark_initialized();
+                    self.exit_tries(0)?;
+
+                    self.line = keyword.line;
+                    self.named_variable("(return)")?;
+                    self.state_mut().locals.pop();
+                }
+                self.emit_op(OpCode::Return);
+            }
+            Stmt::Throw(keyword, value) => {
+                self.expression(value)?;
+                self.line = keyword.line;
+                self.emit_op(OpCode::Throw);
+            }
+            Stmt::Try(keyword, body, catch, finally) => {
+                self.try_stmt(keyword.line, body, catch, finally.as_deref())?
+            }
+            Stmt::Class(name, superclass, methods) => self.class(name, superclass, methods)?,
+        }
+
+        Ok(())
+    }
+
+    // The iterable and the position in it are kept in hidden locals, which no
+    // identifier can name. The loop variable gets a scope of its own in each
+    // iteration, so closures capture that iteration's value.
+    fn for_in(&mut self, line: usize, name: &Name, iterable: &Expr, body: &Stmt) -> Result<()> {
+        self.begin_scope();
+        self.expression(iterable)?;
+        self.line = line;
+        self.emit_op(OpCode::GetIterator);
+        for hidden in ["(iterable)", "(position)"] {
+            self.declare_variable(hidden)?;
+            self.define_variable(hidden)?;
+        }
+
+        // Lists, maps and strings skip over the ForCheck,
+        // which only ends the loop when an iterator returns nil
+        let loop_start = self.chunk().code.len();
+        let exit_jump = self.emit_jump(OpCode::ForNext);
+        let check_jump = self.emit_jump(OpCode::ForCheck);
+
+        let scope_depth = self.state().scope_depth;
+        self.begin_scope();
+        self.declare_variable(name.lexeme())?;
+        self.define_variable(name.lexeme())?;
+
+        self.begin_loop(scope_depth);
+        self.statement(body)?;
+        let Loop {
+            breaks, continues, ..
+        } = self.state_mut().loops.pop().unwrap();
+        self.end_scope();
+
+        // `continue` has already discarded the loop variable
+        for jump in continues {
+            self.patch_jump(jump)?;
+        }
+        self.line = line;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.patch_jump(check_jump)?;
+        for jump in breaks {
+            self.patch_jump(jump)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    // The body's handler goes to the catch clause, or straight to the finally
+    // block's error path without one. Errors in the catch clause go there too:
+    //
+    //     PushHandler -> handler
+    //     body
+    //     PopHandler
+    //     Jump -> after
+    //   handler:
+    //     catch clause, under PushHandler -> rethrow when there's a finally
+    //   after:
+    //     finally
+    //     Jump -> end
+    //   rethrow:
+    //     finally, with the error in a hidden local
+    //     Throw
+    //   end:
+    fn try_stmt(
+        &mut self,
+        line: usize,
+        body: &Stmt,
+        catch: &Option<(Name, Box<Stmt>)>,
+        finally: Option<&Stmt>,
+    ) -> Result<()> {
+        self.line = line;
+        let scope_depth = self.state().scope_depth;
+        let handler_jump = self.emit_jump(OpCode::PushHandler);
+        self.state_mut().tries.push(Try {
+            scope_depth,
+            handler: true,
+            finally: finally.cloned(),
+        });
+
+        self.statement(body)?;
+        self.line = line;
+        self.emit_op(OpCode::PopHandler);
+        let body_jump = self.emit_jump(OpCode::Jump);
+
+        // The caught error is already in the slot of the catch variable
+        let mut rethrow_jump = None;
+        if let Some((name, handler)) = catch {
+            self.patch_jump(handler_jump)?;
+            self.begin_scope();
+            self.line = name.line();
+            self.declare_variable(name.lexeme())?;
+            self.define_variable(name.lexeme())?;
+
+            self.state_mut().tries.last_mut().unwrap().handler = finally.is_some();
+            if finally.is_some() {
+                rethrow_jump = Some(self.emit_jump(OpCode::PushHandler));
+            }
+            self.statement(handler)?;
+            if finally.is_some() {
+                self.emit_op(OpCode::PopHandler);
+            }
+            self.end_scope();
+        }
+        self.state_mut().tries.pop();
+        self.patch_jump(body_jump)?;
+
+        let finally = match finally {
+            Some(finally) => finally,
+            None => return Ok(()),
+        };
+        self.statement(finally)?;
+        self.line = line;
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        match rethrow_jump {
+            Some(jump) => self.patch_jump(jump)?,
+            None => self.patch_jump(handler_jump)?,
+        }
+
+        // Coming from the catch clause, its variable is still under the error
+        self.begin_scope();
+        let hidden: &[&str] = match catch {
+            Some(_) => &["(caught)", "(error)"],
+            None => &["(error)"],
+        };
+        for name in hidden {
+            self.declare_variable(name)?;
+            self.define_variable(name)?;
+        }
+        self.statement(finally)?;
+        self.line = line;
+        self.named_variable("(error)")?;
+        self.emit_op(OpCode::Throw);
+        self.end_scope();
+
+        self.patch_jump(end_jump)
+    }
+
+    // Leave every try statement inside the `outer` innermost ones, removing
+    // their handlers and running their finally blocks. Each finally block is
+    // compiled as if it were where its try statement is: the locals declared
+    // inside the try are still on the stack, but can't be named, and the loops
+    // inside it can't be broken out of.
+    fn exit_tries(&mut self, outer: usize) -> Result<()> {
+        let mut exited = vec![];
+        while self.state().tries.len() > outer {
+            let current = self.state_mut().tries.pop().unwrap();
+            if current.handler {
+                self.emit_op(OpCode::PopHandler);
+            }
+
+            if let Some(finally) = &current.finally {
+                let tries = self.state().tries.len();
+                let state = self.state_mut();
+                let first = state
+                    .loops
+                    .iter()
+                    .position(|inner| inner.tries > tries)
+                    .unwrap_or(state.loops.len());
+                let inner_loops = state.loops.split_off(first);
+
+                let hidden: Vec<(usize, String)> = state
+                    .locals
+                    .iter_mut()
+                    .enumerate()
+                    .filter(|(_, local)| local.depth.map_or(true, |d| d > current.scope_depth))
+                    .map(|(slot, local)| (slot, std::mem::take(&mut local.name)))
+                    .collect();
+
+                self.statement(finally)?;
+
+                let state = self.state_mut();
+                state.loops.extend(inner_loops);
+                for (slot, name) in hidden {
+                    state.locals[slot].name = name;
+                }
+            }
+            exited.push(current);
+        }
+
+        let tries = &mut self.state_mut().tries;
+        tries.extend(exited.into_iter().rev());
+        Ok(())
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        params: &[Name],
+        body: &[Stmt],
+        kind: FunctionKind,
+    ) -> Result<()> {
+        self.states.push(FunctionState::new(name, kind));
+
+        // Parameters are the first locals after slot 0
+        self.begin_scope();
+        for param in params {
+            self.line = param.line();
+            self.declare_variable(param.lexeme())?;
+            self.mark_initialized();
+        }
+        self.state_mut().function.arity = params.len() as u8;
+
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+
+        // Locals don't need to be popped, returning discards the whole frame.
+        self.emit_return();
+        let FunctionState {
+            mut function,
+            upvalues,
+            ..
+        } = self.states.pop().unwrap();
+        function.upvalue_count = upvalues.len() as u8;
+
+        // The closure instruction tells the VM where to find each upvalue
+        let constant = self.make_constant(Constant::Function(Rc::new(function)))?;
+        self.emit_op(OpCode::Closure);
+        self.emit_u16(constant);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+        Ok(())
+    }
+
+    fn class(&mut self, name: &Name, superclass: &Option<Expr>, methods: &[Stmt]) -> Result<()> {
+        self.line = name.line();
+        let ident = name.lexeme();
+        let constant = self.identifier_constant(ident)?;
+
+        self.declare_variable(ident)?;
+        self.emit_op(OpCode::Class);
+        self.emit_u16(constant);
+        self.define_variable(ident)?;
+
+        if let Some(superclass) = superclass {
+            if let Expr::Variable(super_ident, _) = superclass {
+                if super_ident.lexeme() == ident {
+                    return Err(RloxError::Compile(
+                        CompileError::InheritFromSelf(ident.to_string()),
+                        Some(super_ident.span()),
+                    ));
+                }
+            }
+
+            // The superclass stays on the stack as a local named "super",
+            // for methods to capture. Its methods are copied down into the class.
+            self.expression(superclass)?;
+            self.begin_scope();
+            self.declare_variable("super")?;
+            self.define_variable("super")?;
+
+            self.named_variable(ident)?;
+            self.emit_op(OpCode::Inherit);
+        }
+
+        // Keep the class on the stack while its methods are attached to it
+        self.named_variable(ident)?;
+        for method in methods {
+            if let Stmt::Fun(method_ident, params, body) = method {
+                let kind = match method_ident.lexeme() {
+                    "init" => FunctionKind::Initializer,
+                    _ => FunctionKind::Method,
+                };
+                let constant = self.identifier_constant(method_ident.lexeme())?;
+
+                self.function(method_ident.lexeme(), params, body, kind)?;
+                self.emit_op(OpCode::Method);
+                self.emit_u16(constant);
+            } else {
+                unreachable!("class bodies only contain methods");
+            }
+        }
+        self.emit_op(OpCode::Pop);
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+
+        Ok(())
+    }
+
+    //
+    // Expressions
+    //
+
+    fn expression(&mut self, expr: &Expr) -> Result<()> {
+        match expr {
+            Expr::Literal(span, lit) => {
+                self.line = span.line;
+                match lit {
+                    ExprLiteral::Bool(true) => self.emit_op(OpCode::True),
+                    ExprLiteral::Bool(false) => self.emit_op(OpCode::False),
+                    ExprLiteral::Nil => self.emit_op(OpCode::Nil),
+                    ExprLiteral::Number(n) => self.emit_constant(Constant::Number(*n))?,
+                    ExprLiteral::String(s) => self.emit_constant(Constant::String(s.clone()))?,
+                }
+            }
+            Expr::Grouping(expr) => self.expression(expr)?,
+            Expr::Stringify(expr) => {
+                self.expression(expr)?;
+                self.emit_op(OpCode::Stringify);
+            }
+            Expr::Lambda(keyword, params, body) => {
+                // Leaves the closure on the stack as the expression's value
+                self.line = keyword.line;
+                let name = ast::lambda_name(*keyword);
+                self.function(&name, params, body, FunctionKind::Function)?;
+            }
+            Expr::Unary(operator, expr) => {
+                self.expression(expr)?;
+                self.line = operator.span.line;
+                match operator.token_type {
+                    TokenType::Bang => self.emit_op(OpCode::Not),
+                    TokenType::Minus => self.emit_op(OpCode::Negate),
+                    _ => unreachable!("parser only produces ! and - unary operators"),
+                }
+            }
+            Expr::Binary(left, operator, right) => {
+                self.expression(left)?;
+                self.expression(right)?;
+                self.line = operator.span.line;
+                match operator.token_type {
+                    TokenType::Plus => self.emit_op(OpCode::Add),
+                    TokenType::Minus => self.emit_op(OpCode::Subtract),
+                    TokenType::Star => self.emit_op(OpCode::Multiply),
+                    TokenType::Slash => self.emit_op(OpCode::Divide),
+                    TokenType::EqualEqual => self.emit_op(OpCode::Equal),
+                    TokenType::BangEqual => {
+                        self.emit_op(OpCode::Equal);
+                        self.emit_op(OpCode::Not);
+                    }
+                    TokenType::Greater => self.emit_op(OpCode::Greater),
+                    TokenType::GreaterEqual => {
+                        self.emit_op(OpCode::Less);
+                        self.emit_op(OpCode::Not);
+                    }
+                    TokenType::Less => self.emit_op(OpCode::Less),
+                    TokenType::LessEqual => {
+                        self.emit_op(OpCode::Greater);
+                        self.emit_op(OpCode::Not);
+                    }
+                    _ => unreachable!("parser only produces arithmetic and comparison operators"),
+                }
+            }
+            Expr::Logical(left, operator, right) => {
+                self.expression(left)?;
+                self.line = operator.span.line;
+                match operator.token_type {
+                    TokenType::And => {
+                        // Short-circuit: leave the falsey left side on the stack
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right)?;
+                        self.patch_jump(end_jump)?;
+                    }
+                    TokenType::Or => {
+                        // Short-circuit: leave the truthy left side on the stack
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+                        self.patch_jump(else_jump)?;
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right)?;
+                        self.patch_jump(end_jump)?;
+                    }
+                    _ => unreachable!("parser only produces `and` and `or` logical operators"),
+                }
+            }
+            Expr::Variable(ident, _) => {
+                self.line = ident.line();
+                self.named_variable(ident.lexeme())?;
+            }
+            Expr::Assign(ident, value, _) => {
+                self.expression(value)?;
+                self.line = ident.line();
+                match self.resolve_variable(ident.lexeme())? {
+                    Variable::Local(slot) => {
+                        self.emit_op(OpCode::SetLocal);
+                        self.emit_byte(slot);
+                    }
+                    Variable::Upvalue(index) => {
+                        self.emit_op(OpCode::SetUpvalue);
+                        self.emit_byte(index);
+                    }
+                    Variable::Global(constant) => {
+                        self.emit_op(OpCode::SetGlobal);
+                        self.emit_u16(constant);
+                    }
+                }
+            }
+            Expr::Call(callee, paren, arguments) => {
+                // Calling a method directly off of an object skips creating a bound method
+                if let Expr::Super(keyword, method, _) = callee.as_ref() {
+                    self.line = keyword.line;
+                    self.named_variable("this")?;
+                    for argument in arguments {
+                        self.expression(argument)?;
+                    }
+
+                    let constant = self.identifier_constant(method.lexeme())?;
+                    self.named_variable("super")?;
+                    self.emit_op(OpCode::SuperInvoke);
+                    self.emit_u16(constant);
+                    self.emit_byte(arguments.len() as u8);
+                } else if let Expr::Get(object, name) = callee.as_ref() {
+                    self.expression(object)?;
+                    for argument in arguments {
+                        self.expression(argument)?;
+                    }
+
+                    self.line = name.line();
+                    let constant = self.identifier_constant(name.lexeme())?;
+                    self.emit_op(OpCode::Invoke);
+                    self.emit_u16(constant);
+                    self.emit_byte(arguments.len() as u8);
+                } else {
+                    self.expression(callee)?;
+                    for argument in arguments {
+                        self.expression(argument)?;
+                    }
+
+                    self.line = paren.line;
+                    self.emit_op(OpCode::Call);
+                    self.emit_byte(arguments.len() as u8);
+                }
+            }
+            Expr::Get(object, name) => {
+                self.expression(object)?;
+
+                self.line = name.line();
+                let constant = self.identifier_constant(name.lexeme())?;
+                self.emit_op(OpCode::GetProperty);
+                self.emit_u16(constant);
+            }
+            Expr::Set(object, name, value) => {
+                self.expression(object)?;
+                self.expression(value)?;
+
+                self.line = name.line();
+                let constant = self.identifier_constant(name.lexeme())?;
+                self.emit_op(OpCode::SetProperty);
+                self.emit_u16(constant);
+            }
+            Expr::This(keyword, _) => {
+                self.line = keyword.line;
+                self.named_variable("this")?;
+            }
+            Expr::List(bracket, elements) => {
+                for element in elements {
+                    self.expression(element)?;
+                }
+
+                self.line = bracket.line;
+                let count = u16::try_from(elements.len())
+                    .map_err(|_| RloxError::Compile(CompileError::TooManyElements, None))?;
+                self.emit_op(OpCode::BuildList);
+                self.emit_u16(count);
+            }
+            Expr::Map(brace, entries) => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+
+                self.line = brace.line;
+                let count = u16::try_from(entries.len())
+                    .map_err(|_| RloxError::Compile(CompileError::TooManyElements, None))?;
+                self.emit_op(OpCode::BuildMap);
+                self.emit_u16(count);
+            }
+            Expr::Index(object, bracket, index) => {
+                self.expression(object)?;
+                self.expression(index)?;
+
+                self.line = bracket.line;
+                self.emit_op(OpCode::GetIndex);
+            }
+            Expr::SetIndex(object, bracket, index, value) => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+
+                self.line = bracket.line;
+                self.emit_op(OpCode::SetIndex);
+            }
+            Expr::Super(keyword, method, _) => {
+                self.line = keyword.line;
+                let constant = self.identifier_constant(method.lexeme())?;
+
+                self.named_variable("this")?;
+                self.named_variable("super")?;
+                self.emit_op(OpCode::GetSuper);
+                self.emit_u16(constant);
+            }
+        }
+
+        Ok(())
+    }
+
+    //
+    // Variables
+    //
+
+    fn named_variable(&mut self, ident: &str) -> Result<()> {
+        match self.resolve_variable(ident)? {
+            Variable::Local(slot) => {
+                self.emit_op(OpCode::GetLocal);
+                self.emit_byte(slot);
+            }
+            Variable::Upvalue(index) => {
+                self.emit_op(OpCode::GetUpvalue);
+                self.emit_byte(index);
+            }
+            Variable::Global(constant) => {
+                self.emit_op(OpCode::GetGlobal);
+                self.emit_u16(constant);
+            }
+        }
+        Ok(())
+    }
+
+    // Locals of the current function are on the stack, locals of enclosing
+    // functions are captured as upvalues, and everything else is a global.
+    fn resolve_variable(&mut self, ident: &str) -> Result<Variable> {
+        if let Some(slot) = find_local(self.state(), ident)? {
+            return Ok(Variable::Local(slot));
+        }
+
+        match self.resolve_upvalue(self.states.len() - 1, ident)? {
+            Some(index) => Ok(Variable::Upvalue(index)),
+            None => Ok(Variable::Global(self.identifier_constant(ident)?)),
+        }
+    }
+
+    // Find `ident` in the functions enclosing `self.states[depth]`, threading
+    // an upvalue through every function in between.
+    fn resolve_upvalue(&mut self, depth: usize, ident: &str) -> Result<Option<u8>> {
+        if depth == 0 {
+            return Ok(None); // the script has no enclosing function
+        }
+
+        let enclosing = depth - 1;
+        if let Some(slot) = find_local(&self.states[enclosing], ident)? {
+            self.states[enclosing].locals[slot as usize].is_captured = true;
+            return self.add_upvalue(depth, slot, true).map(Some);
+        }
+
+        match self.resolve_upvalue(enclosing, ident)? {
+            Some(index) => self.add_upvalue(depth, index, false).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Returns the index of the upvalue, reusing it if it's already captured
+    fn add_upvalue(&mut self, depth: usize, index: u8, is_local: bool) -> Result<u8> {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.states[depth].upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(existing as u8);
+        }
+        // The count has to fit in the Closure's u8 operand as well as each index
+        if upvalues.len() >= u8::MAX as usize {
+            return Err(RloxError::Compile(CompileError::TooManyUpvalues, None));
+        }
+
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
+    }
+
+    // Locals are pushed onto the stack in the order they're declared,
+    // so their index in `locals` is also their stack slot.
+    fn declare_variable(&mut self, ident: &str) -> Result<()> {
+        if self.state().scope_depth == 0 {
+            return Ok(()); // globals are late-bound
+        }
+
+        if self.state().locals.len() > u8::MAX as usize {
+            return Err(RloxError::Compile(CompileError::TooManyLocals, None));
+        }
+
+        self.state_mut().locals.push(Local {
+            name: ident.to_string(),
+            depth: None,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    fn define_variable(&mut self, ident: &str) -> Result<()> {
+        if self.state().scope_depth > 0 {
+            // The initializer's value is already sitting in the local's slot
+            self.mark_initialized();
+            return Ok(());
+        }
+
+        let constant = self.identifier_constant(ident)?;
+        self.emit_op(OpCode::DefineGlobal);
+        self.emit_u16(constant);
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let depth = state.scope_depth;
+        state.locals.last_mut().unwrap().depth = Some(depth);
+    }
+
+    fn identifier_constant(&mut self, ident: &str) -> Result<u16> {
+        self.make_constant(Constant::String(ident.to_string()))
+    }
+
+    fn begin_loop(&mut self, scope_depth: u32) {
+        let tries = self.state().tries.len();
+        self.state_mut().loops.push(Loop {
+            scope_depth,
+            tries,
+            breaks: vec![],
+            continues: vec![],
+        });
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    // Jumping out of a loop's body leaves the scopes inside of it, so their
+    // locals are discarded. The compiler still keeps track of them, since the
+    // code following the jump is still inside those scopes.
+    fn discard_loop_locals(&mut self) {
+        let state = self.state();
+        let loop_depth = match state.loops.last() {
+            Some(current) => current.scope_depth,
+            None => return,
+        };
+
+        let ops: Vec<OpCode> = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.map_or(false, |depth| depth > loop_depth))
+            .map(|local| match local.is_captured {
+                true => OpCode::CloseUpvalue,
+                false => OpCode::Pop,
+            })
+            .collect();
+        for op in ops {
+            self.emit_op(op);
+        }
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        // Discard every local declared in the scope we're leaving,
+        // moving the captured ones off of the stack and into their upvalue.
+        loop {
+            let state = self.state();
+            match state.locals.last() {
+                Some(Local {
+                    depth: Some(depth),
+                    is_captured,
+                    ..
+                }) if *depth > state.scope_depth => {
+                    let op = match is_captured {
+                        true => OpCode::CloseUpvalue,
+                        false => OpCode::Pop,
+                    };
+                    self.state_mut().locals.pop();
+                    self.emit_op(op);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    //
+    // Emitting bytecode
+    //
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.line;
+        self.chunk().write(byte, line);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        let line = self.line;
+        self.chunk().write_op(op, line);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        let line = self.line;
+        self.chunk().write_u16(value, line);
+    }
+
+    // Functions without an explicit return value return nil,
+    // except initializers which always return the instance.
+    fn emit_return(&mut self) {
+        self.emit_implicit_value();
+        self.emit_op(OpCode::Return);
+    }
+
+    fn emit_implicit_value(&mut self) {
+        match self.state().kind {
+            FunctionKind::Initializer => {
+                self.emit_op(OpCode::GetLocal);
+                self.emit_byte(0);
+            }
+            _ => self.emit_op(OpCode::Nil),
+        }
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> Result<u16> {
+        let index = self.chunk().add_constant(constant);
+        u16::try_from(index).map_err(|_| RloxError::Compile(CompileError::TooManyConstants, None))
+    }
+
+    fn emit_constant(&mut self, constant: Constant) -> Result<()> {
+        let index = self.make_constant(constant)?;
+        self.emit_op(OpCode::Constant);
+        self.emit_u16(index);
+        Ok(())
+    }
+
+    // Emits a jump with a placeholder offset, returns the offset's location
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit_u16(u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    // Point a previously emitted jump at the current end of the chunk
+    fn patch_jump(&mut self, offset: usize) -> Result<()> {
+        let chunk = self.chunk();
+        let jump = chunk.code.len() - offset - 2;
+        let jump = u16::try_from(jump)
+            .map_err(|_| RloxError::Compile(CompileError::JumpTooLarge, None))?;
+
+        let [high, low] = jump.to_be_bytes();
+        chunk.code[offset] = high;
+        chunk.code[offset + 1] = low;
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> Result<()> {
+        self.emit_op(OpCode::Loop);
+
+        // +2 to also jump back over the Loop instruction's own operand
+        let offset = self.chunk().code.len() - loop_start + 2;
+        let offset = u16::try_from(offset)
+            .map_err(|_| RloxError::Compile(CompileError::JumpTooLarge, None))?;
+        self.emit_u16(offset);
+        Ok(())
+    }
+}
+
+fn find_local(state: &FunctionState, ident: &str) -> Result<Option<u8>> {
+    for (slot, local) in state.locals.iter().enumerate().rev() {
+        if local.name == ident {
+            if local.depth.is_none() {
+                return Err(RloxError::Compile(
+                    CompileError::LocalInOwnInitializer(ident.to_string()),
+                    None,
+                ));
+            }
+            return Ok(Some(slot as u8));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn compile_source(source: &str) -> Result<Function> {
+        let mut scanner = Scanner::new();
+        scanner.scan_tokens(source)?;
+        let program: Result<Vec<Stmt>> = scanner.into_parser().parse().into_iter().collect();
+        compile(&program?)
+    }
+
+    #[test]
+    fn compiling_globals() {
+        let script = compile_source("var a = 1 + 2;").unwrap();
+
+        assert_eq!(
+            script.chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                0,
+                OpCode::Constant as u8,
+                0,
+                1,
+                OpCode::Add as u8,
+                OpCode::DefineGlobal as u8,
+                0,
+                2,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(
+            script.chunk.constants,
+            vec![
+                Constant::Number(1.),
+                Constant::Number(2.),
+                Constant::String("a".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn locals_use_stack_slots() {
+        let script = compile_source("{ var a = 1; var b = a; }").unwrap();
+
+        assert_eq!(
+            script.chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                0,
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::Pop as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn functions_are_constants() {
+        let script = compile_source("fun add(a, b) { return a + b; }").unwrap();
+
+        match &script.chunk.constants[0] {
+            Constant::Function(function) => {
+                assert_eq!(function.name, "add");
+                assert_eq!(function.arity, 2);
+            }
+            other => panic!("expected a function, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn compile_errors() {
+        assert_eq!(
+            compile_source("class A < A {}").err(),
+            Some(RloxError::Compile(
+                CompileError::InheritFromSelf("A".to_string()),
+                Some(Span::new(1, 11, 1))
+            ))
+        );
+        assert_eq!(
+            compile_source("{ var a = a; }").err(),
+            Some(RloxError::Compile(
+                CompileError::LocalInOwnInitializer("a".to_string()),
+                Some(Span::line(1))
+            ))
+        );
+    }
+
+    #[test]
+    fn closures_capture_upvalues() {
+        let script =
+            compile_source("fun outer() { var a = 1; fun middle() { fun inner() { return a; } } }")
+                .unwrap();
+
+        let nested =
+            |function: &Function| match function.chunk.constants.iter().find_map(|c| match c {
+                Constant::Function(f) => Some(f.clone()),
+                _ => None,
+            }) {
+                Some(f) => f,
+                None => panic!("expected a nested function in {}", function.name),
+            };
+        let outer = nested(&script);
+        let middle = nested(&outer);
+        let inner = nested(&middle);
+
+        // `a` is threaded through middle to reach inner
+        assert_eq!(middle.upvalue_count, 1);
+        assert_eq!(inner.upvalue_count, 1);
+        assert_eq!(inner.chunk.code[..2], [OpCode::GetUpvalue as u8, 0],);
+
+        // middle captures outer's local slot, inner captures middle's upvalue
+        let closure = |function: &Function| {
+            let at = function
+                .chunk
+                .code
+                .iter()
+                .position(|b| *b == OpCode::Closure as u8)
+                .unwrap();
+            function.chunk.code[at + 3..at + 5].to_vec()
+        };
+        assert_eq!(closure(&outer), vec![1, 1]);
+        assert_eq!(closure(&middle), vec![0, 0]);
+    }
+
+    #[test]
+    fn upvalue_limit() {
+        // inner reads `outer_count` locals of outer and the rest of middle
+        let source = |outer_count: usize, middle_count: usize| {
+            let declare = |prefix: &str, count: usize| {
+                (0..count)
+                    .map(|i| format!("var {prefix}{i} = {i};"))
+                    .collect::<String>()
+            };
+            let reads = (0..outer_count)
+                .map(|i| format!("o{i};"))
+                .chain((0..middle_count).map(|i| format!("m{i};")))
+                .collect::<String>();
+            format!(
+                "fun outer() {{ {} fun middle() {{ {} fun inner() {{ {reads} }} }} }}",
+                declare("o", outer_count),
+                declare("m", middle_count),
+            )
+        };
+
+        assert!(compile_source(&source(200, 55)).is_ok());
+        assert!(matches!(
+            compile_source(&source(200, 56)),
+            Err(RloxError::Compile(CompileError::TooManyUpvalues, _))
+        ));
+    }
+}
@@ -15,10 +15,17 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::error;
-use std::fmt;
+use std::fmt::{self, Write};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::scanner::Span;
 
 pub type Result<T> = std::result::Result<T, RloxError>;
 
+// Most frames of a backtrace that are printed
+const MAX_TRACE: usize = 20;
+
 #[derive(Debug, PartialEq)]
 pub struct ScanError {
     line: usize,
@@ -47,26 +54,427 @@ pub enum ParseError {
 }
 
 #[derive(Debug, PartialEq)]
-pub enum ResolverError {}
+pub enum ResolverError {
+    ReturnOutsideFunction,
+    ReturnFromInitializer,
+    AlreadyDeclared(String),
+    LocalInOwnInitializer(String),
+    ThisOutsideClass,
+    SuperOutsideClass,
+    SuperWithoutSuperclass,
+    OutsideLoop(String), // `break` or `continue`
+}
+
+// Code that is allowed, but is probably a mistake.
+// Each kind of warning has a code it can be silenced by.
+#[derive(Debug, PartialEq)]
+pub enum ResolverWarning {
+    UnreachableCode,
+    UnusedVariable(String),
+    UnusedParameter(String),
+    Shadowing(String),
+    UnusedAssignment(String),
+}
+
+impl ResolverWarning {
+    pub const CODES: [&'static str; 5] = [
+        "unreachable_code",
+        "unused_variable",
+        "unused_parameter",
+        "shadowing",
+        "unused_assignment",
+    ];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnreachableCode => Self::CODES[0],
+            Self::UnusedVariable(_) => Self::CODES[1],
+            Self::UnusedParameter(_) => Self::CODES[2],
+            Self::Shadowing(_) => Self::CODES[3],
+            Self::UnusedAssignment(_) => Self::CODES[4],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub kind: ResolverWarning,
+    pub span: Span,
+}
+
+impl Warning {
+    pub fn new(kind: ResolverWarning, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    // Rendered like errors, with the code to silence it:
+    //
+    //   warning[unused_variable]: Local variable 'x' is never read.
+    //    --> script.lox:2:7
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let mut out = format!("warning[{}]: {}\n", self.kind.code(), self.kind);
+        render_snippet(&mut out, filename, source, self.span);
+        out
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    TooManyConstants,
+    TooManyLocals,
+    TooManyUpvalues,
+    JumpTooLarge,
+    LocalInOwnInitializer(String),
+    InheritFromSelf(String),
+    TooManyElements,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingData,
+    InvalidUtf8,
+    InvalidConstant(u8),      // unknown constant tag
+    InvalidOpcode(u8, usize), // opcode, offset
+    InvalidOperand(usize),    // offset of the instruction
+    LineTableMismatch,
+    NestingTooDeep,
+    InvalidScript,         // the script takes arguments or has upvalues
+    InvalidStack(usize),   // offset of the instruction
+    InvalidHandler(usize), // offset of the instruction
+    FallsOffEnd,
+    UnexpectedValue, // found while running, not loading
+}
 
 #[derive(Debug, PartialEq)]
 pub enum RuntimeError {
     TypeComparison(String),
     Concatenation(String),
     Arithmetic(String),
-    UndefinedVariable, // Null access error
-    UndefinedVariableAssignment,
+    UndefinedVariable(String), // Null access error
+    UndefinedVariableAssignment(String),
     NotACallableType(String),
     MismatchedArguments(String),
+    NotAnInstance(String),     // Only instances have properties
+    UndefinedProperty(String), // Null property access error
+    SuperclassMustBeClass(String),
+    InheritFromSelf(String),
+    StackOverflow,
+    NotIndexable(String),           // Only lists and maps can be indexed
+    InvalidIndex(String),           // Indices are whole numbers
+    IndexOutOfRange(String, usize), // index, length
+    EmptyList,
+    UndefinedKey(String),
+    UnhashableKey(String),
+    NotIterable(String),
+    InvalidRange(String),            // Range bounds are numbers
+    Thrown(String),                  // an uncaught `throw`, described by its message
+    InvalidArgument(String, String), // what was expected, and the value given
+    Output(String),                  // writing printed output failed
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ParseFailure(msg) | Self::UnexpectedToken(msg) => write!(f, "{msg}"),
+            Self::TooManyParameters => write!(f, "Can't have more than 255 parameters."),
+            Self::EOF => write!(f, "Unexpected end of input."),
+        }
+    }
+}
+
+impl fmt::Display for ResolverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReturnOutsideFunction => write!(f, "Can't return from top-level code."),
+            Self::ReturnFromInitializer => write!(f, "Can't return a value from an initializer."),
+            Self::AlreadyDeclared(name) => {
+                write!(f, "Already a variable named '{name}' in this scope.")
+            }
+            Self::LocalInOwnInitializer(name) => {
+                write!(
+                    f,
+                    "Can't read local variable '{name}' in its own initializer."
+                )
+            }
+            Self::ThisOutsideClass => write!(f, "Can't use 'this' outside of a class."),
+            Self::SuperOutsideClass => write!(f, "Can't use 'super' outside of a class."),
+            Self::SuperWithoutSuperclass => {
+                write!(f, "Can't use 'super' in a class with no superclass.")
+            }
+            Self::OutsideLoop(keyword) => write!(f, "Can't use '{keyword}' outside of a loop."),
+        }
+    }
+}
+
+impl fmt::Display for ResolverWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnreachableCode => write!(f, "Unreachable code."),
+            Self::UnusedVariable(name) => write!(f, "Local variable '{name}' is never read."),
+            Self::UnusedParameter(name) => write!(f, "Parameter '{name}' is never read."),
+            Self::Shadowing(name) => {
+                write!(
+                    f,
+                    "Local variable '{name}' shadows one in an enclosing scope."
+                )
+            }
+            Self::UnusedAssignment(name) => {
+                write!(f, "Variable '{name}' is assigned to, but never read.")
+            }
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyConstants => write!(f, "Too many constants in one chunk."),
+            Self::TooManyLocals => write!(f, "Too many local variables in function."),
+            Self::TooManyUpvalues => write!(f, "Too many closure variables in function."),
+            Self::JumpTooLarge => write!(f, "Too much code to jump over."),
+            Self::LocalInOwnInitializer(name) => {
+                write!(
+                    f,
+                    "Can't read local variable '{name}' in its own initializer."
+                )
+            }
+            Self::InheritFromSelf(name) => write!(f, "Class {name} can't inherit from itself."),
+            Self::TooManyElements => {
+                write!(f, "Can't have more than 65535 elements in a literal.")
+            }
+        }
+    }
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "Not a compiled Lox file."),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {version}.")
+            }
+            Self::Truncated => write!(f, "The file is truncated."),
+            Self::TrailingData => write!(f, "Unexpected data after the script."),
+            Self::InvalidUtf8 => write!(f, "A string is not valid UTF-8."),
+            Self::InvalidConstant(tag) => write!(f, "Unknown constant tag {tag}."),
+            Self::InvalidOpcode(op, offset) => {
+                write!(f, "Unknown opcode {op} at offset {offset}.")
+            }
+            Self::InvalidOperand(offset) => {
+                write!(f, "Invalid operand for the instruction at offset {offset}.")
+            }
+            Self::LineTableMismatch => write!(f, "The line table doesn't match the code."),
+            Self::NestingTooDeep => write!(f, "Functions are nested too deeply."),
+            Self::InvalidScript => write!(f, "The script can't take arguments or upvalues."),
+            Self::InvalidStack(offset) => {
+                write!(f, "The stack doesn't line up at offset {offset}.")
+            }
+            Self::InvalidHandler(offset) => {
+                write!(f, "Unbalanced exception handlers at offset {offset}.")
+            }
+            Self::FallsOffEnd => write!(f, "The code runs past the end of a function."),
+            Self::UnexpectedValue => {
+                write!(f, "An instruction got a value the compiler never gives it.")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TypeComparison(msg)
+            | Self::Concatenation(msg)
+            | Self::Arithmetic(msg)
+            | Self::MismatchedArguments(msg)
+            | Self::Thrown(msg) => write!(f, "{msg}"),
+            Self::UndefinedVariable(name) => write!(f, "Undefined variable '{name}'."),
+            Self::UndefinedVariableAssignment(name) => {
+                write!(f, "Cannot assign to undefined variable '{name}'.")
+            }
+            Self::NotACallableType(value) => {
+                write!(f, "Can only call functions and classes, not {value}.")
+            }
+            Self::NotAnInstance(value) => write!(f, "Only instances have properties, not {value}."),
+            Self::UndefinedProperty(name) => write!(f, "Undefined property '{name}'."),
+            Self::SuperclassMustBeClass(value) => {
+                write!(f, "Superclass must be a class, not {value}.")
+            }
+            Self::InheritFromSelf(name) => write!(f, "Class {name} can't inherit from itself."),
+            Self::StackOverflow => write!(f, "Stack overflow."),
+            Self::NotIndexable(value) => {
+                write!(f, "Only lists and maps can be indexed, not {value}.")
+            }
+            Self::InvalidIndex(value) => write!(f, "Index must be a whole number, not {value}."),
+            Self::IndexOutOfRange(index, len) => {
+                write!(f, "Index {index} is out of range for length {len}.")
+            }
+            Self::EmptyList => write!(f, "Can't pop from an empty list."),
+            Self::UndefinedKey(key) => write!(f, "Undefined key {key}."),
+            Self::UnhashableKey(value) => write!(
+                f,
+                "Map keys must be strings, numbers, booleans or nil, not {value}."
+            ),
+            Self::NotIterable(value) => write!(
+                f,
+                "Can only iterate over lists, maps, strings and iterators, not {value}."
+            ),
+            Self::InvalidRange(value) => write!(f, "Range bounds must be numbers, not {value}."),
+            Self::InvalidArgument(expected, value) => {
+                write!(f, "Expected {expected}, not {value}.")
+            }
+            Self::Output(err) => write!(f, "Could not write output: {err}."),
+        }
+    }
+}
+
+// A call that was active when a runtime error happened,
+// and the line it had reached in the called function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub function: String,
+    pub line: usize,
+}
+
+impl Frame {
+    pub fn new(function: &str, line: usize) -> Self {
+        Self {
+            function: function.to_string(),
+            line,
+        }
+    }
+}
+
+// Parse and runtime errors remember where they happened, when it's known.
+// Runtime errors are created without a location deep inside the interpreter,
+// and the location is filled in by the expression that failed.
+// They also carry the Lox call stack, innermost call first.
 #[derive(Debug, PartialEq)]
 pub enum RloxError {
     Cmdline(String),
     Scan(ScanError),
-    Parse(ParseError),
-    Resolver(ResolverError),
-    Interpret(RuntimeError),
+    Parse(ParseError, Option<Span>),
+    Resolver(ResolverError, Option<Span>),
+    Compile(CompileError, Option<Span>),
+    Bytecode(BytecodeError),
+    Interpret(RuntimeError, Option<Span>, Vec<Frame>),
+}
+
+impl RloxError {
+    // Attach a location to an error that doesn't have one yet
+    pub fn at(self, span: Span) -> Self {
+        match self {
+            Self::Parse(err, None) => Self::Parse(err, Some(span)),
+            Self::Compile(err, None) => Self::Compile(err, Some(span)),
+            Self::Interpret(err, None, trace) => Self::Interpret(err, Some(span), trace),
+            err => err,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::Scan(err) => Some(Span::new(err.line, err.position, 1)),
+            Self::Parse(_, span)
+            | Self::Resolver(_, span)
+            | Self::Compile(_, span)
+            | Self::Interpret(_, span, _) => *span,
+            _ => None,
+        }
+    }
+
+    // Attach the call stack to a runtime error that doesn't have one yet
+    // The trace is built from where the error happened.
+    pub fn with_trace(self, trace: impl FnOnce(Option<Span>) -> Vec<Frame>) -> Self {
+        match self {
+            Self::Interpret(err, span, frames) if frames.is_empty() => {
+                let frames = trace(span);
+                Self::Interpret(err, span, frames)
+            }
+            err => err,
+        }
+    }
+
+    pub fn trace(&self) -> &[Frame] {
+        match self {
+            Self::Interpret(_, _, trace) => trace,
+            _ => &[],
+        }
+    }
+
+    // Render the error the way rustc does, pointing into the source:
+    //
+    //   error: Undefined variable 'x'.
+    //    --> script.lox:2:7
+    //     |
+    //   2 | print(x);
+    //     |       ^
+    //     at show (script.lox:2)
+    //     at <script> (script.lox:5)
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let mut out = self.render_source(filename, source);
+
+        // Deep recursion makes for long traces, only the ends are interesting
+        let trace = self.trace();
+        let (head, tail) = match trace.len() {
+            len if len > MAX_TRACE => trace.split_at(MAX_TRACE / 2),
+            _ => (trace, &[][..]),
+        };
+        let hidden = tail.len().saturating_sub(MAX_TRACE / 2);
+
+        for frame in head {
+            writeln!(out, "    at {} ({filename}:{})", frame.function, frame.line).unwrap();
+        }
+        if hidden > 0 {
+            writeln!(out, "    ... {hidden} more calls").unwrap();
+        }
+        for frame in &tail[hidden..] {
+            writeln!(out, "    at {} ({filename}:{})", frame.function, frame.line).unwrap();
+        }
+        out
+    }
+
+    fn render_source(&self, filename: &str, source: &str) -> String {
+        let mut out = format!("error: {self}\n");
+        if let Some(span) = self.span() {
+            render_snippet(&mut out, filename, source, span);
+        }
+        out
+    }
+}
+
+// Show the line of source a span is on, with a caret under the span
+fn render_snippet(out: &mut String, filename: &str, source: &str, span: Span) {
+    let line = match source.lines().nth(span.line.wrapping_sub(1)) {
+        Some(line) => line,
+        None => {
+            writeln!(out, " --> {filename}:{span}").unwrap();
+            return;
+        }
+    };
+
+    let gutter = " ".repeat(span.line.to_string().len());
+    writeln!(out, "{gutter}--> {filename}:{span}").unwrap();
+    writeln!(out, "{gutter} |").unwrap();
+    writeln!(out, "{} | {}", span.line, line).unwrap();
+
+    // Without a column only the line can be shown
+    if span.column == 0 {
+        return;
+    }
+
+    // Keep tabs in the padding so the caret lines up with the source
+    let graphemes: Vec<&str> = line.graphemes(true).collect();
+    let start = (span.column - 1).min(graphemes.len());
+    let padding: String = graphemes[..start]
+        .iter()
+        .map(|g| if *g == "\t" { '\t' } else { ' ' })
+        .collect();
+    let len = span.len.clamp(1, (graphemes.len() - start).max(1));
+
+    writeln!(out, "{gutter} | {padding}{}", "^".repeat(len)).unwrap();
 }
 
 impl fmt::Display for RloxError {
@@ -82,14 +490,20 @@ impl fmt::Display for RloxError {
                     err.line, err.position, err.message, err.help
                 )
             }
-            Self::Parse(err) => {
-                write!(f, "{err:?}")
+            Self::Parse(err, _) => {
+                write!(f, "{err}")
+            }
+            Self::Resolver(err, _) => {
+                write!(f, "{err}")
+            }
+            Self::Compile(err, _) => {
+                write!(f, "{err}")
             }
-            Self::Resolver(err) => {
-                write!(f, "{err:?}")
+            Self::Bytecode(err) => {
+                write!(f, "Invalid bytecode: {err}")
             }
-            Self::Interpret(err) => {
-                write!(f, "{err:?}")
+            Self::Interpret(err, ..) => {
+                write!(f, "{err}")
             }
         }
     }
@@ -14,29 +14,57 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::fmt::{Debug, Display};
 use std::rc::Rc;
-use std::time::SystemTime;
 
 use super::environment::{self, RfEnv};
+use super::loxinstance::LoxInstance;
+use super::loxreturn::ControlFlow;
 use super::{Interpreter, LoxType};
 use crate::errors::Result;
-use crate::parser::ast::Stmt;
-use crate::scanner::Token;
+use crate::parser::ast::{Name, Stmt};
 
 // Callable trait defines an interface for functions, lambdas and classes
 pub trait Callable: Debug + Display {
-    fn arity(&self) -> u8;
+    fn name(&self) -> Rc<str>;
+    fn arity(&self) -> Arity;
     fn call(&self, interpreter: &mut Interpreter, arguments: &[LoxType]) -> Result<LoxType>;
 }
 
+// How many arguments a callable takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Fixed(u8),
+    Variadic(u8), // at least this many
+}
+
+impl Arity {
+    pub fn accepts(self, count: usize) -> bool {
+        match self {
+            Arity::Fixed(arity) => count == arity as usize,
+            Arity::Variadic(minimum) => count >= minimum as usize,
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Arity::Fixed(arity) => write!(f, "{arity}"),
+            Arity::Variadic(minimum) => write!(f, "at least {minimum}"),
+        }
+    }
+}
+
 // A user-defined function.
 #[derive(Debug, Clone)]
 pub struct LoxFunction {
-    name: String,
+    name: Rc<str>,
     closure: RfEnv,
-    params: Vec<Token>,
+    params: Vec<Name>,
     body: Vec<Stmt>,
+    is_initializer: bool,
 }
 
 impl Display for LoxFunction {
@@ -46,105 +74,311 @@ impl Display for LoxFunction {
 }
 
 impl LoxFunction {
-    pub fn new(name: &str, params: Vec<Token>, body: Vec<Stmt>, closure: RfEnv) -> Self {
+    pub fn new(
+        name: &str,
+        params: Vec<Name>,
+        body: Vec<Stmt>,
+        closure: RfEnv,
+        is_initializer: bool,
+    ) -> Self {
         Self {
             closure,
-            name: name.to_string(),
+            name: Rc::from(name),
             params,
             body,
+            is_initializer,
+        }
+    }
+
+    // Create a copy of this method with `this` bound to the instance.
+    // `this` lives in its own scope between the method and the class's
+    // enclosing environment, which is what the resolver expects.
+    pub fn bind(&self, instance: LoxInstance) -> LoxFunction {
+        let env = environment::from(&self.closure);
+        environment::define(&env, "this", LoxType::Instance(instance));
+
+        LoxFunction {
+            closure: env,
+            ..self.clone()
         }
     }
 }
 
 impl Callable for LoxFunction {
-    fn arity(&self) -> u8 {
-        self.params.len() as u8
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+
+    fn arity(&self) -> Arity {
+        Arity::Fixed(self.params.len() as u8)
     }
 
     // the function defintion stores its parameters.
     // we pass in the evaluated arguments when the function is called.
     // the parameters and arguments are zipped up, and each argument is bound to the
-    // parameter name from the function defintion in the function's environment.
+    // parameter name from the function defintion in a fresh environment for this call.
+    // Recursive calls and closures created by separate calls never share locals.
     fn call(&self, interpreter: &mut Interpreter, arguments: &[LoxType]) -> Result<LoxType> {
         assert_eq!(self.params.len(), arguments.len());
         log::trace!("calling: {}", self.name);
 
+        // Every call gets its own scope, enclosed by the closure
+        let env = environment::from(&self.closure);
+
         // Zip up arguments and their results
         // Bind each value to its name in the new environment
 
         let items = self.params.iter().zip(arguments.iter());
 
         for (token, loxtype) in items {
-            environment::define(&self.closure, token.lexeme(), loxtype.clone());
+            environment::define(&env, token.lexeme(), loxtype.clone());
         }
 
         // Preserve the old call stack
         let old_stack = Rc::clone(&interpreter.env);
 
         // Put in place the new stack
-        interpreter.env = Rc::clone(&self.closure);
+        interpreter.env = env;
 
         // Execute function and return its (optional) return value
-        let ret = match interpreter.execute_block(self.body.clone())? {
-            Some(ret) => Ok(ret.0),
-            None => Ok(LoxType::Nil),
-        };
+        let ret = interpreter.execute_block(self.body.clone());
 
         // Restore the old stack
         interpreter.env = old_stack;
 
-        ret
+        // Initializers always return the instance, even from an empty `return;`
+        if self.is_initializer {
+            ret?;
+            return environment::get_n(&self.closure, "this", 0);
+        }
+
+        match ret? {
+            Some(ControlFlow::Return(ret)) => Ok(ret),
+            Some(_) => unreachable!("resolver keeps `break` and `continue` inside loops"),
+            None => Ok(LoxType::Nil),
+        }
     }
 }
 
+// A user-defined class.
+// Calling the class creates a new instance of it.
 #[derive(Debug, Clone)]
-pub struct FfiClock;
+pub struct LoxClass {
+    name: Rc<str>,
+    superclass: Option<Rc<LoxClass>>,
+    methods: Rc<HashMap<String, LoxFunction>>,
+}
 
-impl Display for FfiClock {
+impl Display for LoxClass {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<native fn>")
+        write!(f, "<class {}>", self.name)
     }
 }
 
-impl Callable for FfiClock {
-    fn arity(&self) -> u8 {
-        0
+impl PartialEq for LoxClass {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.methods, &other.methods)
     }
-    fn call(&self, _: &mut Interpreter, _: &[LoxType]) -> Result<LoxType> {
-        Ok(LoxType::Number(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as f64,
-        ))
+}
+
+impl LoxClass {
+    pub fn new(
+        name: &str,
+        superclass: Option<LoxClass>,
+        methods: HashMap<String, LoxFunction>,
+    ) -> Self {
+        Self {
+            name: Rc::from(name),
+            superclass: superclass.map(Rc::new),
+            methods: Rc::new(methods),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Look for the method on this class, then up the superclass chain
+    pub fn find_method(&self, name: &str) -> Option<&LoxFunction> {
+        match self.methods.get(name) {
+            Some(method) => Some(method),
+            None => self
+                .superclass
+                .as_ref()
+                .and_then(|superclass| superclass.find_method(name)),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct FfiPrint;
-impl Display for FfiPrint {
+impl Callable for LoxClass {
+    // A class takes the same arguments as its initializer
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+
+    fn arity(&self) -> Arity {
+        match self.find_method("init") {
+            Some(init) => init.arity(),
+            None => Arity::Fixed(0),
+        }
+    }
+
+    fn call(&self, interpreter: &mut Interpreter, arguments: &[LoxType]) -> Result<LoxType> {
+        log::trace!("instantiating: {}", self.name);
+
+        let instance = LoxInstance::new(self.clone());
+
+        // Run the user's initializer against the new instance
+        if let Some(init) = self.find_method("init") {
+            init.bind(instance.clone()).call(interpreter, arguments)?;
+        }
+
+        Ok(LoxType::Instance(instance))
+    }
+}
+
+type NativeFn = dyn Fn(&[LoxType]) -> Result<LoxType>;
+
+// A built-in method of a value, such as a list's `push`, or a built-in
+// function like `range`. Methods have already captured the value they were
+// accessed on.
+#[derive(Clone)]
+pub struct NativeMethod {
+    name: &'static str,
+    arity: u8,
+    function: Rc<NativeFn>,
+}
+
+impl NativeMethod {
+    pub fn new(
+        name: &'static str,
+        arity: u8,
+        function: impl Fn(&[LoxType]) -> Result<LoxType> + 'static,
+    ) -> Self {
+        Self {
+            name,
+            arity,
+            function: Rc::new(function),
+        }
+    }
+}
+
+impl Debug for NativeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeMethod")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for NativeMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "<native fn>")
     }
 }
 
-impl Callable for FfiPrint {
-    fn arity(&self) -> u8 {
-        1
+impl Callable for NativeMethod {
+    fn name(&self) -> Rc<str> {
+        Rc::from(self.name)
+    }
+    fn arity(&self) -> Arity {
+        Arity::Fixed(self.arity)
     }
     fn call(&self, _: &mut Interpreter, arguments: &[LoxType]) -> Result<LoxType> {
-        println!("{}", arguments[0]);
-        Ok(LoxType::Nil)
+        (self.function)(arguments)
+    }
+}
+
+type HostFn = dyn Fn(&mut Interpreter, &[LoxType]) -> Result<LoxType>;
+
+// A global function written in Rust, like `clock` and `print`, or one an
+// embedder registered with `Interpreter::define_native`.
+// It gets the interpreter it's running in, so it can call back into Lox.
+#[derive(Clone)]
+pub struct NativeFunction {
+    name: Rc<str>,
+    arity: Arity,
+    function: Rc<HostFn>,
+}
+
+impl NativeFunction {
+    pub fn new(
+        name: &str,
+        arity: Arity,
+        function: impl Fn(&mut Interpreter, &[LoxType]) -> Result<LoxType> + 'static,
+    ) -> Self {
+        Self {
+            name: Rc::from(name),
+            arity,
+            function: Rc::new(function),
+        }
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn>")
+    }
+}
+
+impl Callable for NativeFunction {
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+    fn arity(&self) -> Arity {
+        self.arity
+    }
+    fn call(&self, interpreter: &mut Interpreter, arguments: &[LoxType]) -> Result<LoxType> {
+        (self.function)(interpreter, arguments)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    #[allow(unused_imports)]
     use super::*;
 
+    fn param(name: &str) -> Name {
+        Name::new(&crate::scanner::Token::new(
+            crate::scanner::TokenType::Identifier,
+            name.to_string(),
+            1,
+            1,
+        ))
+    }
+
     #[test]
     fn test_basics_callable() {
-        todo!();
+        let global = environment::new_global();
+        let fun = LoxFunction::new("add", vec![param("a"), param("b")], vec![], global, false);
+
+        assert_eq!(fun.arity(), Arity::Fixed(2));
+        assert_eq!(fun.to_string(), "<fn add#2()>");
+    }
+
+    #[test]
+    fn class_arity_follows_initializer() {
+        let global = environment::new_global();
+        let init = LoxFunction::new("init", vec![param("a")], vec![], Rc::clone(&global), true);
+
+        let empty = LoxClass::new("Empty", None, HashMap::new());
+        let mut methods = HashMap::new();
+        methods.insert("init".to_string(), init);
+        let class = LoxClass::new("Point", None, methods);
+        let subclass = LoxClass::new("Point3", Some(class.clone()), HashMap::new());
+
+        assert_eq!(empty.arity(), Arity::Fixed(0));
+        assert_eq!(class.arity(), Arity::Fixed(1));
+        assert_eq!(subclass.arity(), Arity::Fixed(1));
+        assert_eq!(class.to_string(), "<class Point>");
     }
 }
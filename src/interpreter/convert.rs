@@ -0,0 +1,407 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Conversions between Lox values and Rust types, for embedding.
+//
+// `FromLox` reads a Rust value out of a `LoxType`, failing with a runtime error
+// that says what was expected when the value doesn't fit, and `IntoLox` goes the
+// other way. Lists convert to and from `Vec`s and tuples, maps to and from
+// `HashMap`s, and nil to and from `None`. Rust functions whose arguments and
+// result convert can be called from Lox with `Interpreter::define_typed_native`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use super::callable::{Arity, NativeFunction};
+use super::loxlist::LoxList;
+use super::loxmap::LoxMap;
+use super::loxtype::LoxType;
+use crate::errors::{Result, RloxError, RuntimeError};
+
+pub trait FromLox: Sized {
+    fn from_lox(value: &LoxType) -> Result<Self>;
+}
+
+pub trait IntoLox {
+    fn into_lox(self) -> LoxType;
+}
+
+// Rust types that can be the keys of a Lox map: strings, numbers and booleans
+pub trait LoxKey: IntoLox {}
+
+// What a typed native function returns: a value, or a value or an error
+pub trait IntoLoxResult {
+    fn into_lox_result(self) -> Result<LoxType>;
+}
+
+impl FromLox for LoxType {
+    fn from_lox(value: &LoxType) -> Result<Self> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoLox for LoxType {
+    fn into_lox(self) -> LoxType {
+        self
+    }
+}
+
+impl IntoLox for () {
+    fn into_lox(self) -> LoxType {
+        LoxType::Nil
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: &LoxType) -> Result<Self> {
+        match value {
+            LoxType::Number(n) => Ok(*n),
+            other => Err(invalid_argument("a number", other)),
+        }
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self) -> LoxType {
+        LoxType::Number(self)
+    }
+}
+
+// Integers must be whole numbers that fit in the type
+macro_rules! integer {
+    ($($t:ty),*) => {$(
+        impl FromLox for $t {
+            fn from_lox(value: &LoxType) -> Result<Self> {
+                if let LoxType::Number(n) = value {
+                    let whole = *n as i128;
+                    if whole as f64 == *n {
+                        if let Ok(integer) = <$t>::try_from(whole) {
+                            return Ok(integer);
+                        }
+                    }
+                }
+                let expected = format!("a whole number from {} to {}", <$t>::MIN, <$t>::MAX);
+                Err(invalid_argument(&expected, value))
+            }
+        }
+
+        impl IntoLox for $t {
+            fn into_lox(self) -> LoxType {
+                LoxType::Number(self as f64)
+            }
+        }
+
+        impl LoxKey for $t {}
+    )*};
+}
+
+integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromLox for bool {
+    fn from_lox(value: &LoxType) -> Result<Self> {
+        match value {
+            LoxType::Bool(b) => Ok(*b),
+            other => Err(invalid_argument("a boolean", other)),
+        }
+    }
+}
+
+impl IntoLox for bool {
+    fn into_lox(self) -> LoxType {
+        LoxType::Bool(self)
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: &LoxType) -> Result<Self> {
+        match value {
+            LoxType::String(s) => Ok(s.clone()),
+            other => Err(invalid_argument("a string", other)),
+        }
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self) -> LoxType {
+        LoxType::String(self)
+    }
+}
+
+impl IntoLox for &str {
+    fn into_lox(self) -> LoxType {
+        LoxType::String(self.to_string())
+    }
+}
+
+impl LoxKey for f64 {}
+impl LoxKey for bool {}
+impl LoxKey for String {}
+impl LoxKey for &str {}
+
+// nil is None, anything else has to convert
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: &LoxType) -> Result<Self> {
+        match value {
+            LoxType::Nil => Ok(None),
+            other => T::from_lox(other).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self) -> LoxType {
+        self.map_or(LoxType::Nil, T::into_lox)
+    }
+}
+
+impl<T: FromLox> FromLox for Vec<T> {
+    fn from_lox(value: &LoxType) -> Result<Self> {
+        match value {
+            LoxType::List(list) => (0..list.len())
+                .filter_map(|position| list.item_at(position))
+                .map(|item| T::from_lox(&item))
+                .collect(),
+            other => Err(invalid_argument("a list", other)),
+        }
+    }
+}
+
+impl<T: IntoLox> IntoLox for Vec<T> {
+    fn into_lox(self) -> LoxType {
+        LoxType::List(LoxList::new(self.into_iter().map(T::into_lox).collect()))
+    }
+}
+
+impl<K: FromLox + Eq + Hash, V: FromLox> FromLox for HashMap<K, V> {
+    fn from_lox(value: &LoxType) -> Result<Self> {
+        let map = match value {
+            LoxType::Map(map) => map,
+            other => return Err(invalid_argument("a map", other)),
+        };
+
+        let mut entries = HashMap::with_capacity(map.len());
+        for key in (0..map.len()).filter_map(|position| map.key_at(position)) {
+            let value = map.get(&key)?;
+            entries.insert(K::from_lox(&key)?, V::from_lox(&value)?);
+        }
+        Ok(entries)
+    }
+}
+
+impl<K: LoxKey, V: IntoLox> IntoLox for HashMap<K, V> {
+    fn into_lox(self) -> LoxType {
+        let map = LoxMap::default();
+        for (key, value) in self {
+            map.set(&key.into_lox(), value.into_lox())
+                .expect("LoxKey types are always valid keys");
+        }
+        LoxType::Map(map)
+    }
+}
+
+// Tuples are lists of exactly their length
+macro_rules! tuple {
+    ($len:literal: $($t:ident $position:tt),*) => {
+        impl<$($t: FromLox),*> FromLox for ($($t,)*) {
+            fn from_lox(value: &LoxType) -> Result<Self> {
+                match value {
+                    LoxType::List(list) if list.len() == $len => Ok((
+                        $($t::from_lox(&list.item_at($position).unwrap_or(LoxType::Nil))?,)*
+                    )),
+                    other => Err(invalid_argument(concat!("a list of ", $len, " elements"), other)),
+                }
+            }
+        }
+
+        impl<$($t: IntoLox),*> IntoLox for ($($t,)*) {
+            fn into_lox(self) -> LoxType {
+                LoxType::List(LoxList::new(vec![$(self.$position.into_lox()),*]))
+            }
+        }
+    };
+}
+
+tuple!(1: A 0);
+tuple!(2: A 0, B 1);
+tuple!(3: A 0, B 1, C 2);
+tuple!(4: A 0, B 1, C 2, D 3);
+
+impl<T: IntoLox> IntoLoxResult for T {
+    fn into_lox_result(self) -> Result<LoxType> {
+        Ok(self.into_lox())
+    }
+}
+
+impl<T: IntoLox> IntoLoxResult for Result<T> {
+    fn into_lox_result(self) -> Result<LoxType> {
+        self.map(T::into_lox)
+    }
+}
+
+// A Rust function that takes arguments of the types `Args`.
+// Its arity is the number of arguments, and each is converted before calling it.
+pub trait IntoNative<Args> {
+    fn into_native(self, name: &str) -> NativeFunction;
+}
+
+macro_rules! into_native {
+    ($len:literal: $($t:ident $position:tt),*) => {
+        impl<F, R, $($t),*> IntoNative<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R + 'static,
+            R: IntoLoxResult,
+            $($t: FromLox,)*
+        {
+            #[allow(unused_variables)]
+            fn into_native(self, name: &str) -> NativeFunction {
+                NativeFunction::new(name, Arity::Fixed($len), move |_, arguments| {
+                    self($($t::from_lox(&arguments[$position])?),*).into_lox_result()
+                })
+            }
+        }
+    };
+}
+
+into_native!(0:);
+into_native!(1: A 0);
+into_native!(2: A 0, B 1);
+into_native!(3: A 0, B 1, C 2);
+into_native!(4: A 0, B 1, C 2, D 3);
+into_native!(5: A 0, B 1, C 2, D 3, E 4);
+
+fn invalid_argument(expected: &str, value: &LoxType) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::InvalidArgument(expected.to_string(), value.to_string()),
+        None,
+        Vec::new(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+
+    fn eval(source: &str) -> LoxType {
+        Interpreter::new().eval(source).unwrap()
+    }
+
+    fn expected(err: RloxError) -> String {
+        match err {
+            RloxError::Interpret(RuntimeError::InvalidArgument(expected, _), ..) => expected,
+            other => panic!("expected an invalid argument, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn scalars() {
+        assert_eq!(f64::from_lox(&eval("1.5;")).unwrap(), 1.5);
+        assert_eq!(i32::from_lox(&eval("-42;")).unwrap(), -42);
+        assert_eq!(u8::from_lox(&eval("255;")).unwrap(), 255);
+        assert!(bool::from_lox(&eval("1 < 2;")).unwrap());
+        assert_eq!(String::from_lox(&eval("\"a\" + \"b\";")).unwrap(), "ab");
+        assert_eq!(Option::<f64>::from_lox(&LoxType::Nil).unwrap(), None);
+        assert_eq!(Option::<f64>::from_lox(&eval("2;")).unwrap(), Some(2.0));
+
+        assert_eq!(
+            expected(u8::from_lox(&eval("256;")).unwrap_err()),
+            "a whole number from 0 to 255"
+        );
+        assert!(i64::from_lox(&eval("1.5;")).is_err());
+        assert!(u64::from_lox(&eval("-1;")).is_err());
+        assert!(i64::from_lox(&LoxType::Number(2f64.powi(63))).is_err());
+        assert_eq!(
+            expected(f64::from_lox(&eval("\"1\";")).unwrap_err()),
+            "a number"
+        );
+        assert_eq!(
+            String::from_lox(&LoxType::Nil).unwrap_err().to_string(),
+            "Expected a string, not nil."
+        );
+
+        assert!(matches!(7u16.into_lox(), LoxType::Number(n) if n == 7.0));
+        assert!(matches!("s".into_lox(), LoxType::String(s) if s == "s"));
+        assert!(matches!(None::<bool>.into_lox(), LoxType::Nil));
+        assert!(matches!(().into_lox(), LoxType::Nil));
+    }
+
+    #[test]
+    fn collections() {
+        let list = Vec::<Option<String>>::from_lox(&eval("[\"a\", nil];")).unwrap();
+        assert_eq!(list, [Some("a".to_string()), None]);
+        let map = HashMap::<String, Vec<i32>>::from_lox(&eval("({\"a\": [1, 2]});")).unwrap();
+        assert_eq!(map, HashMap::from([("a".to_string(), vec![1, 2])]));
+        let pair = <(String, f64)>::from_lox(&eval("[\"x\", 1];")).unwrap();
+        assert_eq!(pair, ("x".to_string(), 1.0));
+
+        assert_eq!(
+            expected(<(f64, f64)>::from_lox(&eval("[1, 2, 3];")).unwrap_err()),
+            "a list of 2 elements"
+        );
+        assert_eq!(
+            expected(Vec::<f64>::from_lox(&eval("[1, true];")).unwrap_err()),
+            "a number"
+        );
+        assert!(HashMap::<String, f64>::from_lox(&eval("({1: 2});")).is_err());
+
+        assert_eq!(vec![(1, "one")].into_lox().to_string(), "[[1, \"one\"]]");
+        let map = HashMap::from([(true, vec![1.5])]).into_lox();
+        assert_eq!(map.to_string(), "{true: [1.5]}");
+    }
+
+    #[test]
+    fn typed_native_functions() {
+        let mut interpreter = Interpreter::new();
+        interpreter.define_typed_native("hypot", |a: f64, b: f64| a.hypot(b));
+        interpreter.define_typed_native("greet", |name: String, times: usize| {
+            format!("hi {name}").repeat(times)
+        });
+        interpreter.define_typed_native("first", |items: Vec<LoxType>| -> Result<LoxType> {
+            match items.into_iter().next() {
+                Some(item) => Ok(item),
+                None => Err(RloxError::Interpret(
+                    RuntimeError::EmptyList,
+                    None,
+                    Vec::new(),
+                )),
+            }
+        });
+        interpreter.define_typed_native("nothing", || ());
+
+        let value = interpreter
+            .eval("[hypot(3, 4), greet(\"ada\", 2), first([nil, 1]), nothing()];")
+            .unwrap();
+        assert_eq!(value.to_string(), "[5, \"hi adahi ada\", nil, nil]");
+
+        // Arity comes from the function, and arguments are checked
+        assert!(matches!(
+            interpreter.eval("hypot(1);"),
+            Err(RloxError::Interpret(
+                RuntimeError::MismatchedArguments(_),
+                ..
+            ))
+        ));
+        let err = interpreter.eval("greet(\"ada\", -1);").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Expected a whole number from 0 to {}, not -1.", usize::MAX)
+        );
+        assert!(matches!(
+            interpreter.eval("first([]);"),
+            Err(RloxError::Interpret(RuntimeError::EmptyList, ..))
+        ));
+    }
+}
@@ -98,7 +98,11 @@ pub fn get(rfenv: &RfEnv, key: &str) -> Result<LoxType> {
         None => {
             log::trace!("  {} not found in this environment.", key);
             match rfenv.borrow().parent {
-                None => Err(RloxError::Interpret(RuntimeError::UndefinedVariable)),
+                None => Err(RloxError::Interpret(
+                    RuntimeError::UndefinedVariable(key.to_string()),
+                    None,
+                    Vec::new(),
+                )),
                 Some(ref parent) => get(parent, key),
             }
         }
@@ -111,8 +115,13 @@ pub fn assign_n(rfenv: &RfEnv, key: &str, val: LoxType, depth: u32) -> Result<()
 
     let parent = ancestor(Rc::clone(rfenv), depth);
 
-    get(&parent, key)
-        .map_err(|_| RloxError::Interpret(RuntimeError::UndefinedVariableAssignment))?;
+    get(&parent, key).map_err(|_| {
+        RloxError::Interpret(
+            RuntimeError::UndefinedVariableAssignment(key.to_string()),
+            None,
+            Vec::new(),
+        )
+    })?;
 
     parent.borrow_mut().env.insert(key.to_string(), val);
 
@@ -123,12 +132,18 @@ pub fn assign(rfenv: &RfEnv, key: &str, val: LoxType) -> Result<()> {
     log::trace!("slow assigning: {} -> {}", key, val);
 
     // return error if variable isn't defined.
-    get(rfenv, key).map_err(|_| RloxError::Interpret(RuntimeError::UndefinedVariableAssignment))?;
+    get(rfenv, key).map_err(|_| {
+        RloxError::Interpret(
+            RuntimeError::UndefinedVariableAssignment(key.to_string()),
+            None,
+            Vec::new(),
+        )
+    })?;
 
     // we know the assignment exists somewhere, so now we just need to find it.
     // check current scope
 
-    if rfenv.borrow().env.get(key).is_none() {
+    if !rfenv.borrow().env.contains_key(key) {
         match &rfenv.borrow().parent {
             Some(parent) => return assign(parent, key, val),
             _ => unreachable!(),
@@ -198,10 +213,10 @@ mod tests {
         define(&root, "name1", LoxType::Bool(true));
         define(&root, "name2", LoxType::Bool(false));
 
-        let env1 = from(&root);
+        let _env1 = from(&root);
         define(&root, "name3", LoxType::String("Found".to_string()));
 
-        let env2 = from(&root);
+        let _env2 = from(&root);
 
         if get(&root, "name1").is_err() {
             panic!("Nested environments did not work");
@@ -227,7 +242,7 @@ mod tests {
             panic!("name was not defined.");
         }
 
-        assign(&env1, "name1", LoxType::Bool(true));
+        let _ = assign(&env1, "name1", LoxType::Bool(true));
         if let Ok(LoxType::Bool(true)) = get(&env1, "name1") {
         } else {
             panic!("name was not defined.");
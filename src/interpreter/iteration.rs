@@ -0,0 +1,118 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// What a for-in loop steps through.
+//
+// Lists, maps and strings are stepped through directly, so a nil element
+// doesn't end the loop. Anything callable is an iterator: it's called for
+// each value, until it returns nil. Instances are iterated through whatever
+// their `iterator` method returns.
+
+use std::cell::Cell;
+use std::vec;
+
+use super::callable::NativeMethod;
+use super::loxlist::LoxList;
+use super::loxmap::LoxMap;
+use super::{Interpreter, LoxType};
+use crate::errors::{Result, RloxError, RuntimeError};
+use crate::scanner;
+
+pub enum Iteration {
+    List(LoxList, usize),
+    Map(LoxMap, usize),
+    Graphemes(vec::IntoIter<String>),
+    Iterator(LoxType),
+}
+
+impl Iteration {
+    // `line` is where the loop is, for backtraces of calls it makes
+    pub fn new(interpreter: &mut Interpreter, mut value: LoxType, line: usize) -> Result<Self> {
+        while let LoxType::Instance(instance) = &value {
+            let method = instance.get("iterator").map_err(|_| not_iterable(&value))?;
+            value = interpreter.call_value(method, &[], line)?;
+        }
+
+        let iteration = match value {
+            LoxType::List(list) => Iteration::List(list, 0),
+            LoxType::Map(map) => Iteration::Map(map, 0),
+            LoxType::String(s) => {
+                let graphemes: Vec<String> = scanner::graphemes(&s).map(str::to_string).collect();
+                Iteration::Graphemes(graphemes.into_iter())
+            }
+            LoxType::Fun(_) | LoxType::NativeMethod(_) | LoxType::Native(_) => {
+                Iteration::Iterator(value)
+            }
+            other => return Err(not_iterable(&other)),
+        };
+        Ok(iteration)
+    }
+
+    pub fn next(&mut self, interpreter: &mut Interpreter, line: usize) -> Result<Option<LoxType>> {
+        match self {
+            Iteration::List(list, position) => Ok(step(list.item_at(*position), position)),
+            Iteration::Map(map, position) => Ok(step(map.key_at(*position), position)),
+            Iteration::Graphemes(graphemes) => Ok(graphemes.next().map(LoxType::String)),
+            Iteration::Iterator(iterator) => {
+                match interpreter.call_value(iterator.clone(), &[], line)? {
+                    LoxType::Nil => Ok(None),
+                    value => Ok(Some(value)),
+                }
+            }
+        }
+    }
+}
+
+fn step(next: Option<LoxType>, position: &mut usize) -> Option<LoxType> {
+    *position += 1;
+    next
+}
+
+// range(start, end) counts up from `start` to just before `end`
+pub fn range(_: &mut Interpreter, arguments: &[LoxType]) -> Result<LoxType> {
+    let (start, end) = match arguments {
+        [LoxType::Number(start), LoxType::Number(end)] => (*start, *end),
+        [LoxType::Number(_), other] | [other, _] => {
+            return Err(RloxError::Interpret(
+                RuntimeError::InvalidRange(other.to_string()),
+                None,
+                Vec::new(),
+            ))
+        }
+        _ => unreachable!("arity is checked before calling"),
+    };
+
+    let next = Cell::new(start);
+    let iterator = NativeMethod::new("next", 0, move |_| {
+        let current = next.get();
+        match current < end {
+            true => {
+                next.set(current + 1.0);
+                Ok(LoxType::Number(current))
+            }
+            false => Ok(LoxType::Nil),
+        }
+    });
+    Ok(LoxType::NativeMethod(iterator))
+}
+
+fn not_iterable(value: &LoxType) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::NotIterable(value.to_string()),
+        None,
+        Vec::new(),
+    )
+}
@@ -0,0 +1,87 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::rc::Rc;
+
+use super::callable::LoxClass;
+use super::loxtype::LoxType;
+use crate::errors::{Result, RloxError, RuntimeError};
+
+// An instance of a user-defined class.
+// Instances are reference types, clones share the same fields.
+#[derive(Clone)]
+pub struct LoxInstance {
+    class: LoxClass,
+    fields: Rc<RefCell<HashMap<String, LoxType>>>,
+}
+
+impl LoxInstance {
+    pub fn new(class: LoxClass) -> Self {
+        Self {
+            class,
+            fields: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    // Fields shadow methods of the same name
+    pub fn get(&self, name: &str) -> Result<LoxType> {
+        if let Some(val) = self.fields.borrow().get(name) {
+            return Ok(val.clone());
+        }
+
+        match self.class.find_method(name) {
+            Some(method) => Ok(LoxType::Fun(method.bind(self.clone()))),
+            None => Err(RloxError::Interpret(
+                RuntimeError::UndefinedProperty(name.to_string()),
+                None,
+                Vec::new(),
+            )),
+        }
+    }
+
+    // A field, without looking for a method of the same name
+    pub fn field(&self, name: &str) -> Option<LoxType> {
+        self.fields.borrow().get(name).cloned()
+    }
+
+    pub fn set(&self, name: &str, val: LoxType) {
+        self.fields.borrow_mut().insert(name.to_string(), val);
+    }
+}
+
+impl PartialEq for LoxInstance {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.fields, &other.fields)
+    }
+}
+
+// Fields may refer back to the instance, so don't recurse into them.
+impl Debug for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoxInstance")
+            .field("class", &self.class.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} instance>", self.class.name())
+    }
+}
@@ -0,0 +1,241 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Debug, Display};
+use std::rc::Rc;
+
+use super::callable::NativeMethod;
+use super::loxtype::LoxType;
+use crate::errors::{Result, RloxError, RuntimeError};
+
+// A list of values.
+// Lists are reference types, clones share the same elements.
+#[derive(Clone, Default)]
+pub struct LoxList {
+    items: Rc<RefCell<Vec<LoxType>>>,
+}
+
+impl LoxList {
+    pub fn new(items: Vec<LoxType>) -> Self {
+        Self {
+            items: Rc::new(RefCell::new(items)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: &LoxType) -> Result<LoxType> {
+        let index = to_index(index, self.len(), self.len())?;
+        Ok(self.items.borrow()[index].clone())
+    }
+
+    pub fn item_at(&self, position: usize) -> Option<LoxType> {
+        self.items.borrow().get(position).cloned()
+    }
+
+    pub fn set(&self, index: &LoxType, value: LoxType) -> Result<()> {
+        let index = to_index(index, self.len(), self.len())?;
+        self.items.borrow_mut()[index] = value;
+        Ok(())
+    }
+
+    // Built-in methods, bound to this list
+    pub fn method(&self, name: &str) -> Result<LoxType> {
+        let list = self.clone();
+        let method = match name {
+            "length" => {
+                NativeMethod::new("length", 0, move |_| Ok(LoxType::Number(list.len() as f64)))
+            }
+            "push" => NativeMethod::new("push", 1, move |arguments| {
+                list.items.borrow_mut().push(arguments[0].clone());
+                Ok(LoxType::Nil)
+            }),
+            "pop" => NativeMethod::new("pop", 0, move |_| {
+                list.items
+                    .borrow_mut()
+                    .pop()
+                    .ok_or_else(|| error(RuntimeError::EmptyList))
+            }),
+            // Inserting at the length appends
+            "insert" => NativeMethod::new("insert", 2, move |arguments| {
+                let index = to_index(&arguments[0], list.len(), list.len() + 1)?;
+                list.items.borrow_mut().insert(index, arguments[1].clone());
+                Ok(LoxType::Nil)
+            }),
+            "remove" => NativeMethod::new("remove", 1, move |arguments| {
+                let index = to_index(&arguments[0], list.len(), list.len())?;
+                Ok(list.items.borrow_mut().remove(index))
+            }),
+            // A new list from `start` up to, but not including, `end`
+            "slice" => NativeMethod::new("slice", 2, move |arguments| {
+                let start = to_index(&arguments[0], list.len(), list.len() + 1)?;
+                let end = to_index(&arguments[1], list.len(), list.len() + 1)?;
+                let items = match start < end {
+                    true => list.items.borrow()[start..end].to_vec(),
+                    false => Vec::new(),
+                };
+                Ok(LoxType::List(LoxList::new(items)))
+            }),
+            // Each call returns the next element, then nil once there are none left
+            "iterator" => NativeMethod::new("iterator", 0, move |_| {
+                let list = list.clone();
+                let position = Cell::new(0);
+                let next = NativeMethod::new("next", 0, move |_| {
+                    let item = list.item_at(position.get());
+                    position.set(position.get() + 1);
+                    Ok(item.unwrap_or(LoxType::Nil))
+                });
+                Ok(LoxType::NativeMethod(next))
+            }),
+            _ => return Err(error(RuntimeError::UndefinedProperty(name.to_string()))),
+        };
+        Ok(LoxType::NativeMethod(method))
+    }
+
+    // `seen` holds the lists and maps being written.
+    // Lists may contain themselves, which is printed as [...]
+    pub(super) fn write(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        seen: &mut Vec<*const ()>,
+    ) -> fmt::Result {
+        let ptr = Rc::as_ptr(&self.items) as *const ();
+        if seen.contains(&ptr) {
+            return write!(f, "[...]");
+        }
+        seen.push(ptr);
+
+        write!(f, "[")?;
+        for (i, item) in self.items.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            item.write_element(f, seen)?;
+        }
+        seen.pop();
+        write!(f, "]")
+    }
+}
+
+// Indices are whole numbers below `limit`, which is the length for reading
+// an element and one past it for positions between elements
+pub(super) fn to_index(value: &LoxType, len: usize, limit: usize) -> Result<usize> {
+    match value {
+        LoxType::Number(n) if n.fract() == 0.0 => match *n >= 0.0 && *n < limit as f64 {
+            true => Ok(*n as usize),
+            false => Err(error(RuntimeError::IndexOutOfRange(value.to_string(), len))),
+        },
+        other => Err(error(RuntimeError::InvalidIndex(other.to_string()))),
+    }
+}
+
+fn error(err: RuntimeError) -> RloxError {
+    RloxError::Interpret(err, None, Vec::new())
+}
+
+impl PartialEq for LoxList {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.items, &other.items)
+    }
+}
+
+// Lists may contain themselves, so don't recurse into them.
+impl Debug for LoxList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoxList")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for LoxList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(values: &[f64]) -> LoxList {
+        LoxList::new(values.iter().map(|n| LoxType::Number(*n)).collect())
+    }
+
+    #[test]
+    fn display() {
+        let list = numbers(&[1., 2., 3.]);
+        assert_eq!(list.to_string(), "[1, 2, 3]");
+        assert_eq!(LoxList::default().to_string(), "[]");
+
+        let nested = LoxList::new(vec![
+            LoxType::String("a".to_string()),
+            LoxType::List(list),
+            LoxType::Nil,
+        ]);
+        assert_eq!(nested.to_string(), "[\"a\", [1, 2, 3], nil]");
+
+        // A list containing itself doesn't recurse forever
+        nested
+            .items
+            .borrow_mut()
+            .push(LoxType::List(nested.clone()));
+        assert_eq!(nested.to_string(), "[\"a\", [1, 2, 3], nil, [...]]");
+
+        // Neither does one nested too deep
+        let mut deep = LoxList::new(vec![]);
+        for _ in 0..1000 {
+            deep = LoxList::new(vec![LoxType::List(deep)]);
+        }
+        assert_eq!(
+            deep.to_string(),
+            format!("{}[...]{}", "[".repeat(100), "]".repeat(100))
+        );
+    }
+
+    #[test]
+    fn indices() {
+        let list = numbers(&[1., 2., 3.]);
+        assert_eq!(list.get(&LoxType::Number(2.)).unwrap().to_string(), "3");
+
+        list.set(&LoxType::Number(0.), LoxType::Nil).unwrap();
+        assert_eq!(list.to_string(), "[nil, 2, 3]");
+
+        assert_eq!(
+            list.get(&LoxType::Number(3.)).err(),
+            Some(error(RuntimeError::IndexOutOfRange("3".to_string(), 3)))
+        );
+        assert_eq!(
+            list.get(&LoxType::Number(-1.)).err(),
+            Some(error(RuntimeError::IndexOutOfRange("-1".to_string(), 3)))
+        );
+        assert_eq!(
+            list.get(&LoxType::Number(0.5)).err(),
+            Some(error(RuntimeError::InvalidIndex("0.5".to_string())))
+        );
+        assert_eq!(
+            list.get(&LoxType::String("0".to_string())).err(),
+            Some(error(RuntimeError::InvalidIndex("0".to_string())))
+        );
+    }
+}
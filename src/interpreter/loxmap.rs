@@ -0,0 +1,250 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Debug, Display};
+use std::rc::Rc;
+
+use super::callable::NativeMethod;
+use super::loxlist::LoxList;
+use super::loxtype::LoxType;
+use crate::errors::{Result, RloxError, RuntimeError};
+use crate::ordmap::OrderedMap;
+
+// The values that can be used as keys of a map.
+// Numbers are keyed by their bits, with -0 folded into 0 since they're equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+impl MapKey {
+    pub fn new(value: &LoxType) -> Result<Self> {
+        match value {
+            LoxType::Nil => Ok(MapKey::Nil),
+            LoxType::Bool(v) => Ok(MapKey::Bool(*v)),
+            LoxType::Number(n) if *n == 0.0 => Ok(MapKey::Number(0f64.to_bits())),
+            LoxType::Number(n) => Ok(MapKey::Number(n.to_bits())),
+            LoxType::String(s) => Ok(MapKey::String(s.clone())),
+            other => Err(error(RuntimeError::UnhashableKey(other.to_string()))),
+        }
+    }
+
+    pub fn value(&self) -> LoxType {
+        match self {
+            MapKey::Nil => LoxType::Nil,
+            MapKey::Bool(v) => LoxType::Bool(*v),
+            MapKey::Number(bits) => LoxType::Number(f64::from_bits(*bits)),
+            MapKey::String(s) => LoxType::String(s.clone()),
+        }
+    }
+}
+
+// Keys are written the way they'd appear inside a map
+impl Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value().write_element(f, &mut Vec::new())
+    }
+}
+
+// A map from keys to values, which remembers the order keys were added in.
+// Maps are reference types, clones share the same entries.
+#[derive(Clone, Default)]
+pub struct LoxMap {
+    entries: Rc<RefCell<OrderedMap<MapKey, LoxType>>>,
+}
+
+impl LoxMap {
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &LoxType) -> Result<LoxType> {
+        let key = MapKey::new(key)?;
+        match self.entries.borrow().get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => Err(error(RuntimeError::UndefinedKey(key.to_string()))),
+        }
+    }
+
+    pub fn set(&self, key: &LoxType, value: LoxType) -> Result<()> {
+        let key = MapKey::new(key)?;
+        self.entries.borrow_mut().insert(key, value);
+        Ok(())
+    }
+
+    // The key at `position` in insertion order
+    pub fn key_at(&self, position: usize) -> Option<LoxType> {
+        self.entries
+            .borrow()
+            .get_index(position)
+            .map(|(key, _)| key.value())
+    }
+
+    // Built-in methods, bound to this map
+    pub fn method(&self, name: &str) -> Result<LoxType> {
+        let map = self.clone();
+        let method = match name {
+            "length" => {
+                NativeMethod::new("length", 0, move |_| Ok(LoxType::Number(map.len() as f64)))
+            }
+            "has" => NativeMethod::new("has", 1, move |arguments| {
+                let key = MapKey::new(&arguments[0])?;
+                Ok(LoxType::Bool(map.entries.borrow().contains_key(&key)))
+            }),
+            "remove" => NativeMethod::new("remove", 1, move |arguments| {
+                let key = MapKey::new(&arguments[0])?;
+                let removed = map.entries.borrow_mut().remove(&key);
+                removed.ok_or_else(|| error(RuntimeError::UndefinedKey(key.to_string())))
+            }),
+            "keys" => NativeMethod::new("keys", 0, move |_| {
+                let keys = map.entries.borrow().keys().map(MapKey::value).collect();
+                Ok(LoxType::List(LoxList::new(keys)))
+            }),
+            "values" => NativeMethod::new("values", 0, move |_| {
+                let values = map.entries.borrow().values().cloned().collect();
+                Ok(LoxType::List(LoxList::new(values)))
+            }),
+            // Each entry as a [key, value] list
+            "entries" => NativeMethod::new("entries", 0, move |_| {
+                let entries = map
+                    .entries
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| {
+                        LoxType::List(LoxList::new(vec![key.value(), value.clone()]))
+                    })
+                    .collect();
+                Ok(LoxType::List(LoxList::new(entries)))
+            }),
+            // Each call returns the next key, then nil once there are none left
+            "iterator" => NativeMethod::new("iterator", 0, move |_| {
+                let map = map.clone();
+                let position = Cell::new(0);
+                let next = NativeMethod::new("next", 0, move |_| {
+                    let key = map.key_at(position.get());
+                    position.set(position.get() + 1);
+                    Ok(key.unwrap_or(LoxType::Nil))
+                });
+                Ok(LoxType::NativeMethod(next))
+            }),
+            _ => return Err(error(RuntimeError::UndefinedProperty(name.to_string()))),
+        };
+        Ok(LoxType::NativeMethod(method))
+    }
+
+    pub(super) fn write(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        seen: &mut Vec<*const ()>,
+    ) -> fmt::Result {
+        let ptr = Rc::as_ptr(&self.entries) as *const ();
+        if seen.contains(&ptr) {
+            return write!(f, "{{...}}");
+        }
+        seen.push(ptr);
+
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.entries.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{key}: ")?;
+            value.write_element(f, seen)?;
+        }
+        seen.pop();
+        write!(f, "}}")
+    }
+}
+
+fn error(err: RuntimeError) -> RloxError {
+    RloxError::Interpret(err, None, Vec::new())
+}
+
+impl PartialEq for LoxMap {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.entries, &other.entries)
+    }
+}
+
+// Maps may contain themselves, so don't recurse into them.
+impl Debug for LoxMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoxMap")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for LoxMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> LoxType {
+        LoxType::String(s.to_string())
+    }
+
+    #[test]
+    fn keys() {
+        let map = LoxMap::default();
+        map.set(&string("a"), LoxType::Number(1.)).unwrap();
+        map.set(&LoxType::Number(-0.), string("zero")).unwrap();
+        map.set(&LoxType::Bool(true), LoxType::Nil).unwrap();
+        map.set(&LoxType::Nil, LoxType::List(LoxList::default()))
+            .unwrap();
+        map.set(&string("a"), LoxType::Number(2.)).unwrap();
+
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get(&LoxType::Number(0.)).unwrap().to_string(), "zero");
+        assert_eq!(
+            map.to_string(),
+            "{\"a\": 2, 0: \"zero\", true: nil, nil: []}"
+        );
+
+        assert_eq!(
+            map.get(&string("b")).err(),
+            Some(error(RuntimeError::UndefinedKey("\"b\"".to_string())))
+        );
+        assert_eq!(
+            map.set(&LoxType::List(LoxList::default()), LoxType::Nil)
+                .err(),
+            Some(error(RuntimeError::UnhashableKey("[]".to_string())))
+        );
+    }
+
+    #[test]
+    fn nested_display() {
+        let map = LoxMap::default();
+        assert_eq!(map.to_string(), "{}");
+
+        // A map containing itself doesn't recurse forever
+        map.set(&string("self"), LoxType::Map(map.clone())).unwrap();
+        assert_eq!(map.to_string(), "{\"self\": {...}}");
+    }
+}
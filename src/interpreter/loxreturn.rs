@@ -15,4 +15,10 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::loxtype::LoxType;
-pub struct Return(pub LoxType);
+
+// Statements that stop the rest of a block from running
+pub enum ControlFlow {
+    Return(LoxType), // leave the function with a value
+    Break,           // leave the innermost loop
+    Continue,        // go on to the innermost loop's next iteration
+}
@@ -0,0 +1,120 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::callable::NativeMethod;
+use super::loxlist::{self, LoxList};
+use super::loxtype::LoxType;
+use crate::errors::{Result, RloxError, RuntimeError};
+use crate::strings;
+
+// Built-in methods, bound to the string `text`.
+// Strings are values, so every method returns a new string.
+pub fn method(text: &str, name: &str) -> Result<LoxType> {
+    let text = text.to_string();
+    let method = match name {
+        "length" => NativeMethod::new("length", 0, move |_| {
+            Ok(LoxType::Number(strings::length(&text) as f64))
+        }),
+        // The graphemes from `start` up to, but not including, `end`
+        "substring" => NativeMethod::new("substring", 2, move |arguments| {
+            let len = strings::length(&text);
+            let start = loxlist::to_index(&arguments[0], len, len + 1)?;
+            let end = loxlist::to_index(&arguments[1], len, len + 1)?;
+            Ok(string(strings::substring(&text, start, end)))
+        }),
+        "index_of" => NativeMethod::new("index_of", 1, move |arguments| {
+            let index = strings::index_of(&text, expect_string(&arguments[0])?);
+            Ok(LoxType::Number(index.map_or(-1.0, |index| index as f64)))
+        }),
+        "split" => NativeMethod::new("split", 1, move |arguments| {
+            let parts = strings::split(&text, expect_string(&arguments[0])?);
+            Ok(LoxType::List(LoxList::new(
+                parts.into_iter().map(string).collect(),
+            )))
+        }),
+        // The elements of a list, turned into strings, with this one between them
+        "join" => NativeMethod::new("join", 1, move |arguments| match &arguments[0] {
+            LoxType::List(list) => {
+                let items: Vec<String> = (0..list.len())
+                    .filter_map(|position| list.item_at(position))
+                    .map(|item| item.to_string())
+                    .collect();
+                Ok(LoxType::String(items.join(&text)))
+            }
+            other => Err(invalid_argument("a list", other)),
+        }),
+        "replace" => NativeMethod::new("replace", 2, move |arguments| {
+            let from = expect_string(&arguments[0])?;
+            let to = expect_string(&arguments[1])?;
+            Ok(LoxType::String(text.replace(from, to)))
+        }),
+        "trim" => NativeMethod::new("trim", 0, move |_| Ok(string(text.trim()))),
+        "upper" => NativeMethod::new("upper", 0, move |_| {
+            Ok(LoxType::String(text.to_uppercase()))
+        }),
+        "lower" => NativeMethod::new("lower", 0, move |_| {
+            Ok(LoxType::String(text.to_lowercase()))
+        }),
+        "starts_with" => NativeMethod::new("starts_with", 1, move |arguments| {
+            Ok(LoxType::Bool(
+                text.starts_with(expect_string(&arguments[0])?),
+            ))
+        }),
+        "ends_with" => NativeMethod::new("ends_with", 1, move |arguments| {
+            Ok(LoxType::Bool(text.ends_with(expect_string(&arguments[0])?)))
+        }),
+        "repeat" => NativeMethod::new("repeat", 1, move |arguments| match &arguments[0] {
+            LoxType::Number(n) if n.fract() == 0.0 && *n >= 0.0 => {
+                match strings::repeat(&text, *n as usize) {
+                    Some(repeated) => Ok(LoxType::String(repeated)),
+                    None => Err(invalid_argument("a smaller count", &arguments[0])),
+                }
+            }
+            other => Err(invalid_argument("a whole number count", other)),
+        }),
+        // nil when the string isn't a number
+        "to_number" => NativeMethod::new("to_number", 0, move |_| {
+            Ok(strings::parse_number(&text).map_or(LoxType::Nil, LoxType::Number))
+        }),
+        _ => {
+            return Err(RloxError::Interpret(
+                RuntimeError::UndefinedProperty(name.to_string()),
+                None,
+                Vec::new(),
+            ))
+        }
+    };
+    Ok(LoxType::NativeMethod(method))
+}
+
+fn string(text: &str) -> LoxType {
+    LoxType::String(text.to_string())
+}
+
+fn expect_string(value: &LoxType) -> Result<&str> {
+    match value {
+        LoxType::String(text) => Ok(text),
+        other => Err(invalid_argument("a string", other)),
+    }
+}
+
+fn invalid_argument(expected: &str, value: &LoxType) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::InvalidArgument(expected.to_string(), value.to_string()),
+        None,
+        Vec::new(),
+    )
+}
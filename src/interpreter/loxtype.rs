@@ -14,7 +14,10 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use super::callable::{FfiClock, FfiPrint, LoxFunction};
+use super::callable::{LoxClass, LoxFunction, NativeFunction, NativeMethod};
+use super::loxinstance::LoxInstance;
+use super::loxlist::LoxList;
+use super::loxmap::LoxMap;
 use std::fmt;
 
 // TODO: getting rid of Clone here would allow using trait objects
@@ -25,8 +28,12 @@ pub enum LoxType {
     Number(f64),
     String(String),
     Fun(LoxFunction),
-    Clock(FfiClock),
-    Print(FfiPrint),
+    Class(LoxClass),
+    Instance(LoxInstance),
+    List(LoxList),
+    Map(LoxMap),
+    NativeMethod(NativeMethod),
+    Native(NativeFunction),
     Nil,
 }
 
@@ -44,9 +51,36 @@ impl fmt::Display for LoxType {
             LoxType::Number(n) => write!(f, "{}", n),
             LoxType::String(s) => write!(f, "{s}"),
             LoxType::Fun(call) => write!(f, "{call}"),
-            LoxType::Clock(call) => write!(f, "{call}"),
-            LoxType::Print(call) => write!(f, "{call}"),
-            // LoxType::Class(call) => write!(f, "{call}"),
+            LoxType::Native(call) => write!(f, "{call}"),
+            LoxType::Class(class) => write!(f, "{class}"),
+            LoxType::Instance(instance) => write!(f, "{instance}"),
+            LoxType::List(list) => write!(f, "{list}"),
+            LoxType::Map(map) => write!(f, "{map}"),
+            LoxType::NativeMethod(method) => write!(f, "{method}"),
+        }
+    }
+}
+
+// Lists and maps nested deeper than this are printed as [...] or {...},
+// rather than recursing until the native stack runs out
+const DISPLAY_DEPTH: usize = 100;
+
+impl LoxType {
+    // How a value is written inside a list or map: strings are quoted, and a
+    // list or map that's already being written, or nested too deep, is cut
+    // short instead of recursing.
+    pub(super) fn write_element(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        seen: &mut Vec<*const ()>,
+    ) -> fmt::Result {
+        match self {
+            LoxType::String(s) => write!(f, "\"{s}\""),
+            LoxType::List(_) if seen.len() >= DISPLAY_DEPTH => write!(f, "[...]"),
+            LoxType::Map(_) if seen.len() >= DISPLAY_DEPTH => write!(f, "{{...}}"),
+            LoxType::List(list) => list.write(f, seen),
+            LoxType::Map(map) => map.write(f, seen),
+            other => write!(f, "{other}"),
         }
     }
 }
@@ -15,23 +15,54 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 mod callable; // Traits for callable objects (functions, classes, lambdas)
+mod convert; // Converting between Lox values and Rust types
 mod environment; // Call stack
+mod iteration;
+mod loxinstance;
+mod loxlist;
+mod loxmap;
 mod loxreturn;
+mod loxstring;
 mod loxtype;
+mod output;
 
+use std::collections::HashMap;
+use std::io::{self, Write};
 use std::rc::Rc;
+use std::time::SystemTime;
 
-use crate::errors::{Result, RloxError, RuntimeError};
-use crate::parser::ast::{Expr, ExprLiteral, Stmt};
-use crate::scanner::{Token, TokenType};
-use callable::{Callable, FfiClock, FfiPrint, LoxFunction};
+use crate::errors::{Frame, Result, RloxError, RuntimeError};
+use crate::parser::ast::{self, Expr, ExprLiteral, Stmt};
+use crate::resolver::Resolver;
+use crate::scanner::{Scanner, Span, TokenType};
+pub use callable::{Arity, NativeFunction};
+use callable::{Callable, LoxClass, LoxFunction};
+pub use convert::{FromLox, IntoLox, IntoLoxResult, IntoNative, LoxKey};
 use environment::RfEnv;
-use loxreturn::Return;
-use loxtype::LoxType;
+use iteration::Iteration;
+pub use loxinstance::LoxInstance;
+pub use loxlist::LoxList;
+pub use loxmap::LoxMap;
+use loxreturn::ControlFlow;
+pub use loxtype::LoxType;
+pub use output::OutputBuffer;
+
+// Default call depth before we report a stack overflow, counting the script like clox.
+// Each call recurses through the interpreter, taking kilobytes of native stack,
+// or tens of kilobytes in a debug build, so this fits in an 8MB main thread.
+const MAX_CALL_DEPTH: usize = 64;
 
 pub struct Interpreter {
     global: RfEnv,
     env: RfEnv,
+    calls: Vec<(Rc<str>, usize)>, // Active calls, and the line each was made from
+    max_call_depth: usize,
+    error_class: LoxClass,
+    thrown: Option<LoxType>, // The value of the `throw` being unwound
+    caught: Option<(LoxType, RloxError)>, // The last error caught, to throw again
+    native_line: Option<usize>, // Where the running native function was called from
+    output: Box<dyn Write>,  // Where `print` writes, stdout by default
+    error_output: Box<dyn Write>, // For built-ins reporting problems, stderr by default
 }
 
 impl Default for Interpreter {
@@ -45,9 +76,110 @@ impl Interpreter {
         let global = environment::new_global();
         let env = Rc::clone(&global);
 
-        environment::define(&global, "clock", LoxType::Clock(FfiClock {}));
-        environment::define(&global, "print", LoxType::Print(FfiPrint {}));
-        Self { global, env }
+        // Runtime errors are caught as instances of Error
+        let error_class = LoxClass::new("Error", None, HashMap::new());
+        environment::define(&global, "Error", LoxType::Class(error_class.clone()));
+        let mut interpreter = Self {
+            global,
+            env,
+            calls: Vec::new(),
+            max_call_depth: MAX_CALL_DEPTH,
+            error_class,
+            thrown: None,
+            caught: None,
+            native_line: None,
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::stderr()),
+        };
+
+        interpreter.define_native("clock", Arity::Fixed(0), clock);
+        interpreter.define_native("print", Arity::Fixed(1), print);
+        interpreter.define_native("range", Arity::Fixed(2), iteration::range);
+        interpreter.define_native("str", Arity::Fixed(1), |_, arguments| {
+            Ok(LoxType::String(arguments[0].to_string()))
+        });
+        interpreter
+    }
+
+    // Make a Rust function callable from Lox as the global `name`.
+    // Arguments are checked against `arity` before it's called.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        function: impl Fn(&mut Interpreter, &[LoxType]) -> Result<LoxType> + 'static,
+    ) {
+        let native = NativeFunction::new(name, arity, function);
+        environment::define(&self.global, name, LoxType::Native(native));
+    }
+
+    // Send what scripts print somewhere other than stdout
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    // Send problems reported by built-ins somewhere other than stderr
+    pub fn set_error_output(&mut self, error_output: impl Write + 'static) {
+        self.error_output = Box::new(error_output);
+    }
+
+    // How deep scripts can call before a stack overflow error. Raise this only
+    // when running on a thread with enough native stack to reach it.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    // Collect what scripts print in a buffer, instead of writing it to stdout
+    pub fn capture_output(&mut self) -> OutputBuffer {
+        let buffer = OutputBuffer::new();
+        self.set_output(buffer.clone());
+        buffer
+    }
+
+    // Writers for native functions that print, or report problems
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.output
+    }
+
+    pub fn error_output(&mut self) -> &mut dyn Write {
+        &mut *self.error_output
+    }
+
+    // The value of a global variable, such as a function a script defined
+    pub fn get_global(&self, name: &str) -> Result<LoxType> {
+        environment::get(&self.global, name)
+    }
+
+    // Define or replace a global variable, for scripts to use
+    pub fn set_global(&mut self, name: &str, value: impl IntoLox) {
+        environment::define(&self.global, name, value.into_lox());
+    }
+
+    // Call a function, class or native function from Rust, with arguments
+    // checked against its arity. Native functions can use this to call back
+    // into Lox, and errors in the call are traced back to them.
+    pub fn call(&mut self, callee: &LoxType, arguments: &[LoxType]) -> Result<LoxType> {
+        if let Some(line) = self.native_line {
+            return self.call_value(callee.clone(), arguments, line);
+        }
+
+        // Called by the host, so no script is running around the call
+        self.call_value(callee.clone(), arguments, 0)
+            .map_err(|err| match self.abort(err) {
+                RloxError::Interpret(err, span, mut trace) => {
+                    trace.pop();
+                    RloxError::Interpret(err, span, trace)
+                }
+                other => other,
+            })
+    }
+
+    // Make a Rust function with typed arguments, like `fn(f64, String) -> bool`,
+    // callable from Lox. Its arity is the number of arguments, and each is
+    // converted with `FromLox` before calling it.
+    pub fn define_typed_native<Args>(&mut self, name: &str, function: impl IntoNative<Args>) {
+        let native = function.into_native(name);
+        environment::define(&self.global, name, LoxType::Native(native));
     }
 
     //
@@ -56,15 +188,71 @@ impl Interpreter {
 
     pub fn interpret(&mut self, program: Vec<Stmt>) -> Result<()> {
         for statement in program {
-            self.execute(statement)?;
-            // we don't need the Return() type here, only inside
+            if let Err(err) = self.execute(statement) {
+                return Err(self.abort(err));
+            }
+            // we don't need the ControlFlow type here, only inside
             // functions/closures/methods
         }
 
         Ok(())
     }
 
-    fn execute(&mut self, stmt: Stmt) -> Result<Option<Return>> {
+    // Scan, parse, resolve and run `source`, for embedding Lox in Rust.
+    // Returns the value of the last statement if it's an expression statement, like `x + 1;`,
+    // and nil otherwise.
+    // Globals are kept between calls, and the first error found stops it.
+    pub fn eval(&mut self, source: &str) -> Result<LoxType> {
+        let mut scanner = Scanner::new();
+        scanner.scan_tokens(source)?;
+
+        let program: Result<Vec<Stmt>> = scanner.into_parser().parse().into_iter().collect();
+        let mut program = program?;
+        Resolver::new()
+            .resolver(&mut program)
+            .map_err(|mut errors| errors.remove(0))?;
+
+        match program.pop() {
+            Some(Stmt::Expression(expr)) => {
+                self.interpret(program)?;
+                let span = expr.span();
+                self.evaluate(expr).map_err(|err| self.abort(err.at(span)))
+            }
+            last => {
+                program.extend(last);
+                self.interpret(program)?;
+                Ok(LoxType::Nil)
+            }
+        }
+    }
+
+    // An error that stopped the program, with the calls it unwound through
+    fn abort(&mut self, err: RloxError) -> RloxError {
+        self.thrown = None;
+        self.caught = None;
+        err.with_trace(|span| self.backtrace(span))
+    }
+
+    // Pair each active call with the line it had reached, innermost first.
+    // A caller is stopped at the line where it made the next call.
+    fn backtrace(&self, span: Option<Span>) -> Vec<Frame> {
+        let mut line = span.map_or(0, |span| span.line);
+        let mut trace = Vec::with_capacity(self.calls.len() + 1);
+        for (function, call_line) in self.calls.iter().rev() {
+            trace.push(Frame::new(function, line));
+            line = *call_line;
+        }
+        trace.push(Frame::new("<script>", line));
+        trace
+    }
+
+    // Errors are reported at the innermost node that knows where it is
+    fn execute(&mut self, stmt: Stmt) -> Result<Option<ControlFlow>> {
+        let span = stmt.span();
+        self.execute_stmt(stmt).map_err(|err| err.at(span))
+    }
+
+    fn execute_stmt(&mut self, stmt: Stmt) -> Result<Option<ControlFlow>> {
         match stmt {
             Stmt::Expression(expr) => {
                 self.evaluate(expr)?;
@@ -74,62 +262,212 @@ impl Interpreter {
                     Some(expr) => self.evaluate(expr)?,
                     None => LoxType::Nil,
                 };
-                environment::define(&self.env, &ident, result);
+                environment::define(&self.env, ident.lexeme(), result);
             }
-            Stmt::Block(block) => {
+            Stmt::Block(_, block) => {
                 self.env = environment::from(&self.env);
 
-                // Execute statements
+                // Execute statements, stopping early on a return or an error
+                let mut result = Ok(None);
                 for stmt in block {
-                    self.execute(stmt)?;
+                    result = self.execute(stmt);
+                    if !matches!(result, Ok(None)) {
+                        break;
+                    }
                 }
 
                 // Return to previous scope
                 self.env = environment::drop(&self.env);
+                return result;
             }
-            Stmt::If(condition, then_branch, else_branch) => {
+            Stmt::If(_, condition, then_branch, else_branch) => {
                 if is_truthy(&self.evaluate(condition)?) {
-                    self.execute(*then_branch)?;
-                } else if else_branch.is_some() {
-                    self.execute(*else_branch.unwrap())?;
+                    return self.execute(*then_branch);
+                } else if let Some(else_branch) = else_branch {
+                    return self.execute(*else_branch);
                 }
             }
             // TODO: Look into this.
             // Can we do something besides clone() to re-evaluate the condition?
-            Stmt::While(condition, stmt) => {
+            Stmt::While(_, condition, stmt, increment) => {
                 while is_truthy(&self.evaluate(condition.clone())?) {
-                    self.execute(*stmt.clone())?;
+                    match self.execute(*stmt.clone())? {
+                        Some(ControlFlow::Break) => break,
+                        Some(ret @ ControlFlow::Return(_)) => return Ok(Some(ret)),
+                        Some(ControlFlow::Continue) | None => (),
+                    }
+
+                    // A `for` loop's increment runs even when the body continues
+                    if let Some(increment) = &increment {
+                        self.evaluate(increment.clone())?;
+                    }
                 }
             }
+            Stmt::ForIn(keyword, name, iterable, body) => {
+                let span = iterable.span();
+                let iterable = self.evaluate(iterable)?;
+                let mut iteration =
+                    Iteration::new(self, iterable, keyword.line).map_err(|err| err.at(span))?;
+
+                // Each iteration binds the loop variable in a fresh scope,
+                // so closures created in the body capture that iteration's value
+                while let Some(value) = iteration.next(self, keyword.line)? {
+                    self.env = environment::from(&self.env);
+                    environment::define(&self.env, name.lexeme(), value);
+                    let result = self.execute(*body.clone());
+                    self.env = environment::drop(&self.env);
+
+                    match result? {
+                        Some(ControlFlow::Break) => break,
+                        Some(ret @ ControlFlow::Return(_)) => return Ok(Some(ret)),
+                        Some(ControlFlow::Continue) | None => (),
+                    }
+                }
+            }
+            Stmt::Throw(_, value) => {
+                let value = self.evaluate(value)?;
+                return Err(self.throw(value));
+            }
+            Stmt::Try(_, body, catch, finally) => {
+                let result = match (self.execute(*body), catch) {
+                    (Err(err @ RloxError::Interpret(..)), Some((name, handler))) => {
+                        let error = self.caught(err);
+                        self.env = environment::from(&self.env);
+                        environment::define(&self.env, name.lexeme(), error);
+                        let result = self.execute(*handler);
+                        self.env = environment::drop(&self.env);
+                        result
+                    }
+                    (result, _) => result,
+                };
+
+                // Jumping or failing out of `finally` replaces how the try ended
+                if let Some(finally) = finally {
+                    let thrown = self.thrown.take();
+                    match self.execute(*finally) {
+                        Ok(None) => self.thrown = thrown,
+                        other => return other,
+                    }
+                }
+                return result;
+            }
+            Stmt::Break(_) => return Ok(Some(ControlFlow::Break)),
+            Stmt::Continue(_) => return Ok(Some(ControlFlow::Continue)),
             Stmt::Fun(ident, params, body) => {
-                // functions create a new environment scope to capture their environment.
-                // the new scope it stored as part of the function definition.
-                let closure = environment::from(&self.env);
-                let fun = LoxFunction::new(&ident, params, body, closure);
-                environment::define(&self.env, &ident, LoxType::Fun(fun));
-            }
-            // TODO: Use token to improve interpreter error messages.
-            Stmt::Return(_tok, expr) => {
+                // functions capture the environment they're declared in.
+                // each call creates its own scope beneath it for parameters and locals.
+                let closure = Rc::clone(&self.env);
+                let fun = LoxFunction::new(ident.lexeme(), params, body, closure, false);
+                environment::define(&self.env, ident.lexeme(), LoxType::Fun(fun));
+            }
+            Stmt::Class(name, superclass, methods) => {
+                let superclass = match superclass {
+                    Some(Expr::Variable(ref ident, _)) if ident.lexeme() == name.lexeme() => {
+                        return Err(RloxError::Interpret(
+                            RuntimeError::InheritFromSelf(ident.lexeme().to_string()),
+                            Some(ident.span()),
+                            Vec::new(),
+                        ));
+                    }
+                    Some(expr) => match (expr.span(), self.evaluate(expr)?) {
+                        (_, LoxType::Class(class)) => Some(class),
+                        (span, other) => {
+                            return Err(RloxError::Interpret(
+                                RuntimeError::SuperclassMustBeClass(other.to_string()),
+                                Some(span),
+                                Vec::new(),
+                            ));
+                        }
+                    },
+                    None => None,
+                };
+
+                // Subclass methods close over an extra scope binding `super`
+                if let Some(ref superclass) = superclass {
+                    self.env = environment::from(&self.env);
+                    environment::define(&self.env, "super", LoxType::Class(superclass.clone()));
+                }
+
+                // methods close over the environment the class is declared in.
+                // `this` is bound later, when the method is accessed from an instance.
+                let mut class_methods = HashMap::new();
+                for method in methods {
+                    if let Stmt::Fun(ident, params, body) = method {
+                        let is_initializer = ident.lexeme() == "init";
+                        let fun = LoxFunction::new(
+                            ident.lexeme(),
+                            params,
+                            body,
+                            Rc::clone(&self.env),
+                            is_initializer,
+                        );
+                        class_methods.insert(ident.lexeme().to_string(), fun);
+                    } else {
+                        unreachable!("class bodies only contain methods");
+                    }
+                }
+
+                if superclass.is_some() {
+                    self.env = environment::drop(&self.env);
+                }
+
+                let class = LoxClass::new(name.lexeme(), superclass, class_methods);
+                environment::define(&self.env, name.lexeme(), LoxType::Class(class));
+            }
+            Stmt::Return(_, expr) => {
                 let val = match expr {
                     Some(expr) => self.evaluate(expr)?,
                     None => LoxType::Nil,
                 };
 
-                return Ok(Some(Return(val)));
+                return Ok(Some(ControlFlow::Return(val)));
             }
         }
 
         Ok(None)
     }
 
-    fn execute_block(&mut self, body: Vec<Stmt>) -> Result<Option<Return>> {
+    // The value a catch clause binds: whatever was thrown,
+    // or an Error describing a runtime error
+    fn caught(&mut self, err: RloxError) -> LoxType {
+        // Trace the error now, in case it's thrown again
+        let err = err.with_trace(|span| self.backtrace(span));
+
+        let error = match self.thrown.take() {
+            Some(value) => value,
+            None => {
+                let error = LoxInstance::new(self.error_class.clone());
+                error.set("message", LoxType::String(err.to_string()));
+                let line = err.span().map_or(0, |span| span.line);
+                error.set("line", LoxType::Number(line as f64));
+                LoxType::Instance(error)
+            }
+        };
+        self.caught = Some((error.clone(), err));
+        error
+    }
+
+    // Throwing the error that was caught again keeps where it came from
+    fn throw(&mut self, value: LoxType) -> RloxError {
+        self.thrown = Some(value.clone());
+        match self.caught.take() {
+            Some((caught, err)) if is_equal(&caught, &value) => err,
+            caught => {
+                self.caught = caught;
+                let message = describe_thrown(&value);
+                RloxError::Interpret(RuntimeError::Thrown(message), None, Vec::new())
+            }
+        }
+    }
+
+    fn execute_block(&mut self, body: Vec<Stmt>) -> Result<Option<ControlFlow>> {
         // This function is just like execute(), but it's specific to trait Callable
         // We give it its own environment to handle 1) functions, 2) closures.
         // We also deal with return values, unlike execute().
 
         for statement in body {
             if let Some(ret) = self.execute(statement)? {
-                // If we get a Return() struct, immediately stop execution and
+                // If we get a return, immediately stop execution and
                 // return value to caller.
                 return Ok(Some(ret));
             }
@@ -143,9 +481,14 @@ impl Interpreter {
     //
 
     fn evaluate(&mut self, expr: Expr) -> Result<LoxType> {
+        let span = expr.span();
+        self.evaluate_expr(expr).map_err(|err| err.at(span))
+    }
+
+    fn evaluate_expr(&mut self, expr: Expr) -> Result<LoxType> {
         match expr {
             // Evaluate literals
-            Expr::Literal(lit) => match lit {
+            Expr::Literal(_, lit) => match lit {
                 ExprLiteral::Bool(v) => Ok(LoxType::Bool(v)),
                 ExprLiteral::Nil => Ok(LoxType::Nil),
                 ExprLiteral::Number(n) => Ok(LoxType::Number(n)),
@@ -153,24 +496,134 @@ impl Interpreter {
             },
             Expr::Variable(ident, depth) => {
                 // Accessing a variable.
+                // Anything the resolver didn't find in a local scope is a global.
                 match depth {
-                    None => Ok(environment::get(&self.env, &ident)?),
-                    Some(depth) => Ok(environment::get_n(&self.env, &ident, depth)?),
+                    None => Ok(environment::get(&self.global, ident.lexeme())?),
+                    Some(depth) => Ok(environment::get_n(&self.env, ident.lexeme(), depth)?),
                 }
             }
+            Expr::This(_, depth) => match depth {
+                Some(depth) => Ok(environment::get_n(&self.env, "this", depth)?),
+                None => unreachable!("resolver always binds `this` inside a class"),
+            },
+            Expr::Super(_, method, depth) => {
+                // `super` is resolved like a variable, and `this` is always
+                // bound in the scope just inside of it.
+                let depth = depth.expect("resolver always binds `super` inside a subclass");
+                let superclass = match environment::get_n(&self.env, "super", depth)? {
+                    LoxType::Class(class) => class,
+                    _ => unreachable!("`super` is always bound to a class"),
+                };
+                let instance = match environment::get_n(&self.env, "this", depth - 1)? {
+                    LoxType::Instance(instance) => instance,
+                    _ => unreachable!("`this` is always bound to an instance"),
+                };
+
+                match superclass.find_method(method.lexeme()) {
+                    Some(method) => Ok(LoxType::Fun(method.bind(instance))),
+                    None => Err(RloxError::Interpret(
+                        RuntimeError::UndefinedProperty(method.lexeme().to_string()),
+                        None,
+                        Vec::new(),
+                    )),
+                }
+            }
+            Expr::Get(object, name) => match self.evaluate(*object)? {
+                LoxType::Instance(instance) => instance.get(name.lexeme()),
+                LoxType::List(list) => list.method(name.lexeme()),
+                LoxType::Map(map) => map.method(name.lexeme()),
+                LoxType::String(text) => loxstring::method(&text, name.lexeme()),
+                other => Err(RloxError::Interpret(
+                    RuntimeError::NotAnInstance(other.to_string()),
+                    None,
+                    Vec::new(),
+                )),
+            },
+            Expr::Set(object, name, value) => {
+                let instance = match self.evaluate(*object)? {
+                    LoxType::Instance(instance) => instance,
+                    other => {
+                        return Err(RloxError::Interpret(
+                            RuntimeError::NotAnInstance(other.to_string()),
+                            None,
+                            Vec::new(),
+                        ));
+                    }
+                };
+
+                let value = self.evaluate(*value)?;
+                instance.set(name.lexeme(), value.clone());
+                Ok(value)
+            }
+            Expr::List(_, elements) => {
+                let mut items = Vec::with_capacity(elements.len());
+                for element in elements {
+                    items.push(self.evaluate(element)?);
+                }
+                Ok(LoxType::List(LoxList::new(items)))
+            }
+            Expr::Map(_, entries) => {
+                let map = LoxMap::default();
+                for (key, value) in entries {
+                    let key = self.evaluate(key)?;
+                    let value = self.evaluate(value)?;
+                    map.set(&key, value)?;
+                }
+                Ok(LoxType::Map(map))
+            }
+            Expr::Index(object, _, index) => {
+                let object = self.evaluate(*object)?;
+                let index = self.evaluate(*index)?;
+                match object {
+                    LoxType::List(list) => list.get(&index),
+                    LoxType::Map(map) => map.get(&index),
+                    other => Err(RloxError::Interpret(
+                        RuntimeError::NotIndexable(other.to_string()),
+                        None,
+                        Vec::new(),
+                    )),
+                }
+            }
+            Expr::SetIndex(object, _, index, value) => {
+                let object = self.evaluate(*object)?;
+                let index = self.evaluate(*index)?;
+                let value = self.evaluate(*value)?;
+                match object {
+                    LoxType::List(list) => list.set(&index, value.clone())?,
+                    LoxType::Map(map) => map.set(&index, value.clone())?,
+                    other => {
+                        return Err(RloxError::Interpret(
+                            RuntimeError::NotIndexable(other.to_string()),
+                            None,
+                            Vec::new(),
+                        ));
+                    }
+                }
+                Ok(value)
+            }
             // Recursively evaluate grouping's subexpressions.
             Expr::Grouping(group) => self.evaluate(*group),
-            Expr::Unary(token, expr) => self.unary(token, *expr),
-            Expr::Binary(expr1, token, expr2) => self.binary(*expr1, token, *expr2),
+            Expr::Stringify(expr) => Ok(LoxType::String(self.evaluate(*expr)?.to_string())),
+            Expr::Lambda(keyword, params, body) => {
+                // anonymous functions capture their environment just like declared ones
+                let closure = Rc::clone(&self.env);
+                let name = ast::lambda_name(keyword);
+                let fun = LoxFunction::new(&name, params, body, closure, false);
+                Ok(LoxType::Fun(fun))
+            }
+            Expr::Unary(operator, expr) => self.unary(operator.token_type, *expr),
+            Expr::Binary(expr1, operator, expr2) => {
+                self.binary(*expr1, operator.token_type, *expr2)
+            }
             Expr::Assign(ident, expr, depth) => {
                 // Try to evaluate the r-value
                 let exprres = self.evaluate(*expr)?;
 
                 // Assign r-value to l-value
                 match depth {
-                    None => environment::assign(&self.env, &ident, exprres.clone())?,
+                    None => environment::assign(&self.global, ident.lexeme(), exprres.clone())?,
                     Some(depth) => {
-                        environment::assign_n(&self.env, &ident, exprres.clone(), depth)?
+                        environment::assign_n(&self.env, ident.lexeme(), exprres.clone(), depth)?
                     }
                 }
 
@@ -181,7 +634,7 @@ impl Interpreter {
 
                 // short-circuit. only evaluate the right if needed.
 
-                if operator == TokenType::Or {
+                if operator.token_type == TokenType::Or {
                     if is_truthy(&left) {
                         // operator == or, and left is true
                         // so return true
@@ -196,19 +649,8 @@ impl Interpreter {
                 // otherwise, return whatever the right side is after evaluating it.
                 Ok(self.evaluate(*right)?)
             }
-            // TODO: Use token to improve interpreter error messages.
-            Expr::Call(callee, _tok, arguments) => {
+            Expr::Call(callee, paren, arguments) => {
                 let callee = self.evaluate(*callee)?;
-                let call: Box<dyn Callable> = match callee {
-                    LoxType::Fun(callee) => Box::new(callee),
-                    LoxType::Clock(callee) => Box::new(callee),
-                    LoxType::Print(callee) => Box::new(callee),
-                    other => {
-                        return Err(RloxError::Interpret(RuntimeError::NotACallableType(
-                            other.to_string(),
-                        )));
-                    }
-                };
 
                 // Evaluate arguments of function call
                 let mut args = vec![];
@@ -216,23 +658,65 @@ impl Interpreter {
                     args.push(self.evaluate(argument)?);
                 }
 
-                // Make sure we have the correct number of arguments,
-                // Then call the function
-                if call.arity() as usize != args.len() {
-                    Err(RloxError::Interpret(RuntimeError::MismatchedArguments(
-                        format!(
-                            "Expected {} arguments, but got {}.",
-                            call.arity(),
-                            args.len()
-                        ),
-                    )))
-                } else {
-                    Ok(call.call(self, &args)?)
-                }
+                self.call_value(callee, &args, paren.line)
             }
         }
     }
 
+    // Call a function, class or native function with its evaluated arguments.
+    // `line` is where the call was made from, for backtraces.
+    fn call_value(&mut self, callee: LoxType, args: &[LoxType], line: usize) -> Result<LoxType> {
+        let native = matches!(callee, LoxType::Native(_) | LoxType::NativeMethod(_));
+        let call: Box<dyn Callable> = match callee {
+            LoxType::Fun(callee) => Box::new(callee),
+            LoxType::Class(callee) => Box::new(callee),
+            LoxType::Native(callee) => Box::new(callee),
+            LoxType::NativeMethod(callee) => Box::new(callee),
+            other => {
+                return Err(RloxError::Interpret(
+                    RuntimeError::NotACallableType(other.to_string()),
+                    None,
+                    Vec::new(),
+                ));
+            }
+        };
+
+        // Make sure we have the correct number of arguments,
+        // Then call the function
+        if !call.arity().accepts(args.len()) {
+            Err(RloxError::Interpret(
+                RuntimeError::MismatchedArguments(format!(
+                    "Expected {} arguments, but got {}.",
+                    call.arity(),
+                    args.len()
+                )),
+                None,
+                Vec::new(),
+            ))
+        } else if native {
+            // Native functions don't have a frame of their own,
+            // calls they make back into Lox are traced to this line
+            let outer = self.native_line.replace(line);
+            let result = call.call(self, args);
+            self.native_line = outer;
+            result
+        } else if self.calls.len() + 1 >= self.max_call_depth {
+            Err(RloxError::Interpret(
+                RuntimeError::StackOverflow,
+                None,
+                Vec::new(),
+            ))
+        } else {
+            // Errors inside the call are traced back through every active call
+            self.calls.push((call.name(), line));
+            let result = call
+                .call(self, args)
+                .map_err(|err| err.with_trace(|span| self.backtrace(span)));
+            self.calls.pop();
+            result
+        }
+    }
+
     // TODO: This is pretty sloppy. Cleanup this logic.
     // We have two tokens, ! and -, and two possible types Number and Bool.
     // Evaluate the 4 possible inputs.
@@ -247,9 +731,13 @@ impl Interpreter {
         let right: LoxType = self.evaluate(unary)?;
         match right {
             LoxType::String(_) | LoxType::Nil => {
-                return Err(RloxError::Interpret(RuntimeError::TypeComparison(format!(
-                    "Cannot apply unary operator \"{token:?}\" to expression."
-                ))));
+                return Err(RloxError::Interpret(
+                    RuntimeError::TypeComparison(format!(
+                        "Cannot apply unary operator \"{token:?}\" to expression."
+                    )),
+                    None,
+                    Vec::new(),
+                ));
             }
             _ => (),
         }
@@ -262,7 +750,14 @@ impl Interpreter {
                 } else if let LoxType::Bool(v) = right {
                     Ok(LoxType::Bool(!v))
                 } else {
-                    unimplemented!("Not possible to get here.")
+                    // Functions, classes, instances and lists can't be negated
+                    Err(RloxError::Interpret(
+                        RuntimeError::TypeComparison(format!(
+                            "Cannot apply unary operator \"{token:?}\" to expression."
+                        )),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
             _ => {
@@ -282,27 +777,33 @@ impl Interpreter {
                 if let (Number(left), Number(right)) = (left, right) {
                     Ok(Number(left - right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::Arithmetic(
-                        "Cannot subtract types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::Arithmetic("Cannot subtract types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
             TokenType::Slash => {
                 if let (Number(left), Number(right)) = (left, right) {
                     Ok(Number(left / right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::Arithmetic(
-                        "Cannot divide types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::Arithmetic("Cannot divide types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
             TokenType::Star => {
                 if let (Number(left), Number(right)) = (left, right) {
                     Ok(Number(left * right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::Arithmetic(
-                        "Cannot multiply types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::Arithmetic("Cannot multiply types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
             TokenType::Plus => {
@@ -314,9 +815,11 @@ impl Interpreter {
                 else if let (String(left), String(right)) = (left, right) {
                     Ok(String(left + &right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::Concatenation(
-                        "Cannot concatenate types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::Concatenation("Cannot concatenate types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
 
@@ -324,36 +827,44 @@ impl Interpreter {
                 if let (Number(left), Number(right)) = (left, right) {
                     Ok(Bool(left > right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::TypeComparison(
-                        "Cannot compare types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::TypeComparison("Cannot compare types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
             TokenType::GreaterEqual => {
                 if let (Number(left), Number(right)) = (left, right) {
                     Ok(Bool(left >= right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::TypeComparison(
-                        "Cannot compare types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::TypeComparison("Cannot compare types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
             TokenType::Less => {
                 if let (Number(left), Number(right)) = (left, right) {
                     Ok(Bool(left < right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::TypeComparison(
-                        "Cannot compare types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::TypeComparison("Cannot compare types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
             TokenType::LessEqual => {
                 if let (Number(left), Number(right)) = (left, right) {
                     Ok(Bool(left <= right))
                 } else {
-                    Err(RloxError::Interpret(RuntimeError::TypeComparison(
-                        "Cannot compare types".into(),
-                    )))
+                    Err(RloxError::Interpret(
+                        RuntimeError::TypeComparison("Cannot compare types".into()),
+                        None,
+                        Vec::new(),
+                    ))
                 }
             }
 
@@ -367,6 +878,37 @@ impl Interpreter {
     }
 }
 
+fn clock(_: &mut Interpreter, _: &[LoxType]) -> Result<LoxType> {
+    Ok(LoxType::Number(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as f64,
+    ))
+}
+
+fn print(interpreter: &mut Interpreter, arguments: &[LoxType]) -> Result<LoxType> {
+    match writeln!(interpreter.output, "{}", arguments[0]) {
+        Ok(()) => Ok(LoxType::Nil),
+        Err(err) => Err(RloxError::Interpret(
+            RuntimeError::Output(err.to_string()),
+            None,
+            Vec::new(),
+        )),
+    }
+}
+
+// An uncaught throw reports the thrown error's message, or the value itself
+fn describe_thrown(value: &LoxType) -> String {
+    match value {
+        LoxType::Instance(instance) => match instance.field("message") {
+            Some(message) => message.to_string(),
+            None => value.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
 fn is_truthy(expr: &LoxType) -> bool {
     match expr {
         LoxType::Bool(v) => *v,
@@ -384,20 +926,958 @@ fn is_equal(left: &LoxType, right: &LoxType) -> bool {
         n1 == n2
     } else if let (LoxType::String(s1), LoxType::String(s2)) = (left, right) {
         s1 == s2
+    } else if let (LoxType::Class(c1), LoxType::Class(c2)) = (left, right) {
+        c1 == c2
+    } else if let (LoxType::Instance(i1), LoxType::Instance(i2)) = (left, right) {
+        // Instances are equal only if they are the same object
+        i1 == i2
+    } else if let (LoxType::List(l1), LoxType::List(l2)) = (left, right) {
+        // So are lists and maps
+        l1 == l2
+    } else if let (LoxType::Map(m1), LoxType::Map(m2)) = (left, right) {
+        m1 == m2
     } else {
         false
     }
 
-    // TODO figure out how to compare Fun and Class types
+    // TODO figure out how to compare Fun types
 }
 
 #[cfg(test)]
 mod tests {
-    #[allow(unused_imports)]
     use super::*;
+    use std::cell::Cell;
+
+    // Run a program through the whole pipeline and hand back the interpreter
+    // so tests can inspect its globals.
+    fn run(source: &str) -> Result<Interpreter> {
+        let mut scanner = Scanner::new();
+        scanner.scan_tokens(source)?;
+
+        let program: Result<Vec<Stmt>> = scanner.into_parser().parse().into_iter().collect();
+        let mut program = program?;
+        Resolver::new()
+            .resolver(&mut program)
+            .map_err(|mut errors| errors.remove(0))?;
+
+        let mut interpreter = Interpreter::new();
+        interpreter.interpret(program)?;
+        Ok(interpreter)
+    }
+
+    fn global(interpreter: &Interpreter, name: &str) -> String {
+        environment::get(&interpreter.global, name)
+            .unwrap()
+            .to_string()
+    }
 
     #[test]
     fn test_interpreter() {
-        todo!()
+        let interpreter = run("var a = 1 + 2 * 3; var b = \"lo\" + \"x\";").unwrap();
+        assert_eq!(global(&interpreter, "a"), "7");
+        assert_eq!(global(&interpreter, "b"), "lox");
+    }
+
+    #[test]
+    fn eval_returns_last_expression() {
+        let mut interpreter = Interpreter::new();
+        let value = interpreter
+            .eval("fun square(x) { return x * x; }\nvar n = 4;\nsquare(n) + 1;")
+            .unwrap();
+        assert!(matches!(value, LoxType::Number(n) if n == 17.0));
+
+        // Globals are kept between calls, and other statements evaluate to nil
+        assert!(matches!(interpreter.eval("n = n + 1;"), Ok(LoxType::Number(n)) if n == 5.0));
+        assert!(matches!(interpreter.eval("var k = n;"), Ok(LoxType::Nil)));
+        assert!(matches!(interpreter.eval("str(n);"), Ok(LoxType::String(s)) if s == "5"));
+        assert!(matches!(interpreter.eval(""), Ok(LoxType::Nil)));
+        match interpreter.eval("[square(2), {\"a\": true}];") {
+            Ok(LoxType::List(list)) => {
+                assert_eq!(list.len(), 2);
+                assert_eq!(list.to_string(), "[4, {\"a\": true}]");
+            }
+            other => panic!("expected a list, got {other:?}"),
+        }
+
+        // Errors from every stage come back, and don't stop later calls
+        assert!(matches!(
+            interpreter.eval("var = 1;"),
+            Err(RloxError::Parse(..))
+        ));
+        assert!(matches!(
+            interpreter.eval("return 1;"),
+            Err(RloxError::Resolver(..))
+        ));
+        let err = interpreter.eval("var m = 1;\nsquare(nil);").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(1, 26, 1)));
+        assert_eq!(
+            err.trace(),
+            &[Frame::new("square", 1), Frame::new("<script>", 2)]
+        );
+        assert!(matches!(interpreter.eval("m;"), Ok(LoxType::Number(n)) if n == 1.0));
+    }
+
+    #[test]
+    fn native_functions() {
+        let mut interpreter = Interpreter::new();
+        interpreter.define_native("max", Arity::Variadic(1), |_, arguments| {
+            let mut max = f64::NEG_INFINITY;
+            for argument in arguments {
+                match argument {
+                    LoxType::Number(n) => max = max.max(*n),
+                    other => {
+                        return Err(RloxError::Interpret(
+                            RuntimeError::InvalidArgument(
+                                "a number".to_string(),
+                                other.to_string(),
+                            ),
+                            None,
+                            Vec::new(),
+                        ))
+                    }
+                }
+            }
+            Ok(LoxType::Number(max))
+        });
+        let ticks = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&ticks);
+        interpreter.define_native("tick", Arity::Fixed(0), move |_, _| {
+            counter.set(counter.get() + 1);
+            Ok(LoxType::Nil)
+        });
+
+        let value = interpreter
+            .eval("for (i in range(0, 3)) tick();\nmax(1, 7, 3) + max(2);")
+            .unwrap();
+        assert!(matches!(value, LoxType::Number(n) if n == 9.0));
+        assert_eq!(ticks.get(), 3);
+        assert!(
+            matches!(interpreter.eval("str(max);"), Ok(LoxType::String(s)) if s == "<native fn>")
+        );
+
+        assert_eq!(
+            interpreter.eval("max();").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::MismatchedArguments(
+                    "Expected at least 1 arguments, but got 0.".to_string()
+                ),
+                Some(Span::new(1, 5, 1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+        assert!(matches!(
+            interpreter.eval("tick(1);"),
+            Err(RloxError::Interpret(
+                RuntimeError::MismatchedArguments(_),
+                ..
+            ))
+        ));
+        // Errors from a native function point at the call
+        let err = interpreter.eval("\nmax(1, \"two\");").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(2, 13, 1)));
+        assert_eq!(err.to_string(), "Expected a number, not two.");
+    }
+
+    #[test]
+    fn calling_lox_from_rust() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_global("prefix", "got ");
+        interpreter
+            .eval(
+                r#"
+                var events = [];
+                fun on_event(name, data) {
+                    events.push(prefix + name);
+                    return data * 2;
+                }
+                class Point { init(x) { this.x = x; } }
+                fun fail(x) {
+                    return x + nil;
+                }
+                "#,
+            )
+            .unwrap();
+
+        let on_event = interpreter.get_global("on_event").unwrap();
+        let value = interpreter
+            .call(&on_event, &["click".into_lox(), 21.into_lox()])
+            .unwrap();
+        assert!(matches!(value, LoxType::Number(n) if n == 42.0));
+        assert_eq!(global(&interpreter, "events"), "[\"got click\"]");
+
+        let point = interpreter.get_global("Point").unwrap();
+        let point = interpreter.call(&point, &[1.5.into_lox()]).unwrap();
+        assert!(
+            matches!(point, LoxType::Instance(point) if point.field("x").unwrap().to_string() == "1.5")
+        );
+        let str = interpreter.get_global("str").unwrap();
+        let value = interpreter.call(&str, &[true.into_lox()]).unwrap();
+        assert!(matches!(value, LoxType::String(s) if s == "true"));
+
+        assert!(matches!(
+            interpreter.get_global("missing"),
+            Err(RloxError::Interpret(RuntimeError::UndefinedVariable(_), ..))
+        ));
+        assert_eq!(
+            interpreter.call(&on_event, &[]).err(),
+            Some(RloxError::Interpret(
+                RuntimeError::MismatchedArguments("Expected 2 arguments, but got 0.".to_string()),
+                None,
+                Vec::new()
+            ))
+        );
+        assert!(matches!(
+            interpreter.call(&LoxType::Nil, &[]),
+            Err(RloxError::Interpret(RuntimeError::NotACallableType(_), ..))
+        ));
+
+        // Errors are traced back to the call from Rust
+        let fail = interpreter.get_global("fail").unwrap();
+        let err = interpreter.call(&fail, &[1.into_lox()]).unwrap_err();
+        assert_eq!(err.trace(), &[Frame::new("fail", 9)]);
+
+        // Native functions can call back into Lox, and it's traced back through them
+        interpreter.define_native("apply", Arity::Fixed(2), |interpreter, arguments| {
+            interpreter.call(&arguments[0], &arguments[1..])
+        });
+        assert!(matches!(interpreter.eval("apply(str, 1);"), Ok(LoxType::String(s)) if s == "1"));
+        assert!(matches!(
+            interpreter.eval("apply(on_event, \"key\");"),
+            Err(RloxError::Interpret(
+                RuntimeError::MismatchedArguments(_),
+                ..
+            ))
+        ));
+        let err = interpreter.eval("\napply(fail, 1);").unwrap_err();
+        assert_eq!(
+            err.trace(),
+            &[Frame::new("fail", 9), Frame::new("<script>", 2)]
+        );
+    }
+
+    #[test]
+    fn output_can_be_captured() {
+        let mut interpreter = Interpreter::new();
+        let output = interpreter.capture_output();
+        let errors = OutputBuffer::new();
+        interpreter.set_error_output(errors.clone());
+        interpreter.define_native("warn", Arity::Fixed(1), |interpreter, arguments| {
+            writeln!(interpreter.error_output(), "warning: {}", arguments[0]).unwrap();
+            Ok(LoxType::Nil)
+        });
+
+        interpreter
+            .eval("print(\"hello\");\nfor (i in range(0, 3)) print(i);\nwarn(\"careful\");")
+            .unwrap();
+        assert_eq!(output.take(), "hello\n0\n1\n2\n");
+        assert_eq!(errors.contents(), "warning: careful\n");
+
+        interpreter.eval("print([1, \"two\"]);").unwrap();
+        assert_eq!(output.contents(), "[1, \"two\"]\n");
+
+        // Output that can't be written is a runtime error
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        interpreter.set_output(Closed);
+        assert!(matches!(
+            interpreter.eval("print(1);"),
+            Err(RloxError::Interpret(RuntimeError::Output(_), Some(_), _))
+        ));
+    }
+
+    #[test]
+    fn recursion_and_early_returns() {
+        let interpreter = run(r#"
+            fun fib(n) {
+                if (n <= 1) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            var result = fib(15);
+
+            fun find(limit) {
+                var i = 0;
+                while (true) {
+                    { if (i == limit) return i; }
+                    i = i + 1;
+                }
+            }
+            var found = find(3);
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "result"), "610");
+        assert_eq!(global(&interpreter, "found"), "3");
+    }
+
+    #[test]
+    fn calls_get_fresh_environments() {
+        let interpreter = run(r#"
+            fun makeCounter() {
+                var i = 0;
+                fun count() {
+                    i = i + 1;
+                    return i;
+                }
+                return count;
+            }
+            var a = makeCounter();
+            var b = makeCounter();
+            a();
+            a();
+            var countA = a();
+            var countB = b();
+
+            fun outer(x) {
+                fun inner() { return x; }
+                if (x > 0) outer(x - 1);
+                return inner();
+            }
+            var reentrant = outer(3);
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "countA"), "3");
+        assert_eq!(global(&interpreter, "countB"), "1");
+        assert_eq!(global(&interpreter, "reentrant"), "3");
+    }
+
+    #[test]
+    fn globals_are_resolved_statically() {
+        let interpreter = run(r#"
+            var a = "global";
+            var first;
+            var second;
+            {
+                fun showA() { return a; }
+                first = showA();
+                var a = "block";
+                second = showA();
+            }
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "first"), "global");
+        assert_eq!(global(&interpreter, "second"), "global");
+    }
+
+    #[test]
+    fn class_fields_and_methods() {
+        let interpreter = run(r#"
+            class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                }
+                sum() { return this.x + this.y; }
+            }
+            var p = Point(1, 2);
+            p.x = 10;
+            var sum = p.sum();
+            var method = p.sum;
+            var bound = method();
+            var instance = p;
+            var cls = Point;
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "sum"), "12");
+        assert_eq!(global(&interpreter, "bound"), "12");
+        assert_eq!(global(&interpreter, "instance"), "<Point instance>");
+        assert_eq!(global(&interpreter, "cls"), "<class Point>");
+    }
+
+    #[test]
+    fn initializer_returns_this() {
+        let interpreter = run(r#"
+            class Foo {
+                init() {
+                    this.value = 1;
+                    return;
+                }
+            }
+            var foo = Foo();
+            var again = foo.init();
+            var same = foo == again;
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "same"), "true");
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let interpreter = run(r#"
+            var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                if (i == 2) continue;
+                if (i == 5) break;
+                sum = sum + i;
+            }
+            var steps = 0;
+            while (true) {
+                var inner = steps;
+                steps = steps + 1;
+                { var nested = inner; if (nested >= 3) break; }
+            }
+            var after = "after";
+            fun find() {
+                while (true) {
+                    for (;;) { return "found"; }
+                }
+            }
+            var found = find();
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "sum"), "8");
+        assert_eq!(global(&interpreter, "steps"), "4");
+        assert_eq!(global(&interpreter, "after"), "after");
+        assert_eq!(global(&interpreter, "found"), "found");
+    }
+
+    #[test]
+    fn for_in_loops() {
+        let interpreter = run(r#"
+            var list = [];
+            for (x in [1, nil, 3]) list.push(x);
+            var keys = "";
+            for (key in {"a": 1, "b": 2}) keys = keys + key;
+            var graphemes = [];
+            for (c in "é👋🏽") graphemes.push(c);
+            var sum = 0;
+            for (i in range(0, 10)) {
+                if (i == 2) continue;
+                if (i == 5) break;
+                sum = sum + i;
+            }
+
+            fun countdown(n) {
+                return fun () {
+                    n = n - 1;
+                    if (n < 0) return nil;
+                    return n;
+                };
+            }
+            var counted = [];
+            for (n in countdown(3)) counted.push(n);
+
+            class Bag {
+                init() { this.items = ["x", "y"]; }
+                iterator() { return this.items; }
+            }
+            var bagged = [];
+            for (item in Bag()) bagged.push(item);
+
+            var closures = [];
+            for (x in ["a", "b"]) closures.push(fun () { return x; });
+            var captured = closures[0]() + closures[1]();
+
+            fun first(items) {
+                for (item in items) return item;
+            }
+            var found = first(["found"]);
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "list"), "[1, nil, 3]");
+        assert_eq!(global(&interpreter, "keys"), "ab");
+        assert_eq!(global(&interpreter, "graphemes"), "[\"é\", \"👋🏽\"]");
+        assert_eq!(global(&interpreter, "sum"), "8");
+        assert_eq!(global(&interpreter, "counted"), "[2, 1, 0]");
+        assert_eq!(global(&interpreter, "bagged"), "[\"x\", \"y\"]");
+        assert_eq!(global(&interpreter, "captured"), "ab");
+        assert_eq!(global(&interpreter, "found"), "found");
+
+        assert_eq!(
+            run("for (x in 3) x;").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::NotIterable("3".to_string()),
+                Some(Span::new(1, 11, 1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+        assert!(matches!(
+            run("range(0, nil);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidRange(_), ..))
+        ));
+    }
+
+    #[test]
+    fn exceptions() {
+        let interpreter = run(r#"
+            var message;
+            var line;
+            try {
+                nil.field;
+            } catch (e) {
+                message = e.message;
+                line = e.line;
+            }
+
+            fun risky(value) {
+                try {
+                    if (value) throw value;
+                    return "returned";
+                } finally {
+                    cleaned = cleaned + 1;
+                }
+            }
+            var cleaned = 0;
+            var caught;
+            try { risky("thrown"); } catch (e) { caught = e; }
+            var returned = risky(nil);
+
+            var visited = [];
+            for (i in range(0, 3)) {
+                try {
+                    if (i == 1) continue;
+                    visited.push(i);
+                } finally {
+                    visited.push("f");
+                }
+            }
+            "#)
+        .unwrap();
+        assert_eq!(
+            global(&interpreter, "message"),
+            "Only instances have properties, not nil."
+        );
+        assert_eq!(global(&interpreter, "line"), "5");
+        assert_eq!(global(&interpreter, "caught"), "thrown");
+        assert_eq!(global(&interpreter, "returned"), "returned");
+        assert_eq!(global(&interpreter, "cleaned"), "2");
+        assert_eq!(
+            global(&interpreter, "visited"),
+            "[0, \"f\", \"f\", 2, \"f\"]"
+        );
+
+        // Uncaught errors report the thrown value, or its message
+        assert_eq!(
+            run("throw 42;").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::Thrown("42".to_string()),
+                Some(Span::new(1, 1, 5)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+        assert!(matches!(
+            run("class E < Error { init() { this.message = \"bad\"; } }\nthrow E();"),
+            Err(RloxError::Interpret(RuntimeError::Thrown(message), ..)) if message == "bad"
+        ));
+
+        // Throwing a caught error again keeps where it happened
+        assert_eq!(
+            run("try {\n  [].pop();\n} catch (e) {\n  throw e;\n}").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::EmptyList,
+                Some(Span::new(2, 10, 1)),
+                vec![Frame::new("<script>", 2)]
+            ))
+        );
+    }
+
+    #[test]
+    fn string_interpolation() {
+        let interpreter = run(r#"
+            var name = "Ada";
+            var greeting = "Hello, ${name}!\tBorn ${1815}";
+            var nested = "${"[${[1, "two"]}]"}";
+            var escaped = "\${name} \"\u{1F600}\"";
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "greeting"), "Hello, Ada!\tBorn 1815");
+        assert_eq!(global(&interpreter, "nested"), "[[1, \"two\"]]");
+        assert_eq!(global(&interpreter, "escaped"), "${name} \"😀\"");
+    }
+
+    #[test]
+    fn string_methods() {
+        let interpreter = run(r#"
+            var text = "  Héllo, wörld 👋🏽  ".trim();
+            var length = text.length();
+            var sub = text.substring(7, 14);
+            var at = text.index_of("wörld");
+            var missing = text.index_of("x");
+            var parts = "a,b,,c".split(",");
+            var letters = "é👋🏽".split("");
+            var joined = ", ".join([1, "two", nil, [3]]);
+            var replaced = "a-b-c".replace("-", "+");
+            var cases = text.upper() + " " + text.lower();
+            var affixes = [text.starts_with("Hé"), text.ends_with("!")];
+            var repeated = "ab".repeat(3) + "x".repeat(0);
+            var numbers = ["42".to_number(), " -1.5 ".to_number(), "1e3".to_number()];
+            var strs = str(1.5) + str(nil) + str([true]) + str("s");
+            var upper = "abc".upper;
+            var bound = upper();
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "length"), "14");
+        assert_eq!(global(&interpreter, "sub"), "wörld 👋🏽");
+        assert_eq!(global(&interpreter, "at"), "7");
+        assert_eq!(global(&interpreter, "missing"), "-1");
+        assert_eq!(global(&interpreter, "parts"), "[\"a\", \"b\", \"\", \"c\"]");
+        assert_eq!(global(&interpreter, "letters"), "[\"é\", \"👋🏽\"]");
+        assert_eq!(global(&interpreter, "joined"), "1, two, nil, [3]");
+        assert_eq!(global(&interpreter, "replaced"), "a+b+c");
+        assert_eq!(
+            global(&interpreter, "cases"),
+            "HÉLLO, WÖRLD 👋🏽 héllo, wörld 👋🏽"
+        );
+        assert_eq!(global(&interpreter, "affixes"), "[true, false]");
+        assert_eq!(global(&interpreter, "repeated"), "ababab");
+        assert_eq!(global(&interpreter, "numbers"), "[42, -1.5, nil]");
+        assert_eq!(global(&interpreter, "strs"), "1.5nil[true]s");
+        assert_eq!(global(&interpreter, "bound"), "ABC");
+
+        assert!(matches!(
+            run("\"abc\".substring(2, 4);"),
+            Err(RloxError::Interpret(RuntimeError::IndexOutOfRange(..), ..))
+        ));
+        assert!(matches!(
+            run("\"abc\".index_of(1);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidArgument(expected, _), ..)) if expected == "a string"
+        ));
+        assert!(matches!(
+            run("\"ab\".repeat(-1);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidArgument(..), ..))
+        ));
+        assert!(matches!(
+            run("\"ab\".repeat(10000000000000000000);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidArgument(expected, _), ..)) if expected == "a smaller count"
+        ));
+        assert!(matches!(
+            run("\"abc\".reverse();"),
+            Err(RloxError::Interpret(RuntimeError::UndefinedProperty(_), ..))
+        ));
+    }
+
+    #[test]
+    fn anonymous_functions() {
+        let interpreter = run(r#"
+            fun apply(f, x) { return f(x); }
+            var doubled = apply(fun (n) { return n * 2; }, 21);
+            fun adder(n) { return fun (x) { return x + n; }; }
+            var added = adder(5)(10);
+            var immediate = fun () { return "called"; }();
+            var lambda = fun () {};
+            fun (unused) {};
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "doubled"), "42");
+        assert_eq!(global(&interpreter, "added"), "15");
+        assert_eq!(global(&interpreter, "immediate"), "called");
+        assert_eq!(global(&interpreter, "lambda"), "<fn lambda@7:26#0()>");
+    }
+
+    #[test]
+    fn lists() {
+        let interpreter = run(r#"
+            var xs = [1, 2, 3];
+            xs[1] = "two";
+            xs.push([4]);
+            var popped = xs.pop();
+            xs.insert(0, nil);
+            var removed = xs.remove(1);
+            var sliced = xs.slice(1, 3);
+            var length = xs.length();
+            var nested = popped[0];
+            var empty = xs.slice(2, 1);
+
+            var next = sliced.iterator();
+            var first = next();
+            var second = next();
+            var done = next();
+
+            var push = xs.push;
+            push("late" + "r");
+            var same = xs == xs;
+            var different = [] == [];
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "xs"), "[nil, \"two\", 3, \"later\"]");
+        assert_eq!(global(&interpreter, "popped"), "[4]");
+        assert_eq!(global(&interpreter, "removed"), "1");
+        assert_eq!(global(&interpreter, "sliced"), "[\"two\", 3]");
+        assert_eq!(global(&interpreter, "length"), "3");
+        assert_eq!(global(&interpreter, "nested"), "4");
+        assert_eq!(global(&interpreter, "empty"), "[]");
+        assert_eq!(global(&interpreter, "first"), "two");
+        assert_eq!(global(&interpreter, "second"), "3");
+        assert_eq!(global(&interpreter, "done"), "nil");
+        assert_eq!(global(&interpreter, "same"), "true");
+        assert_eq!(global(&interpreter, "different"), "false");
+    }
+
+    #[test]
+    fn list_errors() {
+        assert_eq!(
+            run("var xs = [1, 2];\nxs[2];").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::IndexOutOfRange("2".to_string(), 2),
+                Some(Span::new(2, 5, 1)),
+                vec![Frame::new("<script>", 2)]
+            ))
+        );
+        assert!(matches!(
+            run("var xs = 1; xs[0] = 1;"),
+            Err(RloxError::Interpret(RuntimeError::NotIndexable(_), ..))
+        ));
+        assert!(matches!(
+            run("[].pop();"),
+            Err(RloxError::Interpret(RuntimeError::EmptyList, ..))
+        ));
+        assert!(matches!(
+            run("[1].insert(\"0\", 2);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidIndex(_), ..))
+        ));
+    }
+
+    #[test]
+    fn maps() {
+        let interpreter = run(r#"
+            var ages = {"ada": 36, "alan": 41};
+            ages["grace"] = 85;
+            ages["ada"] = 37;
+            var ada = ages["ada"];
+            var length = ages.length();
+            var has = ages.has("alan");
+            var missing = ages.has("linus");
+            var removed = ages.remove("alan");
+            var keys = ages.keys();
+            var values = ages.values();
+            var entries = ages.entries();
+
+            var next = ages.iterator();
+            var first = next();
+            var second = next();
+            var done = next();
+
+            var mixed = {nil: 1, true: 2, 0: 3, "0": 4};
+            var zero = mixed[-0];
+            var nested = {"list": [1, {"deep": true}], "empty": {}};
+            var same = ages == ages;
+            var different = {} == {};
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "ages"), "{\"ada\": 37, \"grace\": 85}");
+        assert_eq!(global(&interpreter, "ada"), "37");
+        assert_eq!(global(&interpreter, "length"), "3");
+        assert_eq!(global(&interpreter, "has"), "true");
+        assert_eq!(global(&interpreter, "missing"), "false");
+        assert_eq!(global(&interpreter, "removed"), "41");
+        assert_eq!(global(&interpreter, "keys"), "[\"ada\", \"grace\"]");
+        assert_eq!(global(&interpreter, "values"), "[37, 85]");
+        assert_eq!(
+            global(&interpreter, "entries"),
+            "[[\"ada\", 37], [\"grace\", 85]]"
+        );
+        assert_eq!(global(&interpreter, "first"), "ada");
+        assert_eq!(global(&interpreter, "second"), "grace");
+        assert_eq!(global(&interpreter, "done"), "nil");
+        assert_eq!(
+            global(&interpreter, "mixed"),
+            "{nil: 1, true: 2, 0: 3, \"0\": 4}"
+        );
+        assert_eq!(global(&interpreter, "zero"), "3");
+        assert_eq!(
+            global(&interpreter, "nested"),
+            "{\"list\": [1, {\"deep\": true}], \"empty\": {}}"
+        );
+        assert_eq!(global(&interpreter, "same"), "true");
+        assert_eq!(global(&interpreter, "different"), "false");
+    }
+
+    #[test]
+    fn map_errors() {
+        assert_eq!(
+            run("var m = {\"a\": 1};\nm[\"b\"];").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::UndefinedKey("\"b\"".to_string()),
+                Some(Span::new(2, 6, 1)),
+                vec![Frame::new("<script>", 2)]
+            ))
+        );
+        assert!(matches!(
+            run("var m = {}; m[[1]] = 2;"),
+            Err(RloxError::Interpret(RuntimeError::UnhashableKey(_), ..))
+        ));
+        assert!(matches!(
+            run("({[]: 1});"),
+            Err(RloxError::Interpret(RuntimeError::UnhashableKey(_), ..))
+        ));
+        assert!(matches!(
+            run("({}).remove(1);"),
+            Err(RloxError::Interpret(RuntimeError::UndefinedKey(_), ..))
+        ));
+    }
+
+    #[test]
+    fn property_errors() {
+        assert_eq!(
+            run("class A {} var a = A(); a.missing;").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::UndefinedProperty("missing".to_string()),
+                Some(Span::new(1, 27, 7)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+        assert_eq!(
+            run("var a = 10; a.field = 1;").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::NotAnInstance("10".to_string()),
+                Some(Span::new(1, 15, 5)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+    }
+
+    #[test]
+    fn inheritance_and_super() {
+        let interpreter = run(r#"
+            class A {
+                init(name) { this.name = name; }
+                greet() { return "A " + this.name; }
+                only_a() { return "only a"; }
+            }
+            class B < A {
+                greet() { return "B " + super.greet(); }
+            }
+            class C < B {}
+            var c = C("c");
+            var greeting = c.greet();
+            var inherited = c.only_a();
+            "#)
+        .unwrap();
+        assert_eq!(global(&interpreter, "greeting"), "B A c");
+        assert_eq!(global(&interpreter, "inherited"), "only a");
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        assert_eq!(
+            run("var NotAClass = 1; class A < NotAClass {}").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::SuperclassMustBeClass("1".to_string()),
+                Some(Span::new(1, 30, 9)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+        assert_eq!(
+            run("class A < A {}").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::InheritFromSelf("A".to_string()),
+                Some(Span::new(1, 11, 1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+    }
+
+    #[test]
+    fn runtime_error_points_at_source() {
+        let source = "var a = 1;\nprint(a + \"b\");\n";
+        let err = run(source).err().unwrap();
+        assert_eq!(err.span(), Some(Span::new(2, 9, 1)));
+        assert_eq!(
+            err.render("test.lox", source),
+            "error: Cannot concatenate types\n \
+             --> test.lox:2:9\n  \
+             |\n\
+             2 | print(a + \"b\");\n  \
+             |         ^\n    \
+             at <script> (test.lox:2)\n"
+        );
+
+        // Errors inside a call are reported where they happened, not at the call
+        let source = "fun f() {\n\treturn missing;\n}\nf();";
+        let err = run(source).err().unwrap();
+        assert_eq!(err.span(), Some(Span::new(2, 9, 7)));
+        assert!(err
+            .render("test.lox", source)
+            .ends_with("\t       ^^^^^^^\n    at f (test.lox:2)\n    at <script> (test.lox:4)\n"));
+    }
+
+    #[test]
+    fn runtime_error_backtrace() {
+        let source = r#"
+            class Counter {
+                init(limit) { this.limit = limit; }
+                check(n) {
+                    if (n > this.limit) return n + "!";
+                    return this.check(n + 1);
+                }
+            }
+            fun main() {
+                return Counter(2).check(0);
+            }
+            main();
+            "#;
+        let err = run(source).err().unwrap();
+        assert_eq!(
+            err.trace(),
+            &[
+                Frame::new("check", 5),
+                Frame::new("check", 6),
+                Frame::new("check", 6),
+                Frame::new("check", 6),
+                Frame::new("main", 10),
+                Frame::new("<script>", 12),
+            ]
+        );
+
+        // The interpreter can keep going after an error
+        let mut interpreter = Interpreter::new();
+        let mut scanner = Scanner::new();
+        scanner
+            .scan_tokens("fun f() { return nil + 1; } f();")
+            .unwrap();
+        let program: Result<Vec<Stmt>> = scanner.into_parser().parse().into_iter().collect();
+        assert!(interpreter.interpret(program.unwrap()).is_err());
+        assert!(interpreter.calls.is_empty());
+    }
+
+    #[test]
+    fn deep_recursion_overflows() {
+        // A deeper limit needs a bigger stack than a test thread has
+        let overflow = std::thread::Builder::new()
+            .stack_size(256 * 1024 * 1024)
+            .spawn(|| {
+                let mut interpreter = Interpreter::new();
+                interpreter.set_max_call_depth(1024);
+                let err = interpreter.eval("fun f() { f(); } f();").err().unwrap();
+                let caught = interpreter.eval(
+                    "var caught; try { fun f() { f(); } f(); } catch (e) { caught = e.message; } caught;",
+                );
+                (err.to_string(), err.trace().len(), caught.map(|c| c.to_string()))
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(
+            overflow,
+            (
+                "Stack overflow.".to_string(),
+                1024,
+                Ok("Stack overflow.".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn default_call_depth_fits_a_main_thread() {
+        let overflow = std::thread::Builder::new()
+            .stack_size(8 * 1024 * 1024)
+            .spawn(|| {
+                let err = Interpreter::new()
+                    .eval("fun f(n) { if (n == 0) return 0; return f(n - 1) + 1; } f(1000);")
+                    .err()
+                    .unwrap();
+                (err.to_string(), err.trace().len())
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(overflow, ("Stack overflow.".to_string(), MAX_CALL_DEPTH));
     }
 }
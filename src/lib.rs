@@ -14,8 +14,13 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod chunk;
+pub mod compiler;
 pub mod errors;
 pub mod interpreter;
+mod ordmap;
 pub mod parser;
 pub mod resolver;
 pub mod scanner;
+mod strings;
+pub mod vm;
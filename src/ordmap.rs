@@ -0,0 +1,135 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A hash map that remembers the order keys were first inserted in.
+//
+// Both interpreters use it for Lox maps, which print and iterate in insertion
+// order. Entries are kept in a vector, with a hash map from each key to its
+// position. Removing an entry shifts the ones after it down, so removal is
+// linear, but lookups and insertions stay constant time.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[derive(Debug, Clone)]
+pub struct OrderedMap<K, V> {
+    entries: Vec<(K, V)>,
+    positions: HashMap<K, usize>,
+}
+
+impl<K, V> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> OrderedMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.positions.contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.positions.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    // Replacing a value keeps the key where it was
+    pub fn insert(&mut self, key: K, value: V) {
+        match self.positions.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.positions.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let position = self.positions.remove(key)?;
+        let (_, value) = self.entries.remove(position);
+        for (key, _) in &self.entries[position..] {
+            *self.positions.get_mut(key).unwrap() -= 1;
+        }
+        Some(value)
+    }
+
+    // The entry at `position` in insertion order
+    pub fn get_index(&self, position: usize) -> Option<(&K, &V)> {
+        self.entries.get(position).map(|(key, value)| (key, value))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insertion_order() {
+        let mut map = OrderedMap::new();
+        map.insert("b", 1);
+        map.insert("a", 2);
+        map.insert("c", 3);
+        map.insert("b", 4);
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(&"b"), Some(&4));
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), ["b", "a", "c"]);
+        assert_eq!(map.get_index(1), Some((&"a", &2)));
+    }
+
+    #[test]
+    fn removal_keeps_order() {
+        let mut map = OrderedMap::new();
+        for (i, key) in ["a", "b", "c", "d"].iter().enumerate() {
+            map.insert(*key, i);
+        }
+
+        assert_eq!(map.remove(&"b"), Some(1));
+        assert_eq!(map.remove(&"b"), None);
+        assert!(!map.contains_key(&"b"));
+
+        // Later entries can still be found after shifting down
+        assert_eq!(map.get(&"d"), Some(&3));
+        map.insert("b", 5);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            [(&"a", &0), (&"c", &2), (&"d", &3), (&"b", &5)]
+        );
+        assert_eq!(map.values().sum::<usize>(), 10);
+    }
+}
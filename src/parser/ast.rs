@@ -14,47 +14,179 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-// TODO: Should TokenType be replaced with an AST-specific new type?
-// It's used for Binary operations: +, -, /, * and Unary: -, !.
-use crate::scanner::{Token, TokenType};
+use std::rc::Rc;
+
+use crate::scanner::{Span, Token, TokenType};
 
 // Depth of this variable's usage in the call stack.
 // Used for quick lookups in interpreter::environment,
 // and set via the semantic analysis pass: resolver.
 type EnvDepth = u32;
 
-// TODO: Replace String and TokenType types with Token.
-// Give `Token`s to the interpreter to print better error messages.
+// Every node knows where it came from in the source, for error messages.
+// Names are kept as a `Name`, operators as an `Operator`, and nodes that are
+// only introduced by a keyword or punctuation keep that token's `Span`.
 
 #[derive(Debug, Clone)]
 pub enum Stmt {
-    Block(Vec<Stmt>), // {}
+    Block(Span, Vec<Stmt>), // {}, starting at the opening brace
+    Break(Span),            // break;
+    Class(
+        Name,         // class name
+        Option<Expr>, // superclass, always an Expr::Variable
+        Vec<Stmt>,    // methods, each a Stmt::Fun
+    ),
+    Continue(Span),   // continue;
     Expression(Expr), // all kinds of expressions
+    ForIn(
+        Span,      // `for` keyword
+        Name,      // loop variable
+        Expr,      // what to iterate over
+        Box<Stmt>, // body
+    ),
     Fun(
-        String,     // fun name
-        Vec<Token>, // fun params
-        Vec<Stmt>,  // fun body
+        Name,      // fun name
+        Vec<Name>, // fun params
+        Vec<Stmt>, // fun body
     ),
     If(
+        Span,              // `if` keyword
         Expr,              // condition
         Box<Stmt>,         // statement
         Option<Box<Stmt>>, // optional else statement
     ),
-    Return(Token, Option<Expr>), // return a;
-    Var(String, Option<Expr>),   // "var" x (= 10)? ;
-    While(Expr, Box<Stmt>),      // while (true) { do_thing(); }
+    Return(Span, Option<Expr>), // return a;
+    Throw(Span, Expr),          // throw a;
+    Try(
+        Span,                      // `try` keyword
+        Box<Stmt>,                 // body, a Stmt::Block
+        Option<(Name, Box<Stmt>)>, // catch variable and block
+        Option<Box<Stmt>>,         // finally block
+    ),
+    Var(Name, Option<Expr>), // "var" x (= 10)? ;
+    While(
+        Span,         // `while` or `for` keyword
+        Expr,         // condition
+        Box<Stmt>,    // body
+        Option<Expr>, // increment of a `for` loop, run after the body and on `continue`
+    ),
 }
 
 #[derive(Debug, Clone)]
 pub enum Expr {
-    Assign(String, Box<Expr>, Option<EnvDepth>), // a = 10;
-    Binary(Box<Expr>, TokenType, Box<Expr>),     // a + a
-    Call(Box<Expr>, Token, Vec<Expr>),           // doSomething();
-    Grouping(Box<Expr>),                         // (a)
-    Literal(ExprLiteral),                        // 3.0, "", false
-    Logical(Box<Expr>, TokenType, Box<Expr>),    // false or "10"
-    Unary(TokenType, Box<Expr>),                 // -a, !true
-    Variable(String, Option<EnvDepth>),          // r-value
+    Assign(Name, Box<Expr>, Option<EnvDepth>),       // a = 10;
+    Binary(Box<Expr>, Operator, Box<Expr>),          // a + a
+    Call(Box<Expr>, Span, Vec<Expr>),                // doSomething();
+    Get(Box<Expr>, Name),                            // a.b
+    Grouping(Box<Expr>),                             // (a)
+    Index(Box<Expr>, Span, Box<Expr>),               // a[0]
+    Lambda(Span, Vec<Name>, Vec<Stmt>),              // fun (a) { return a; }
+    List(Span, Vec<Expr>),                           // [1, 2, 3]
+    Literal(Span, ExprLiteral),                      // 3.0, "", false
+    Logical(Box<Expr>, Operator, Box<Expr>),         // false or "10"
+    Map(Span, Vec<(Expr, Expr)>),                    // {"a": 1}
+    Set(Box<Expr>, Name, Box<Expr>),                 // a.b = 10;
+    SetIndex(Box<Expr>, Span, Box<Expr>, Box<Expr>), // a[0] = 10;
+    Stringify(Box<Expr>),                            // a, as interpolated into "${a}"
+    Super(Span, Name, Option<EnvDepth>),             // super.method
+    This(Span, Option<EnvDepth>),                    // this
+    Unary(Operator, Box<Expr>),                      // -a, !true
+    Variable(Name, Option<EnvDepth>),                // r-value
+}
+
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Block(span, _)
+            | Stmt::Break(span)
+            | Stmt::Continue(span)
+            | Stmt::ForIn(span, ..)
+            | Stmt::If(span, ..)
+            | Stmt::Return(span, _)
+            | Stmt::Throw(span, _)
+            | Stmt::Try(span, ..)
+            | Stmt::While(span, ..) => *span,
+            Stmt::Class(name, ..) | Stmt::Fun(name, ..) | Stmt::Var(name, _) => name.span(),
+            Stmt::Expression(expr) => expr.span(),
+        }
+    }
+}
+
+impl Expr {
+    // Where an error in this expression is reported: its operator, name or keyword
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Grouping(expr) | Expr::Stringify(expr) => expr.span(),
+            Expr::Binary(_, operator, _)
+            | Expr::Logical(_, operator, _)
+            | Expr::Unary(operator, _) => operator.span,
+            Expr::Assign(name, ..)
+            | Expr::Get(_, name)
+            | Expr::Set(_, name, _)
+            | Expr::Variable(name, _) => name.span(),
+            Expr::Call(_, span, _)
+            | Expr::Index(_, span, _)
+            | Expr::Lambda(span, ..)
+            | Expr::List(span, _)
+            | Expr::Literal(span, _)
+            | Expr::Map(span, _)
+            | Expr::SetIndex(_, span, ..)
+            | Expr::Super(span, ..)
+            | Expr::This(span, _) => *span,
+        }
+    }
+}
+
+// Operators only need their kind and location, which keeps them cheap to
+// clone along with the rest of the tree, unlike a `Token` and its lexeme.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Operator {
+    pub token_type: TokenType,
+    pub span: Span,
+}
+
+impl Operator {
+    pub fn new(token: &Token) -> Self {
+        Self {
+            token_type: token.token_type(),
+            span: token.span(),
+        }
+    }
+}
+
+// Names of variables, functions, classes and properties. They read like the
+// `Token` they came from, but share their text, so cloning the tree only bumps
+// a reference count.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    lexeme: Rc<str>,
+    span: Span,
+}
+
+impl Name {
+    pub fn new(token: &Token) -> Self {
+        Self {
+            lexeme: token.lexeme().into(),
+            span: token.span(),
+        }
+    }
+
+    pub fn lexeme(&self) -> &str {
+        &self.lexeme
+    }
+
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+// Anonymous functions are named after the "fun" keyword that starts them
+pub fn lambda_name(keyword: Span) -> String {
+    format!("lambda@{keyword}")
 }
 
 #[derive(Debug, Clone)]
@@ -17,8 +17,8 @@
 pub mod ast;
 
 use crate::errors::{ParseError, Result, RloxError};
-use crate::scanner::{Token, TokenLiteral, TokenType};
-use ast::{Expr, ExprLiteral, Stmt};
+use crate::scanner::{Span, Token, TokenLiteral, TokenType};
+use ast::{Expr, ExprLiteral, Name, Operator, Stmt};
 
 pub struct Parser {
     tokens: Vec<Token>,
@@ -48,21 +48,50 @@ impl Parser {
 
     // Check for variable declarations
     fn declaration(&mut self) -> Result<Stmt> {
-        if self.is_any_tokens(&[TokenType::Var]) {
+        if self.is_any_tokens(&[TokenType::Class]) {
+            self.class_declaration()
+        } else if self.is_any_tokens(&[TokenType::Var]) {
             self.var_declaration()
-        } else if self.is_any_tokens(&[TokenType::Fun]) {
+        } else if self.check(TokenType::Fun) && self.check_next(TokenType::Identifier) {
+            // "fun" without a name starts an anonymous function expression
+            self.advance();
             self.function("function")
         } else {
             self.statement()
         }
     }
 
+    // Handle class declaration
+    // A class body is a list of methods, which are parsed like functions
+    // without the leading "fun" keyword.
+    fn class_declaration(&mut self) -> Result<Stmt> {
+        let name = Name::new(self.consume(TokenType::Identifier, "Expect class name.")?);
+
+        // Optional superclass: class B < A {}
+        let superclass = match self.is_any_tokens(&[TokenType::Less]) {
+            true => {
+                let superclass =
+                    Name::new(self.consume(TokenType::Identifier, "Expect superclass name.")?);
+                Some(Expr::Variable(superclass, None))
+            }
+            false => None,
+        };
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+
+        let mut methods = vec![];
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+
+        Ok(Stmt::Class(name, superclass, methods))
+    }
+
     // Handle variable declaration
     fn var_declaration(&mut self) -> Result<Stmt> {
-        let token = self
-            .consume(TokenType::Identifier, "Expect variable name.")?
-            .token_literal()
-            .to_string();
+        let name = Name::new(self.consume(TokenType::Identifier, "Expect variable name.")?);
 
         let initializer = match self.is_any_tokens(&[TokenType::Equal]) {
             true => Some(self.expression()?),
@@ -74,16 +103,22 @@ impl Parser {
             "Expect ';' after variable declaration",
         )?;
 
-        Ok(Stmt::Var(token, initializer))
+        Ok(Stmt::Var(name, initializer))
     }
 
     // Handle function declaration
     fn function(&mut self, kind: &str) -> Result<Stmt> {
         // function identifier
-        let name = self
-            .consume(TokenType::Identifier, &format!("Expect {} name.", kind))?
-            .clone();
+        let name =
+            Name::new(self.consume(TokenType::Identifier, &format!("Expect {} name.", kind))?);
+
+        let (params, body) = self.function_body(kind)?;
 
+        Ok(Stmt::Fun(name, params, body))
+    }
+
+    // Handle the parameters and body shared by declared and anonymous functions
+    fn function_body(&mut self, kind: &str) -> Result<(Vec<Name>, Vec<Stmt>)> {
         // parameter
         self.consume(
             TokenType::LeftParen,
@@ -100,14 +135,16 @@ impl Parser {
 
             if params.len() >= 255 {
                 // error if there's too many params
-                return Err(RloxError::Parse(ParseError::TooManyParameters));
+                return Err(RloxError::Parse(
+                    ParseError::TooManyParameters,
+                    Some(self.peek().span()),
+                ));
             }
 
             // add param identifier to list
-            params.push(
-                self.consume(TokenType::Identifier, "Expect parameter name.")?
-                    .clone(),
-            );
+            params.push(Name::new(
+                self.consume(TokenType::Identifier, "Expect parameter name.")?,
+            ));
 
             if !self.is_any_tokens(&[TokenType::Comma]) {
                 // break if there's no more params
@@ -129,7 +166,7 @@ impl Parser {
 
         let body = self.block_stmt()?;
 
-        Ok(Stmt::Fun(name.token_literal().to_string(), params, body))
+        Ok((params, body))
     }
 
     // Statement functions
@@ -140,7 +177,8 @@ impl Parser {
             self.if_stmt()
         } else if self.is_any_tokens(&[TokenType::LeftBrace]) {
             // New block/scope
-            Ok(Stmt::Block(self.block_stmt()?))
+            let brace = self.previous().span();
+            Ok(Stmt::Block(brace, self.block_stmt()?))
         } else if self.is_any_tokens(&[TokenType::While]) {
             // While loop
             self.while_stmt()
@@ -149,6 +187,12 @@ impl Parser {
             self.for_stmt()
         } else if self.is_any_tokens(&[TokenType::Return]) {
             self.return_stmt()
+        } else if self.is_any_tokens(&[TokenType::Throw]) {
+            self.throw_stmt()
+        } else if self.is_any_tokens(&[TokenType::Try]) {
+            self.try_stmt()
+        } else if self.is_any_tokens(&[TokenType::Break, TokenType::Continue]) {
+            self.loop_control_stmt()
         } else {
             // We're an expression
             self.expression_stmt()
@@ -169,6 +213,7 @@ impl Parser {
     }
 
     fn if_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().span();
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
 
         let condition = self.expression()?;
@@ -182,10 +227,16 @@ impl Parser {
             else_branch = Some(Box::new(self.statement()?));
         }
 
-        Ok(Stmt::If(condition, Box::new(then_branch), else_branch))
+        Ok(Stmt::If(
+            keyword,
+            condition,
+            Box::new(then_branch),
+            else_branch,
+        ))
     }
 
     fn while_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().span();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
 
         let condition = self.expression()?;
@@ -194,13 +245,26 @@ impl Parser {
 
         let stmts = self.statement()?;
 
-        Ok(Stmt::While(condition, Box::new(stmts)))
+        Ok(Stmt::While(keyword, condition, Box::new(stmts), None))
     }
 
-    // For loops are de-sugared into a while loop with optional initializer
+    // For loops are de-sugared into a while loop with optional initializer.
+    // Every de-sugared statement is located at the `for` keyword.
     fn for_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().span();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
 
+        // for (x in iterable)
+        if self.check(TokenType::Identifier) && self.check_next(TokenType::In) {
+            let name = Name::new(self.advance());
+            self.advance();
+            let iterable = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after for-in clauses.")?;
+
+            let body = self.statement()?;
+            return Ok(Stmt::ForIn(keyword, name, iterable, Box::new(body)));
+        }
+
         // Handle optional initializer
         let initializer = match self.is_any_tokens(&[TokenType::Semicolon]) {
             true => None, // no initializer
@@ -211,7 +275,7 @@ impl Parser {
         };
 
         // Handle optional condition before each loop
-        let condition = match self.is_any_tokens(&[TokenType::Semicolon]) {
+        let condition = match self.check(TokenType::Semicolon) {
             true => None,
             false => Some(self.expression()?),
         };
@@ -219,37 +283,26 @@ impl Parser {
         self.consume(TokenType::Semicolon, "Expect ';' after condition.")?;
 
         // Handle optional increment expression after each loop
-        let increment = match self.is_any_tokens(&[TokenType::Semicolon]) {
+        let increment = match self.check(TokenType::RightParen) {
             true => None,
             false => Some(self.expression()?),
         };
 
         self.consume(TokenType::RightParen, "Expect ')' after increment.")?;
 
-        // We start de-sugaring the for() by appending the increment expression to the end
-        // of the body. Then we'll transform the body into the body of a while loop and
-        // attach its condition. Finally, we prepend it with the initializer.
+        // We start de-sugaring the for() by transforming the body into the body of a
+        // while loop, and attaching its condition and increment. The loop runs the
+        // increment after the body, and also when the body hits a `continue`.
+        // Finally, we prepend it with the initializer.
         let mut body = self.statement()?;
 
-        // if there's an increment, append it to the body
-        if let Some(expr) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(expr)]);
-        }
-
-        match condition {
-            None => {
-                // Make the condition `true` and create a while (true) loop
-                body = Stmt::While(Expr::Literal(ExprLiteral::Bool(true)), Box::new(body));
-            }
-            Some(expr) => {
-                // Otherwise, create a while (condition) loop
-                body = Stmt::While(expr, Box::new(body));
-            }
-        }
+        // Without a condition, create a while (true) loop
+        let condition = condition.unwrap_or(Expr::Literal(keyword, ExprLiteral::Bool(true)));
+        body = Stmt::While(keyword, condition, Box::new(body), increment);
 
         // Prepend the initializer if it exists
         if let Some(expr) = initializer {
-            body = Stmt::Block(vec![expr, body]);
+            body = Stmt::Block(keyword, vec![expr, body]);
         }
 
         // TODO we create an extra Block statement (and create a new environment)
@@ -257,9 +310,8 @@ impl Parser {
         // block {
         //   block {
         //     initializer;
-        //     while(condition) block {
+        //     while(condition; increment) block {
         //        body;
-        //        increment;
         //     }
         //   }
         // }
@@ -269,7 +321,7 @@ impl Parser {
     }
 
     fn return_stmt(&mut self) -> Result<Stmt> {
-        let keyword = self.previous().clone();
+        let keyword = self.previous().span();
         let expr = if !self.check(TokenType::Semicolon) {
             Some(self.expression()?)
         } else {
@@ -280,6 +332,62 @@ impl Parser {
         Ok(Stmt::Return(keyword, expr))
     }
 
+    fn throw_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().span();
+        let value = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.")?;
+        Ok(Stmt::Throw(keyword, value))
+    }
+
+    // try { } catch (e) { } finally { }, with at least one of catch and finally
+    fn try_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().span();
+        let body = self.braced_block("Expect '{' after 'try'.")?;
+
+        let catch = match self.is_any_tokens(&[TokenType::Catch]) {
+            true => {
+                self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+                let name =
+                    Name::new(self.consume(TokenType::Identifier, "Expect error variable name.")?);
+                self.consume(TokenType::RightParen, "Expect ')' after error variable.")?;
+                Some((name, self.braced_block("Expect '{' after catch clause.")?))
+            }
+            false => None,
+        };
+
+        let finally = match self.is_any_tokens(&[TokenType::Finally]) {
+            true => Some(self.braced_block("Expect '{' after 'finally'.")?),
+            false => None,
+        };
+
+        if catch.is_none() && finally.is_none() {
+            self.consume(
+                TokenType::Catch,
+                "Expect 'catch' or 'finally' after try block.",
+            )?;
+        }
+        Ok(Stmt::Try(keyword, body, catch, finally))
+    }
+
+    fn braced_block(&mut self, msg: &str) -> Result<Box<Stmt>> {
+        let brace = self.consume(TokenType::LeftBrace, msg)?.span();
+        Ok(Box::new(Stmt::Block(brace, self.block_stmt()?)))
+    }
+
+    // break; or continue;
+    fn loop_control_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        self.consume(
+            TokenType::Semicolon,
+            &format!("Expect ';' after '{}'.", keyword.lexeme()),
+        )?;
+
+        match keyword.token_type() {
+            TokenType::Break => Ok(Stmt::Break(keyword.span())),
+            _ => Ok(Stmt::Continue(keyword.span())),
+        }
+    }
+
     // Evaluate the expression and return Stmt::Expression(result)
     fn expression_stmt(&mut self) -> Result<Stmt> {
         let expr = self.expression()?;
@@ -297,18 +405,26 @@ impl Parser {
     }
 
     // a = "10";
+    // a.b = "10";
     fn assignment(&mut self) -> Result<Expr> {
         let expr = self.or()?;
 
         if self.is_any_tokens(&[TokenType::Equal]) {
+            let equals = self.previous().span();
             let value = self.assignment()?;
 
-            return if let Expr::Variable(name, _) = expr {
-                Ok(Expr::Assign(name, Box::new(value), None))
-            } else {
-                Err(RloxError::Parse(ParseError::ParseFailure(
-                    "Invalid assignment target.".to_string(),
-                )))
+            return match expr {
+                Expr::Variable(name, _) => Ok(Expr::Assign(name, Box::new(value), None)),
+                // The left-hand side was parsed as a property access,
+                // turn it into a property assignment instead.
+                Expr::Get(object, name) => Ok(Expr::Set(object, name, Box::new(value))),
+                Expr::Index(object, bracket, index) => {
+                    Ok(Expr::SetIndex(object, bracket, index, Box::new(value)))
+                }
+                _ => Err(RloxError::Parse(
+                    ParseError::ParseFailure("Invalid assignment target.".to_string()),
+                    Some(equals),
+                )),
             };
         }
 
@@ -320,7 +436,7 @@ impl Parser {
         let mut expr = self.and()?;
 
         while self.is_any_tokens(&[TokenType::Or]) {
-            let operator = self.previous().token_type();
+            let operator = Operator::new(self.previous());
             let right = self.and()?;
             expr = Expr::Logical(Box::new(expr), operator, Box::new(right));
         }
@@ -333,7 +449,7 @@ impl Parser {
         let mut expr = self.equality()?;
 
         while self.is_any_tokens(&[TokenType::And]) {
-            let operator = self.previous().token_type();
+            let operator = Operator::new(self.previous());
             let right = self.equality()?;
             expr = Expr::Logical(Box::new(expr), operator, Box::new(right));
         }
@@ -348,7 +464,7 @@ impl Parser {
         // Loop over equality expression, building up the AST with recursive Binary Expressions
         // a == b == c == d == e != f ...
         while self.is_any_tokens(&[TokenType::EqualEqual, TokenType::BangEqual]) {
-            let operator = self.previous().token_type(); // one of ==, !=
+            let operator = Operator::new(self.previous()); // one of ==, !=
             let rhs = self.comparison()?;
             expr = Expr::Binary(Box::new(expr), operator, Box::new(rhs));
         }
@@ -366,7 +482,7 @@ impl Parser {
             TokenType::Less,
             TokenType::LessEqual,
         ]) {
-            let operator = self.previous().token_type(); // one of >, >=, <, <+
+            let operator = Operator::new(self.previous()); // one of >, >=, <, <+
             let rhs = self.term()?;
             expr = Expr::Binary(Box::new(expr), operator, Box::new(rhs));
         }
@@ -379,7 +495,7 @@ impl Parser {
         let mut expr = self.factor()?;
 
         while self.is_any_tokens(&[TokenType::Plus, TokenType::Minus]) {
-            let operator = self.previous().token_type(); // one of +, -
+            let operator = Operator::new(self.previous()); // one of +, -
             let rhs = self.factor()?;
             expr = Expr::Binary(Box::new(expr), operator, Box::new(rhs));
         }
@@ -392,7 +508,7 @@ impl Parser {
         let mut expr = self.unary()?;
 
         while self.is_any_tokens(&[TokenType::Star, TokenType::Slash]) {
-            let operator = self.previous().token_type();
+            let operator = Operator::new(self.previous());
             let rhs = self.unary()?;
             expr = Expr::Binary(Box::new(expr), operator, Box::new(rhs));
         }
@@ -403,7 +519,7 @@ impl Parser {
     // !b, -a
     fn unary(&mut self) -> Result<Expr> {
         if self.is_any_tokens(&[TokenType::Minus, TokenType::Bang]) {
-            let operator = self.previous().token_type();
+            let operator = Operator::new(self.previous());
             let rhs = self.unary()?;
             return Ok(Expr::Unary(operator, Box::new(rhs)));
         }
@@ -411,13 +527,24 @@ impl Parser {
         self.call()
     }
 
-    // do_something()
+    // do_something(), a.b.c()
     fn call(&mut self) -> Result<Expr> {
         let mut expr = self.primary()?;
 
         loop {
             if self.is_any_tokens(&[TokenType::LeftParen]) {
                 expr = self.finish_call(expr)?;
+            } else if self.is_any_tokens(&[TokenType::Dot]) {
+                let name = Name::new(
+                    self.consume(TokenType::Identifier, "Expect property name after '.'.")?,
+                );
+                expr = Expr::Get(Box::new(expr), name);
+            } else if self.is_any_tokens(&[TokenType::LeftBracket]) {
+                let index = self.expression()?;
+                let bracket = self
+                    .consume(TokenType::RightBracket, "Expect ']' after index.")?
+                    .span();
+                expr = Expr::Index(Box::new(expr), bracket, Box::new(index));
             } else {
                 break;
             }
@@ -426,30 +553,52 @@ impl Parser {
         Ok(expr)
     }
 
-    // false, true, nil, groupings, identifiers, strings, numbers
+    // false, true, nil, this, super, groupings, identifiers, strings, numbers,
+    // lists, maps and anonymous functions
     fn primary(&mut self) -> Result<Expr> {
         if self.is_any_tokens(&[TokenType::False]) {
-            return Ok(Expr::Literal(ExprLiteral::Bool(false)));
+            return Ok(Expr::Literal(
+                self.previous().span(),
+                ExprLiteral::Bool(false),
+            ));
         }
         if self.is_any_tokens(&[TokenType::True]) {
-            return Ok(Expr::Literal(ExprLiteral::Bool(true)));
+            return Ok(Expr::Literal(
+                self.previous().span(),
+                ExprLiteral::Bool(true),
+            ));
         }
         if self.is_any_tokens(&[TokenType::Nil]) {
-            return Ok(Expr::Literal(ExprLiteral::Nil));
+            return Ok(Expr::Literal(self.previous().span(), ExprLiteral::Nil));
+        }
+        if self.is_any_tokens(&[TokenType::This]) {
+            return Ok(Expr::This(self.previous().span(), None));
+        }
+        if self.is_any_tokens(&[TokenType::Super]) {
+            let keyword = self.previous().span();
+            self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+            let method =
+                Name::new(self.consume(TokenType::Identifier, "Expect superclass method name.")?);
+            return Ok(Expr::Super(keyword, method, None));
         }
 
         if self.is_any_tokens(&[TokenType::Number, TokenType::String]) {
+            let span = self.previous().span();
             match self.previous().token_literal() {
                 TokenLiteral::Number(value) => {
-                    return Ok(Expr::Literal(ExprLiteral::Number(*value)));
+                    return Ok(Expr::Literal(span, ExprLiteral::Number(*value)));
                 }
                 TokenLiteral::Str(value) => {
-                    return Ok(Expr::Literal(ExprLiteral::String(value.clone())));
+                    return Ok(Expr::Literal(span, ExprLiteral::String(value.clone())));
                 }
                 _ => {}
             }
         }
 
+        if self.is_any_tokens(&[TokenType::Interpolation]) {
+            return self.interpolation();
+        }
+
         if self.is_any_tokens(&[TokenType::LeftParen]) {
             let expr = self.expression()?;
             self.consume(TokenType::RightParen, "Expected ')' after expression")?;
@@ -458,22 +607,59 @@ impl Parser {
         }
 
         if self.is_any_tokens(&[TokenType::Identifier]) {
-            if let TokenLiteral::Identifier(literal) = self.previous().token_literal().clone() {
-                return Ok(Expr::Variable(literal, None));
-            } else {
-                // Should not be reachable unless by programmer error in scanner.
-                panic!("Expected identifier");
+            return Ok(Expr::Variable(Name::new(self.previous()), None));
+        }
+
+        if self.is_any_tokens(&[TokenType::LeftBracket]) {
+            let bracket = self.previous().span();
+            let mut elements = vec![];
+            if !self.check(TokenType::RightBracket) {
+                loop {
+                    elements.push(self.expression()?);
+                    if !self.is_any_tokens(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
             }
+            self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+            return Ok(Expr::List(bracket, elements));
+        }
+
+        // Statements starting with '{' are blocks, so here it can only be a map
+        if self.is_any_tokens(&[TokenType::LeftBrace]) {
+            let brace = self.previous().span();
+            let mut entries = vec![];
+            if !self.check(TokenType::RightBrace) {
+                loop {
+                    let key = self.expression()?;
+                    self.consume(TokenType::Colon, "Expect ':' after map key.")?;
+                    entries.push((key, self.expression()?));
+                    if !self.is_any_tokens(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            self.consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+            return Ok(Expr::Map(brace, entries));
+        }
+
+        if self.is_any_tokens(&[TokenType::Fun]) {
+            let keyword = self.previous().span();
+            let (params, body) = self.function_body("function")?;
+            return Ok(Expr::Lambda(keyword, params, body));
         }
 
         if self.is_any_tokens(&[TokenType::Eof]) {
-            return Err(RloxError::Parse(ParseError::EOF));
+            return Err(RloxError::Parse(
+                ParseError::EOF,
+                Some(self.previous().span()),
+            ));
         }
 
-        // TODO: Try to be smarter here.
-        Err(RloxError::Parse(ParseError::UnexpectedToken(
-            self.peek().to_string(),
-        )))
+        Err(RloxError::Parse(
+            ParseError::UnexpectedToken(format!("Unexpected '{}'.", self.peek().lexeme())),
+            Some(self.peek().span()),
+        ))
     }
 
     // Helper functions
@@ -499,14 +685,67 @@ impl Parser {
 
     // checks the next token is the kind we're looking for, and advances if so.
     // otherwise, return the given error message in an UnexpectedToken type
+    // "a ${b} c" is lowered to a concatenation of its parts,
+    // with each expression turned into a string and empty text left out
+    fn interpolation(&mut self) -> Result<Expr> {
+        let mut parts = vec![];
+        loop {
+            let part = self.previous().clone();
+            if let TokenLiteral::Str(text) = part.token_literal() {
+                if !text.is_empty() {
+                    parts.push(Expr::Literal(
+                        part.span(),
+                        ExprLiteral::String(text.clone()),
+                    ));
+                }
+            }
+            if part.token_type() == TokenType::String {
+                break;
+            }
+
+            parts.push(Expr::Stringify(Box::new(self.expression()?)));
+            if !self.is_any_tokens(&[TokenType::Interpolation]) {
+                self.consume(
+                    TokenType::String,
+                    "Expect '}' after interpolated expression.",
+                )?;
+            }
+        }
+
+        // There's always an expression, so the result is always a string
+        let mut parts = parts.into_iter();
+        let first = parts
+            .next()
+            .expect("interpolated strings have an expression");
+        Ok(parts.fold(first, |left, right| {
+            let span = right.span();
+            let plus = Operator {
+                token_type: TokenType::Plus,
+                span: Span::new(span.line, span.column, 1),
+            };
+            Expr::Binary(Box::new(left), plus, Box::new(right))
+        }))
+    }
+
     fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<&Token> {
         if self.check(token_type) {
             return Ok(self.advance());
         }
 
-        Err(RloxError::Parse(ParseError::UnexpectedToken(
-            msg.to_string(),
-        )))
+        // Something missing at the end of the file is pointed at right after
+        // the last token, rather than past the end of the source.
+        let span = match (self.is_at_end(), self.current.checked_sub(1)) {
+            (true, Some(previous)) => {
+                let previous = self.tokens[previous].span();
+                Span::new(previous.line, previous.column + previous.len, 1)
+            }
+            _ => self.peek().span(),
+        };
+
+        Err(RloxError::Parse(
+            ParseError::UnexpectedToken(msg.to_string()),
+            Some(span),
+        ))
     }
 
     fn is_at_end(&self) -> bool {
@@ -517,6 +756,12 @@ impl Parser {
         self.peek().token_type() == token_type
     }
 
+    fn check_next(&self, token_type: TokenType) -> bool {
+        self.tokens
+            .get(self.current + 1)
+            .map_or(false, |token| token.token_type() == token_type)
+    }
+
     fn peek(&self) -> &Token {
         self.tokens.get(self.current).unwrap()
     }
@@ -543,7 +788,11 @@ impl Parser {
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
-                | TokenType::Return => return,
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Try => return,
                 _ => {}
             }
             self.advance();
@@ -559,7 +808,10 @@ impl Parser {
         if !self.check(TokenType::RightParen) {
             loop {
                 if arguments.len() >= 255 {
-                    return Err(RloxError::Parse(ParseError::TooManyParameters));
+                    return Err(RloxError::Parse(
+                        ParseError::TooManyParameters,
+                        Some(self.peek().span()),
+                    ));
                 }
                 arguments.push(self.expression()?);
 
@@ -570,8 +822,10 @@ impl Parser {
         }
 
         // Consume ending ')'
-        let paren = self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
-        Ok(Expr::Call(Box::new(callee), paren.clone(), arguments))
+        let paren = self
+            .consume(TokenType::RightParen, "Expect ')' after arguments.")?
+            .span();
+        Ok(Expr::Call(Box::new(callee), paren, arguments))
     }
 }
 
@@ -637,6 +891,166 @@ mod tests {
         assert!(p.is_any_tokens(&[TokenType::EqualEqual, TokenType::BangEqual]));
     }
 
+    #[test]
+    fn error_spans() {
+        let errors: Vec<_> = get_parser_scanner(Some("var a = ;\nprint(a)"))
+            .parse()
+            .into_iter()
+            .filter_map(|stmt| stmt.err())
+            .map(|err| err.span())
+            .collect();
+
+        // The stray ';', then the missing ';' just after the last token
+        assert_eq!(
+            errors,
+            vec![Some(Span::new(1, 9, 1)), Some(Span::new(2, 9, 1))]
+        );
+    }
+
+    #[test]
+    fn for_in() {
+        let mut p = get_parser_scanner(Some("for (x in xs) print(x);"));
+        let Ok(Stmt::ForIn(keyword, name, Expr::Variable(iterable, _), _)) = p.declaration() else {
+            panic!("expected a for-in loop");
+        };
+        assert_eq!(keyword, Span::new(1, 1, 3));
+        assert_eq!(name.lexeme(), "x");
+        assert_eq!(iterable.lexeme(), "xs");
+
+        // Three clauses are still a while loop
+        let mut p = get_parser_scanner(Some("for (; x < 1;) x;"));
+        assert!(matches!(p.declaration(), Ok(Stmt::While(..))));
+
+        let mut p = get_parser_scanner(Some("for (x in xs print(x);"));
+        assert!(p.declaration().is_err());
+    }
+
+    #[test]
+    fn try_and_throw() {
+        let mut p =
+            get_parser_scanner(Some("try { f(); } catch (e) { throw e; } finally { g(); }"));
+        let Ok(Stmt::Try(keyword, _, Some((name, handler)), Some(_))) = p.declaration() else {
+            panic!("expected a try statement");
+        };
+        assert_eq!(keyword, Span::new(1, 1, 3));
+        assert_eq!(name.lexeme(), "e");
+        let Stmt::Block(_, ref stmts) = *handler else {
+            panic!("expected a catch block");
+        };
+        assert!(matches!(stmts[..], [Stmt::Throw(..)]));
+
+        let mut p = get_parser_scanner(Some("try { f(); } finally { g(); }"));
+        assert!(matches!(
+            p.declaration(),
+            Ok(Stmt::Try(_, _, None, Some(_)))
+        ));
+
+        for source in ["try { f(); }", "try f();", "try {} catch e {}"] {
+            let mut p = get_parser_scanner(Some(source));
+            assert!(p.declaration().is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut p = get_parser_scanner(Some(r#""${a}, ${b}!";"#));
+        let Ok(Stmt::Expression(Expr::Binary(left, plus, right))) = p.declaration() else {
+            panic!("expected a concatenation");
+        };
+        assert_eq!(plus.token_type, TokenType::Plus);
+        assert!(matches!(
+            *right,
+            Expr::Literal(_, ExprLiteral::String(ref text)) if text == "!"
+        ));
+        let Expr::Binary(first, _, second) = *left else {
+            panic!("expected a concatenation");
+        };
+        assert!(matches!(*first, Expr::Binary(_, _, _)));
+        assert!(matches!(*second, Expr::Stringify(_)));
+
+        let mut p = get_parser_scanner(Some(r#""${a}";"#));
+        assert!(matches!(
+            p.declaration(),
+            Ok(Stmt::Expression(Expr::Stringify(_)))
+        ));
+
+        for source in [r#""${a b}";"#, r#""${}";"#] {
+            let mut p = get_parser_scanner(Some(source));
+            assert!(p.declaration().is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn node_spans() {
+        let mut p = get_parser_scanner(Some("  while (a) x = \"long\" + b;"));
+        let stmt = p.declaration().unwrap();
+        assert_eq!(stmt.span(), Span::new(1, 3, 5));
+
+        let Stmt::While(_, _, body, None) = stmt else {
+            panic!("expected a while loop");
+        };
+        let Stmt::Expression(Expr::Assign(_, value, _)) = *body else {
+            panic!("expected an assignment");
+        };
+        let Expr::Binary(left, operator, _) = *value else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(left.span(), Span::new(1, 17, 6));
+        assert_eq!(operator.span, Span::new(1, 24, 1));
+    }
+
+    #[test]
+    fn anonymous_functions() {
+        // Only a named "fun" starts a declaration
+        let mut p = get_parser_scanner(Some("fun (a) { return a; }(1);"));
+        let Ok(Stmt::Expression(Expr::Call(callee, ..))) = p.declaration() else {
+            panic!("expected a call expression");
+        };
+        let Expr::Lambda(keyword, params, body) = *callee else {
+            panic!("expected an anonymous function");
+        };
+        assert_eq!(keyword, Span::new(1, 1, 3));
+        assert_eq!(params.len(), 1);
+        assert_eq!(body.len(), 1);
+
+        let mut p = get_parser_scanner(Some("fun named() {}"));
+        assert!(matches!(p.declaration(), Ok(Stmt::Fun(..))));
+    }
+
+    #[test]
+    fn lists_and_indexing() {
+        let mut p = get_parser_scanner(Some("xs[0] = [1, 2][1];"));
+        let Ok(Stmt::Expression(Expr::SetIndex(_, bracket, _, value))) = p.declaration() else {
+            panic!("expected an index assignment");
+        };
+        assert_eq!(bracket, Span::new(1, 5, 1));
+
+        let Expr::Index(list, ..) = *value else {
+            panic!("expected an index expression");
+        };
+        assert!(matches!(*list, Expr::List(_, ref elements) if elements.len() == 2));
+
+        let mut p = get_parser_scanner(Some("[1, 2;"));
+        assert!(p.declaration().is_err());
+    }
+
+    #[test]
+    fn maps_and_blocks() {
+        let mut p = get_parser_scanner(Some("var m = {\"a\": 1, 2: [3]};"));
+        let Ok(Stmt::Var(_, Some(Expr::Map(brace, entries)))) = p.declaration() else {
+            panic!("expected a map literal");
+        };
+        assert_eq!(brace, Span::new(1, 9, 1));
+        assert_eq!(entries.len(), 2);
+
+        // A brace that starts a statement is always a block
+        let mut p = get_parser_scanner(Some("{}"));
+        assert!(matches!(p.declaration(), Ok(Stmt::Block(_, ref stmts)) if stmts.is_empty()));
+
+        let mut p = get_parser_scanner(Some("({\"a\" 1});"));
+        assert!(p.declaration().is_err());
+    }
+
     // #[test]
     // fn parsing_equality_bool() {
     //     let mut p = get_parser_scanner(Some("10 == 20;"));
@@ -20,36 +20,85 @@
 // 2) `stmt::fun` declaration introduces a new scope for its body and binds its parameters in that scope.
 // 3) `stmt::var` declaration adds a new variable to the current scope.
 // 4) `expr::variable` and `expr::assign` expressions need to have their variables resolved.
+// 5) `stmt::class` declaration introduces a new scope binding `this` for its methods.
+//    Subclasses get one more scope above that, binding `super`.
 //
 //
 // Scopes keeps track of how nested into the code we are. When scopes is len == 0, we're at global scope.
 // The resolver does not concern itself with anything in the global scope.
 
-// TODO:
-// We're going to be adding a few extra things here:
-// 1) Statements after `return` statements in a function.
-//    Perhaps other forms of unreachable code.
-// 2) `return` statements outside of any function.
-//    It's not syntatically incorrect, it just doesn't make sense.
-// 3) Variables used before defintion.
-//    Lox will implicitly assign "nil" to undefined variables, but we're going to reject it here.
+//
+// Code that is syntactically fine but makes no sense, like a `return` outside of
+// any function, is rejected here. Errors don't stop the resolver, it keeps going
+// to report every one of them in the program at once.
+//
+// Code that makes sense but is probably a mistake gets a warning instead:
+// unreachable statements, locals that are never read, and locals shadowing
+// other locals. Each kind of warning can be silenced by its code, and locals
+// starting with an underscore are never reported as unused.
 
 use std::collections::HashMap;
+use std::mem;
 
-use crate::errors::{ResolverError, Result};
-use crate::parser::ast::{Expr, Stmt};
+use crate::errors::{ResolverError, ResolverWarning, RloxError, Warning};
+use crate::parser::ast::{Expr, Name, Stmt};
+use crate::scanner::Span;
 
 #[derive(Default, Clone, Copy)]
 enum FunctionType {
     #[default]
     None,
     Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Default, Clone, Copy)]
+enum ClassType {
+    #[default]
+    None,
+    Class,
+    Subclass,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LocalKind {
+    Variable,
+    Parameter,
+    Declaration, // functions, classes and caught errors
+    Implicit,    // `this` and `super`
+}
+
+// Everything the resolver tracks about a local
+struct Local {
+    kind: LocalKind,
+    span: Span,
+    defined: bool,
+    read: bool,
+    assigned: Option<Span>, // first assignment after its declaration
+}
+
+impl Local {
+    fn new(kind: LocalKind, span: Span) -> Self {
+        Self {
+            kind,
+            span,
+            defined: false,
+            read: false,
+            assigned: None,
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct Resolver {
-    scopes: Vec<HashMap<String, bool>>,
+    scopes: Vec<HashMap<String, Local>>,
     current_function: FunctionType,
+    current_class: ClassType,
+    in_loop: bool,
+    errors: Vec<RloxError>,
+    warnings: Vec<Warning>,
+    allowed: Vec<&'static str>,
 }
 
 impl Resolver {
@@ -57,28 +106,115 @@ impl Resolver {
         Self::default()
     }
 
-    pub fn resolver(&mut self, program: &mut Vec<Stmt>) -> Result<()> {
-        for stmt in program {
-            self.resolve(stmt)?;
+    // Silence a kind of warning by its code, e.g. "unused_variable"
+    pub fn allow(&mut self, code: &str) -> crate::errors::Result<()> {
+        match ResolverWarning::CODES.iter().find(|c| **c == code) {
+            Some(code) => {
+                self.allowed.push(code);
+                Ok(())
+            }
+            None => Err(RloxError::Cmdline(format!("Unknown warning '{code}'."))),
         }
-        Ok(())
     }
 
-    fn resolve(&mut self, stmt: &mut Stmt) -> Result<()> {
+    // Warnings found by the last run, in the order they appear in the source
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Resolve the whole program, returning every error found in it
+    pub fn resolver(&mut self, program: &mut [Stmt]) -> Result<(), Vec<RloxError>> {
+        self.warnings.clear();
+        self.resolve_all(program);
+        self.warnings.sort_by_key(|warning| warning.span);
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(mem::take(&mut self.errors))
+        }
+    }
+
+    fn resolve_all(&mut self, stmts: &mut [Stmt]) {
+        // Only the first unreachable statement is reported
+        let mut returned = false;
+        let mut reported = false;
+
+        for stmt in stmts {
+            if returned && !reported {
+                let span = Span::line(stmt.span().line);
+                self.warn(ResolverWarning::UnreachableCode, span);
+                reported = true;
+            }
+
+            self.resolve(stmt);
+            returned |= always_jumps(stmt);
+        }
+    }
+
+    fn error(&mut self, err: ResolverError, span: Span) {
+        self.errors.push(RloxError::Resolver(err, Some(span)));
+    }
+
+    fn warn(&mut self, warning: ResolverWarning, span: Span) {
+        if !self.allowed.contains(&warning.code()) {
+            self.warnings.push(Warning::new(warning, span));
+        }
+    }
+
+    fn resolve(&mut self, stmt: &mut Stmt) {
         log::trace!("{:?}", stmt);
 
         match stmt {
-            Stmt::Block(body) => {
+            Stmt::Block(_, body) => {
                 log::trace!("block statement");
 
                 // Block creates a new scope, we keep track of the number of hops
                 // from the last environment
                 //
                 self.begin_scope();
-                for stmt in body {
-                    self.resolve(stmt)?;
+                self.resolve_all(body);
+                self.end_scope();
+            }
+            Stmt::Class(name, superclass, methods) => {
+                log::trace!("class statement");
+
+                let saved_class = self.current_class;
+                self.current_class = ClassType::Class;
+
+                self.declare(name, LocalKind::Declaration);
+                self.define(name.lexeme());
+
+                // Methods of a subclass close over a scope which binds `super`
+                // to the superclass.
+                if let Some(superclass) = superclass {
+                    self.current_class = ClassType::Subclass;
+                    self.resolve_expr(superclass);
+
+                    self.begin_scope();
+                    self.define_implicit("super");
+                }
+
+                // Methods are closed over a scope which binds `this`
+                // to the instance the method was accessed from.
+                self.begin_scope();
+                self.define_implicit("this");
+
+                for method in methods {
+                    let ftype = match method {
+                        Stmt::Fun(name, ..) if name.lexeme() == "init" => FunctionType::Initializer,
+                        _ => FunctionType::Method,
+                    };
+                    self.resolve_fun(method, ftype);
                 }
+
                 self.end_scope();
+
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+
+                self.current_class = saved_class;
             }
             Stmt::Expression(expr) => {
                 self.resolve_expr(expr);
@@ -88,28 +224,33 @@ impl Resolver {
 
                 // Functions create a new scope, and also bind their params to names
                 //
-                self.declare(ident);
-                self.define(ident);
+                self.declare(ident, LocalKind::Declaration);
+                self.define(ident.lexeme());
                 self.resolve_fun(stmt, FunctionType::Function);
             }
-            Stmt::If(condition, if_body, else_body) => {
+            Stmt::If(_, condition, if_body, else_body) => {
                 log::trace!("if statement");
                 self.resolve_expr(condition);
-                self.resolve(if_body)?;
+                self.resolve(if_body);
 
                 if let Some(stmt) = else_body {
-                    self.resolve(stmt)?;
+                    self.resolve(stmt);
                 }
             }
-            Stmt::Return(_, expr) => {
+            Stmt::Return(keyword, expr) => {
                 log::trace!("return statement");
 
                 if let FunctionType::None = self.current_function {
-                    // Lint: don't allow return outside of functions
-                    unimplemented!("return statement is not inside a function.")
+                    // Don't allow return outside of functions
+                    self.error(ResolverError::ReturnOutsideFunction, *keyword);
                 }
 
                 if let Some(expr) = expr {
+                    if let FunctionType::Initializer = self.current_function {
+                        // Initializers always return `this`
+                        self.error(ResolverError::ReturnFromInitializer, *keyword);
+                    }
+
                     self.resolve_expr(expr);
                 }
             }
@@ -119,41 +260,104 @@ impl Resolver {
                 // Variable declarations create new names
                 //
 
-                self.declare(ident);
+                self.declare(ident, LocalKind::Variable);
                 if let Some(expr) = init {
                     self.resolve_expr(expr);
                 }
-                self.define(ident);
+                self.define(ident.lexeme());
             }
-            Stmt::While(condition, body) => {
+            Stmt::While(_, condition, body, increment) => {
                 log::trace!("while statement");
                 self.resolve_expr(condition);
-                self.resolve(body)?;
+
+                let saved_loop = mem::replace(&mut self.in_loop, true);
+                self.resolve(body);
+                self.in_loop = saved_loop;
+
+                if let Some(increment) = increment {
+                    self.resolve_expr(increment);
+                }
             }
-        }
+            Stmt::ForIn(_, name, iterable, body) => {
+                log::trace!("for-in statement");
+                self.resolve_expr(iterable);
+
+                // The loop variable gets a fresh scope for every iteration
+                self.begin_scope();
+                self.declare(name, LocalKind::Variable);
+                self.define(name.lexeme());
+
+                let saved_loop = mem::replace(&mut self.in_loop, true);
+                self.resolve(body);
+                self.in_loop = saved_loop;
 
-        Ok(())
+                self.end_scope();
+            }
+            Stmt::Throw(_, value) => {
+                log::trace!("throw statement");
+                self.resolve_expr(value);
+            }
+            Stmt::Try(_, body, catch, finally) => {
+                log::trace!("try statement");
+                self.resolve(body);
+
+                // The error gets a scope around the catch block.
+                // Like a declaration, it's fine never to read it.
+                if let Some((name, handler)) = catch {
+                    self.begin_scope();
+                    self.declare(name, LocalKind::Declaration);
+                    self.define(name.lexeme());
+                    self.resolve(handler);
+                    self.end_scope();
+                }
+
+                if let Some(finally) = finally {
+                    self.resolve(finally);
+                }
+            }
+            Stmt::Break(keyword) | Stmt::Continue(keyword) => {
+                let keyword = *keyword;
+                let name = match stmt {
+                    Stmt::Break(_) => "break",
+                    _ => "continue",
+                };
+                log::trace!("{name} statement");
+
+                // Functions declared inside of a loop can't leave it
+                if !self.in_loop {
+                    let err = ResolverError::OutsideLoop(name.to_string());
+                    self.error(err, keyword);
+                }
+            }
+        }
     }
 
     fn resolve_fun(&mut self, stmt: &mut Stmt, ftype: FunctionType) {
+        if let Stmt::Fun(_, params, body) = stmt {
+            self.resolve_function(params, body, ftype);
+        } else {
+            unreachable!();
+        };
+    }
+
+    // Shared by declared functions, methods and anonymous functions
+    fn resolve_function(&mut self, params: &[Name], body: &mut [Stmt], ftype: FunctionType) {
         let saved_ftype = self.current_function;
         self.current_function = ftype;
+        let saved_loop = mem::replace(&mut self.in_loop, false);
 
         self.begin_scope();
-        if let Stmt::Fun(_, params, body) = stmt {
-            for param in params {
-                self.declare(param.lexeme());
-                self.define(param.lexeme());
-            }
+        for param in params {
+            self.declare(param, LocalKind::Parameter);
+            self.define(param.lexeme());
+        }
 
-            // resolve function body
-            self.resolver(body);
-        } else {
-            unreachable!();
-        };
+        // resolve function body
+        self.resolve_all(body);
         self.end_scope();
 
         self.current_function = saved_ftype;
+        self.in_loop = saved_loop;
     }
 
     fn resolve_expr(&mut self, root: &mut Expr) {
@@ -188,12 +392,23 @@ impl Resolver {
                     self.resolve_expr(i);
                 }
             }
-            Expr::Grouping(expr) => {
+            Expr::Get(object, _) => {
+                log::trace!("get expression");
+
+                // Properties are looked up dynamically, only the object is resolved.
+                self.resolve_expr(object);
+            }
+            Expr::Grouping(expr) | Expr::Stringify(expr) => {
                 log::trace!("grouping expression");
 
                 self.resolve_expr(expr);
             }
-            Expr::Literal(_) => {
+            Expr::Lambda(_, params, body) => {
+                log::trace!("lambda expression");
+
+                self.resolve_function(params, body, FunctionType::Function);
+            }
+            Expr::Literal(..) => {
                 log::trace!("literal expression");
 
                 // do nothing
@@ -204,6 +419,60 @@ impl Resolver {
                 self.resolve_expr(expr1);
                 self.resolve_expr(expr2);
             }
+            Expr::Set(object, _, value) => {
+                log::trace!("set expression");
+
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+            }
+            Expr::List(_, elements) => {
+                log::trace!("list expression");
+
+                for element in elements {
+                    self.resolve_expr(element);
+                }
+            }
+            Expr::Map(_, entries) => {
+                log::trace!("map expression");
+
+                for (key, value) in entries {
+                    self.resolve_expr(key);
+                    self.resolve_expr(value);
+                }
+            }
+            Expr::Index(object, _, index) => {
+                log::trace!("index expression");
+
+                self.resolve_expr(object);
+                self.resolve_expr(index);
+            }
+            Expr::SetIndex(object, _, index, value) => {
+                log::trace!("index assignment expression");
+
+                self.resolve_expr(object);
+                self.resolve_expr(index);
+                self.resolve_expr(value);
+            }
+            Expr::Super(keyword, ..) => {
+                log::trace!("super expression");
+
+                // `super` only has meaning inside of a subclass's methods
+                let span = *keyword;
+                match self.current_class {
+                    ClassType::None => self.error(ResolverError::SuperOutsideClass, span),
+                    ClassType::Class => self.error(ResolverError::SuperWithoutSuperclass, span),
+                    ClassType::Subclass => self.resolve_local(root),
+                }
+            }
+            Expr::This(keyword, ..) => {
+                log::trace!("this expression");
+
+                // `this` only has meaning inside of a method
+                match self.current_class {
+                    ClassType::None => self.error(ResolverError::ThisOutsideClass, *keyword),
+                    _ => self.resolve_local(root),
+                }
+            }
             Expr::Unary(_, expr) => {
                 log::trace!("unary expression");
 
@@ -212,17 +481,14 @@ impl Resolver {
             Expr::Variable(ident, _) => {
                 log::trace!("variable expression");
 
-                if !self.scopes.is_empty() {
-                    match self.scopes.last().unwrap().get(ident) {
-                        Some(false) => {
-                            // Raise an error
-                            // "Can't read local variable in its own initializer"
-                            // return Err();
-                            unimplemented!("can't read local variable in its own initializer");
-                        }
-                        None => (),
-                        Some(_) => (),
-                    }
+                let declared_only = self
+                    .scopes
+                    .last()
+                    .and_then(|scope| scope.get(ident.lexeme()))
+                    .map_or(false, |local| !local.defined);
+                if declared_only {
+                    let err = ResolverError::LocalInOwnInitializer(ident.lexeme().to_string());
+                    self.error(err, ident.span());
                 }
 
                 self.resolve_local(root);
@@ -235,24 +501,33 @@ impl Resolver {
     // We send the expression, name, and number of hops to be stored in
     // the interpreter.
     fn resolve_local(&mut self, expr: &mut Expr) {
-        assert!(matches!(expr, Expr::Assign(..) | Expr::Variable(..)));
+        assert!(matches!(
+            expr,
+            Expr::Assign(..) | Expr::Variable(..) | Expr::This(..) | Expr::Super(..)
+        ));
 
         let name = match expr {
-            Expr::Assign(name, ..) => name.clone(),
-            Expr::Variable(name, ..) => name.clone(),
+            Expr::Assign(name, ..) => name.lexeme().to_string(),
+            Expr::Variable(name, ..) => name.lexeme().to_string(),
+            Expr::This(..) => "this".to_string(),
+            Expr::Super(..) => "super".to_string(),
             _ => unreachable!(),
         };
 
         // If we find the variable in the list of scopes, then modify the expression
         // AST node = Some(distance).
         // Otherwise, we assume it's global and leave it None
-        for (distance, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(&name) {
+        for (distance, scope) in self.scopes.iter_mut().rev().enumerate() {
+            if let Some(local) = scope.get_mut(&name) {
                 match expr {
-                    Expr::Assign(.., ref mut depth) => {
+                    Expr::Assign(ref ident, .., ref mut depth) => {
+                        local.assigned.get_or_insert(ident.span());
                         *depth = Some(distance as u32);
                     }
-                    Expr::Variable(.., ref mut depth) => {
+                    Expr::Variable(.., ref mut depth)
+                    | Expr::This(.., ref mut depth)
+                    | Expr::Super(.., ref mut depth) => {
+                        local.read = true;
                         *depth = Some(distance as u32);
                     }
                     _ => unreachable!(),
@@ -272,49 +547,340 @@ impl Resolver {
     // {
     //   var a = a;
     // }
-    fn declare(&mut self, ident: &str) {
-        if self.scopes.is_empty() {
+    fn declare(&mut self, name: &Name, kind: LocalKind) {
+        let (last, enclosing) = match self.scopes.split_last_mut() {
+            Some(scopes) => scopes,
             // we're in global scope
-            return;
-        }
+            None => return,
+        };
 
-        let last = self.scopes.last_mut().unwrap();
-        if last.get(ident).is_some() {
-            // Lint: Don't allow re-declaring a variable in scopes
-            // (global is fine)
-            unimplemented!("variable re-declared")
-        }
+        // Don't allow re-declaring a variable in scopes
+        // (global is fine)
+        let redeclared = last.contains_key(name.lexeme());
+        let shadows = enclosing.iter().any(|scope| {
+            scope
+                .get(name.lexeme())
+                .map_or(false, |local| local.kind != LocalKind::Implicit)
+        });
 
         // insert name and mark it as un-initialized
-        last.insert(ident.to_string(), false);
+        last.insert(name.lexeme().to_string(), Local::new(kind, name.span()));
+
+        if redeclared {
+            let err = ResolverError::AlreadyDeclared(name.lexeme().to_string());
+            self.error(err, name.span());
+        } else if shadows {
+            let warning = ResolverWarning::Shadowing(name.lexeme().to_string());
+            self.warn(warning, name.span());
+        }
     }
 
     fn define(&mut self, ident: &str) {
-        if self.scopes.is_empty() {
-            // we're in global scope
-            return;
+        // mark the name as initialized (or do nothing if we're global)
+        if let Some(local) = self
+            .scopes
+            .last_mut()
+            .and_then(|scope| scope.get_mut(ident))
+        {
+            local.defined = true;
         }
+    }
 
-        let last = self.scopes.last_mut().unwrap();
-        // insert name and mark it as initialized
-        last.insert(ident.to_string(), true);
+    // Bind a name the user never declared, like `this`
+    fn define_implicit(&mut self, ident: &str) {
+        let mut local = Local::new(LocalKind::Implicit, Span::default());
+        local.defined = true;
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(ident.to_string(), local);
     }
 
     fn begin_scope(&mut self) {
         self.scopes.push(HashMap::new());
     }
 
+    // Locals that were never read are reported as the scope ends
     fn end_scope(&mut self) {
-        self.scopes.pop();
+        let scope = self.scopes.pop().unwrap_or_default();
+
+        for (name, local) in scope {
+            if local.read || name.starts_with('_') {
+                continue;
+            }
+
+            match (local.kind, local.assigned) {
+                (LocalKind::Variable | LocalKind::Parameter, Some(assigned)) => {
+                    self.warn(ResolverWarning::UnusedAssignment(name), assigned)
+                }
+                (LocalKind::Variable, None) => {
+                    self.warn(ResolverWarning::UnusedVariable(name), local.span)
+                }
+                (LocalKind::Parameter, None) => {
+                    self.warn(ResolverWarning::UnusedParameter(name), local.span)
+                }
+                (LocalKind::Declaration | LocalKind::Implicit, _) => (),
+            }
+        }
+    }
+}
+
+// Whether a statement always returns or jumps out of its loop,
+// making anything after it unreachable
+fn always_jumps(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return(..) | Stmt::Break(_) | Stmt::Continue(_) | Stmt::Throw(..) => true,
+        Stmt::Block(_, body) => body.iter().any(always_jumps),
+        Stmt::If(_, _, then_branch, Some(else_branch)) => {
+            always_jumps(then_branch) && always_jumps(else_branch)
+        }
+        // Errors in the body are caught, so the catch block has to jump too
+        Stmt::Try(_, body, catch, finally) => {
+            finally.as_deref().map_or(false, always_jumps)
+                || (always_jumps(body)
+                    && catch
+                        .as_ref()
+                        .map_or(true, |(_, handler)| always_jumps(handler)))
+        }
+        _ => false,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::errors::Result;
+    use crate::scanner::Scanner;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let mut scanner = Scanner::new();
+        scanner.scan_tokens(source).unwrap();
+        let program: Result<Vec<Stmt>> = scanner.into_parser().parse().into_iter().collect();
+        program.unwrap()
+    }
+
+    fn resolve(source: &str) -> Vec<Stmt> {
+        let mut program = parse(source);
+        Resolver::new().resolver(&mut program).unwrap();
+        program
+    }
+
+    fn errors(source: &str) -> Vec<RloxError> {
+        Resolver::new().resolver(&mut parse(source)).unwrap_err()
+    }
 
     #[test]
     fn basics() {
-        todo!();
+        let program = resolve("var a = 1; { var b = a; b = 2; }");
+
+        // Globals are left unresolved, locals get their depth
+        if let Stmt::Block(_, body) = &program[1] {
+            assert!(matches!(
+                &body[0],
+                Stmt::Var(_, Some(Expr::Variable(_, None)))
+            ));
+            assert!(matches!(
+                &body[1],
+                Stmt::Expression(Expr::Assign(_, _, Some(0)))
+            ));
+        } else {
+            panic!("expected a block");
+        }
+    }
+
+    #[test]
+    fn this_resolves_to_method_scope() {
+        let program = resolve("class A { get() { return this; } }");
+
+        // `this` is one hop out from the method's own scope
+        if let Stmt::Class(_, _, methods) = &program[0] {
+            if let Stmt::Fun(_, _, body) = &methods[0] {
+                assert!(matches!(
+                    &body[0],
+                    Stmt::Return(_, Some(Expr::This(_, Some(1))))
+                ));
+                return;
+            }
+        }
+        panic!("expected a class with a method");
+    }
+
+    #[test]
+    fn errors_have_locations() {
+        assert_eq!(
+            errors("return 1;"),
+            vec![RloxError::Resolver(
+                ResolverError::ReturnOutsideFunction,
+                Some(Span::new(1, 1, 6))
+            )]
+        );
+        assert_eq!(
+            errors("fun f() {\n  var a = 1;\n  var a = 2;\n}"),
+            vec![RloxError::Resolver(
+                ResolverError::AlreadyDeclared("a".to_string()),
+                Some(Span::new(3, 7, 1))
+            )]
+        );
+        assert_eq!(
+            errors("{ var a = a; }"),
+            vec![RloxError::Resolver(
+                ResolverError::LocalInOwnInitializer("a".to_string()),
+                Some(Span::new(1, 11, 1))
+            )]
+        );
+    }
+
+    #[test]
+    fn loop_control_outside_loop() {
+        assert_eq!(
+            errors("break;"),
+            vec![RloxError::Resolver(
+                ResolverError::OutsideLoop("break".to_string()),
+                Some(Span::new(1, 1, 5))
+            )]
+        );
+
+        // Functions can't leave the loop they're declared in
+        assert_eq!(
+            errors("while (true) { fun f() { continue; } break; }"),
+            vec![RloxError::Resolver(
+                ResolverError::OutsideLoop("continue".to_string()),
+                Some(Span::new(1, 26, 8))
+            )]
+        );
+        assert_eq!(
+            warnings("while (true) { break; print(1); }", &[]),
+            vec![Warning::new(
+                ResolverWarning::UnreachableCode,
+                Span::line(1)
+            )]
+        );
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let errors: Vec<_> = errors(
+            r#"
+            this;
+            super.method();
+            class A { init() { return 1; } method() { super.method(); } }
+            fun f(a, a) {}
+            return;
+            "#,
+        )
+        .into_iter()
+        .map(|err| match err {
+            RloxError::Resolver(err, Some(_)) => err,
+            other => panic!("unexpected error {other:?}"),
+        })
+        .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                ResolverError::ThisOutsideClass,
+                ResolverError::SuperOutsideClass,
+                ResolverError::ReturnFromInitializer,
+                ResolverError::SuperWithoutSuperclass,
+                ResolverError::AlreadyDeclared("a".to_string()),
+                ResolverError::ReturnOutsideFunction,
+            ]
+        );
+    }
+
+    fn warnings(source: &str, allowed: &[&str]) -> Vec<Warning> {
+        let mut resolver = Resolver::new();
+        for code in allowed {
+            resolver.allow(code).unwrap();
+        }
+        resolver.resolver(&mut parse(source)).unwrap();
+        mem::take(&mut resolver.warnings)
+    }
+
+    #[test]
+    fn lints() {
+        let source = r#"
+            fun f(unused, _ignored, used) {
+                var shadowed = used;
+                {
+                    var shadowed = 1;
+                    var never_read;
+                    never_read = shadowed;
+                }
+                return shadowed;
+                print(1);
+                print(2);
+            }
+            "#;
+        assert_eq!(
+            warnings(source, &[]),
+            vec![
+                Warning::new(
+                    ResolverWarning::UnusedParameter("unused".to_string()),
+                    Span::new(2, 19, 6)
+                ),
+                Warning::new(
+                    ResolverWarning::Shadowing("shadowed".to_string()),
+                    Span::new(5, 25, 8)
+                ),
+                Warning::new(
+                    ResolverWarning::UnusedAssignment("never_read".to_string()),
+                    Span::new(7, 21, 10)
+                ),
+                Warning::new(ResolverWarning::UnreachableCode, Span::line(10)),
+            ]
+        );
+
+        // Each kind can be silenced on its own
+        let allowed = ["unused_parameter", "shadowing", "unused_assignment"];
+        assert_eq!(
+            warnings(source, &allowed),
+            vec![Warning::new(
+                ResolverWarning::UnreachableCode,
+                Span::line(10)
+            )]
+        );
+        assert!(Resolver::new().allow("not_a_warning").is_err());
+    }
+
+    #[test]
+    fn no_false_positives() {
+        let source = r#"
+            var global = 1;
+            var global = 2;
+            class A < B {
+                init(value) { this.value = value; }
+                get() { return super.get(); }
+            }
+            fun outer(n) {
+                fun inner() { return n; }
+                if (n > 0) return inner; else print(n);
+                var counter = 0;
+                counter = counter + 1;
+                return counter;
+            }
+            "#;
+        assert_eq!(warnings(source, &[]), vec![]);
+        assert_eq!(
+            warnings("try { throw 1; } catch (unused) {} print(1);", &[]),
+            vec![]
+        );
+        assert_eq!(
+            warnings(
+                "fun f() { try { return 1; } finally { return 2; } print(3); }",
+                &[]
+            ),
+            vec![Warning::new(
+                ResolverWarning::UnreachableCode,
+                Span::line(1)
+            )]
+        );
+        assert_eq!(
+            warnings("{ var unused; }", &[]),
+            vec![Warning::new(
+                ResolverWarning::UnusedVariable("unused".to_string()),
+                Span::new(1, 7, 6)
+            )]
+        );
     }
 }
@@ -14,7 +14,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use unicode_segmentation::UnicodeSegmentation;
+use unicode_segmentation::{Graphemes, UnicodeSegmentation};
 
 pub trait PeekableIterator: Iterator {
     fn peek(&self) -> Option<Self::Item>;
@@ -24,10 +24,16 @@ pub struct Input<'a>(Vec<&'a str>);
 
 impl<'a> Input<'a> {
     pub fn new(source: &'a str) -> Self {
-        Self(UnicodeSegmentation::graphemes(source, true).collect())
+        Self(graphemes(source).collect())
     }
 }
 
+// Text is split into extended grapheme clusters, both when it's scanned and
+// when a string is iterated over
+pub fn graphemes(text: &str) -> Graphemes<'_> {
+    UnicodeSegmentation::graphemes(text, true)
+}
+
 // Custom iterator type
 impl<'a> IntoIterator for Input<'a> {
     type Item = &'a str;
@@ -53,6 +59,7 @@ impl<'a> InputIter<'a> {
     pub fn line(&self) -> usize {
         self.line_num
     }
+    // Column of the next grapheme
     pub fn location(&self) -> usize {
         self.line_pos
     }
@@ -25,11 +25,15 @@ use self::input::{Input, InputIter, PeekableIterator};
 use crate::errors::{Result, RloxError, ScanError};
 use crate::parser::Parser;
 
-pub use tokens::{Token, TokenLiteral, TokenType};
+pub(crate) use input::graphemes;
+pub use tokens::{Span, Token, TokenLiteral, TokenType};
 
 pub struct Scanner {
     tokens: Vec<Token>,
     keywords: HashMap<String, TokenType>,
+    // Braces opened inside each interpolated expression being scanned.
+    // The `}` that closes the expression goes back to scanning its string.
+    interpolations: Vec<usize>,
 }
 
 impl Debug for Scanner {
@@ -55,18 +59,25 @@ impl Scanner {
     pub fn new() -> Scanner {
         let mut keywords = HashMap::new();
         keywords.insert("and".into(), TokenType::And);
+        keywords.insert("break".into(), TokenType::Break);
+        keywords.insert("catch".into(), TokenType::Catch);
         keywords.insert("class".into(), TokenType::Class);
+        keywords.insert("continue".into(), TokenType::Continue);
         keywords.insert("else".into(), TokenType::Else);
         keywords.insert("false".into(), TokenType::False);
+        keywords.insert("finally".into(), TokenType::Finally);
         keywords.insert("fun".into(), TokenType::Fun);
         keywords.insert("for".into(), TokenType::For);
         keywords.insert("if".into(), TokenType::If);
+        keywords.insert("in".into(), TokenType::In);
         keywords.insert("nil".into(), TokenType::Nil);
         keywords.insert("or".into(), TokenType::Or);
         keywords.insert("return".into(), TokenType::Return);
         keywords.insert("super".into(), TokenType::Super);
         keywords.insert("this".into(), TokenType::This);
+        keywords.insert("throw".into(), TokenType::Throw);
         keywords.insert("true".into(), TokenType::True);
+        keywords.insert("try".into(), TokenType::Try);
         keywords.insert("var".into(), TokenType::Var);
         keywords.insert("while".into(), TokenType::While);
         keywords.insert("eof".into(), TokenType::Eof);
@@ -74,6 +85,7 @@ impl Scanner {
         Self {
             tokens: vec![],
             keywords,
+            interpolations: vec![],
         }
     }
 
@@ -90,15 +102,36 @@ impl Scanner {
         // Loop over each grapheme and try to scan it into tokens
         while let Some(item) = input_iter.next() {
             let mut lexeme = String::from_str(item).expect("tried making a string");
+            // The iterator has already moved past the first grapheme of the token
             let start_line = input_iter.line();
-            let start_pos = input_iter.location();
+            let start_pos = input_iter.location() - 1;
+            let mut value = None; // of a string
 
             let token = match item {
                 // Single character tokens
                 "(" => Some(TokenType::LeftParen),
                 ")" => Some(TokenType::RightParen),
-                "{" => Some(TokenType::LeftBrace),
-                "}" => Some(TokenType::RightBrace),
+                "{" => {
+                    if let Some(depth) = self.interpolations.last_mut() {
+                        *depth += 1;
+                    }
+                    Some(TokenType::LeftBrace)
+                }
+                "}" => match self.interpolations.last_mut() {
+                    Some(0) => {
+                        // The rest of the string an interpolated expression is in
+                        self.interpolations.pop();
+                        Some(self.string((&mut input_iter, &mut lexeme), &mut value)?)
+                    }
+                    Some(depth) => {
+                        *depth -= 1;
+                        Some(TokenType::RightBrace)
+                    }
+                    None => Some(TokenType::RightBrace),
+                },
+                "[" => Some(TokenType::LeftBracket),
+                "]" => Some(TokenType::RightBracket),
+                ":" => Some(TokenType::Colon),
                 "," => Some(TokenType::Comma),
                 "." => Some(TokenType::Dot),
                 "-" => Some(TokenType::Minus),
@@ -165,10 +198,7 @@ impl Scanner {
                     }
                 }
                 // Strings
-                "\"" => {
-                    string((&mut input_iter, &mut lexeme))?;
-                    Some(TokenType::String)
-                }
+                "\"" => Some(self.string((&mut input_iter, &mut lexeme), &mut value)?),
                 // Numbers
                 ch if is_digit(ch) => {
                     number((&mut input_iter, &mut lexeme))?;
@@ -185,9 +215,14 @@ impl Scanner {
             };
 
             // Add scanned token to list of tokens
-            match token {
-                None => (),
-                Some(token_type) => {
+            match (token, value) {
+                (None, _) => (),
+                (Some(token_type), Some(value)) => {
+                    self.tokens.push(Token::string(
+                        token_type, lexeme, value, start_line, start_pos,
+                    ));
+                }
+                (Some(token_type), None) => {
                     self.tokens
                         .push(Token::new(token_type, lexeme, start_line, start_pos));
                 }
@@ -195,12 +230,55 @@ impl Scanner {
         }
 
         // Add an EOF token at end of input
-        self.tokens
-            .push(Token::new(TokenType::Eof, "".into(), input_iter.line(), 0));
+        self.tokens.push(Token::new(
+            TokenType::Eof,
+            "".into(),
+            input_iter.line(),
+            input_iter.location(),
+        ));
 
         Ok(())
     }
 
+    // Scan the rest of a string, after its opening quote or the `}` closing an
+    // interpolated expression. Strings can span lines. A string is cut short
+    // by `${`, which starts an expression to interpolate into it.
+    fn string(
+        &mut self,
+        (iter, lexeme): (&mut InputIter, &mut String),
+        value: &mut Option<String>,
+    ) -> Result<TokenType> {
+        let mut text = String::new();
+        let token_type = loop {
+            let (line, position) = (iter.line(), iter.location());
+            let grapheme = match iter.next() {
+                Some(grapheme) => grapheme,
+                None => {
+                    return Err(RloxError::Scan(ScanError::new(
+                        line,
+                        position,
+                        "",
+                        "Unterminated string",
+                    )))
+                }
+            };
+            lexeme.push_str(grapheme);
+
+            match grapheme {
+                "\"" => break TokenType::String,
+                "$" if match_next_char((iter, lexeme), "{") => {
+                    self.interpolations.push(0);
+                    break TokenType::Interpolation;
+                }
+                "\\" => text.push(escape((iter, lexeme), line, position)?),
+                _ => text.push_str(grapheme),
+            }
+        };
+
+        *value = Some(text);
+        Ok(token_type)
+    }
+
     pub fn into_parser(self) -> Parser {
         Parser::new(self.tokens)
     }
@@ -315,22 +393,62 @@ fn match_next_char((iter, lexeme): (&mut InputIter, &mut String), ch: &str) -> b
     }
 }
 
-fn string((iter, lexeme): (&mut InputIter, &mut String)) -> Result<()> {
-    // Scan till we find another double quote.
-    // Lox doesn't support escaped quotes, so this is simple.
-    scan_forward_until((iter, lexeme), "\"");
+// The character an escape sequence stands for, scanned after its backslash:
+// \n \t \r \0 \\ \" \$ or \u{...} with up to six hex digits
+fn escape(
+    (iter, lexeme): (&mut InputIter, &mut String),
+    line: usize,
+    position: usize,
+) -> Result<char> {
+    let start = lexeme.len() - 1;
+    let ch = match iter.next() {
+        Some(grapheme) => {
+            lexeme.push_str(grapheme);
+            match grapheme {
+                "n" => Some('\n'),
+                "t" => Some('\t'),
+                "r" => Some('\r'),
+                "0" => Some('\0'),
+                "\\" => Some('\\'),
+                "\"" => Some('"'),
+                "$" => Some('$'),
+                "u" => unicode_escape((iter, lexeme)),
+                _ => None,
+            }
+        }
+        None => None,
+    };
 
-    // Got to end of file without a terminating string
-    if iter.peek().is_none() {
-        return Err(RloxError::Scan(ScanError::new(
-            iter.line(),
-            iter.location(),
-            "",
-            "Unterminated string",
-        )));
+    ch.ok_or_else(|| {
+        RloxError::Scan(ScanError::new(
+            line,
+            position,
+            &lexeme[start..],
+            "Invalid escape sequence",
+        ))
+    })
+}
+
+// The digits and closing brace of a \u{...} escape, if they're a valid character
+fn unicode_escape((iter, lexeme): (&mut InputIter, &mut String)) -> Option<char> {
+    if !match_next_char((iter, lexeme), "{") {
+        return None;
     }
 
-    Ok(())
+    let mut digits = String::new();
+    while let Some(digit) = iter
+        .peek()
+        .filter(|g| g.len() == 1 && g.as_bytes()[0].is_ascii_hexdigit())
+    {
+        iter.next();
+        lexeme.push_str(digit);
+        digits.push_str(digit);
+    }
+
+    if !match_next_char((iter, lexeme), "}") || digits.is_empty() || digits.len() > 6 {
+        return None;
+    }
+    char::from_u32(u32::from_str_radix(&digits, 16).ok()?)
 }
 
 fn number((iter, lexeme): (&mut InputIter, &mut String)) -> Result<()> {
@@ -400,6 +518,80 @@ mod tests {
         }
     }
 
+    fn literals(source: &str) -> Result<Vec<(TokenType, TokenLiteral)>> {
+        let mut scanner = Scanner::new();
+        scanner.scan_tokens(source)?;
+        Ok(scanner
+            .tokens
+            .into_iter()
+            .map(|token| (token.token_type(), token.token_literal().clone()))
+            .collect())
+    }
+
+    #[test]
+    fn string_escapes() {
+        let str = |text: &str| (TokenType::String, TokenLiteral::Str(text.to_string()));
+        let eof = (TokenType::Eof, TokenLiteral::None);
+
+        assert_eq!(
+            literals(r#""a\tb\n\"q\"\\\$\0""#).unwrap(),
+            vec![str("a\tb\n\"q\"\\$\0"), eof.clone()]
+        );
+        assert_eq!(
+            literals(r#""\u{1F600}\u{e9}""#).unwrap(),
+            vec![str("😀é"), eof.clone()]
+        );
+        assert_eq!(
+            literals("\"two\nlines\"").unwrap(),
+            vec![str("two\nlines"), eof]
+        );
+
+        for source in [
+            r#""\q""#,
+            r#""\u{}""#,
+            r#""\u{110000}""#,
+            r#""\u1F600""#,
+            "\"open",
+        ] {
+            assert!(
+                matches!(literals(source), Err(RloxError::Scan(_))),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn interpolation_tokens() {
+        let mut scanner = Scanner::new();
+        scanner
+            .scan_tokens(r#""a ${ {"k": x}["k"] } b ${y}""#)
+            .unwrap();
+        let types: Vec<TokenType> = scanner.tokens.iter().map(Token::token_type).collect();
+        assert_eq!(
+            types,
+            [
+                TokenType::Interpolation,
+                TokenType::LeftBrace,
+                TokenType::String,
+                TokenType::Colon,
+                TokenType::Identifier,
+                TokenType::RightBrace,
+                TokenType::LeftBracket,
+                TokenType::String,
+                TokenType::RightBracket,
+                TokenType::Interpolation,
+                TokenType::Identifier,
+                TokenType::String,
+                TokenType::Eof,
+            ]
+        );
+        assert_eq!(
+            scanner.tokens[9].token_literal(),
+            &TokenLiteral::Str(" b ".to_string())
+        );
+        assert_eq!(scanner.tokens[9].span(), Span::new(1, 21, 6));
+    }
+
     #[test]
     fn test_scanner() {
         let scanner = setup_scanner1();
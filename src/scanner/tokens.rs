@@ -16,6 +16,8 @@
 
 use std::fmt::Display;
 
+use unicode_segmentation::UnicodeSegmentation;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TokenType {
     // Single-character tokens.
@@ -23,6 +25,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -40,23 +45,31 @@ pub enum TokenType {
     Less,
     LessEqual,
     // Literals.
-    Identifier, // var X;
-    String,     // "string"
-    Number,     // 0.123
+    Identifier,    // var X;
+    String,        // "string"
+    Interpolation, // "string ${, the part of a string before an expression
+    Number,        // 0.123
     // Keywords.
     And,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     Fun, // function
     For,
     If,
+    In,
     Nil,
     Or,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
     Eof,
@@ -81,6 +94,36 @@ impl Display for TokenLiteral {
     }
 }
 
+// A location in the source: line and column are 1-based, and the column and
+// length count graphemes, like the scanner does.
+// A column of 0 means only the line is known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Self {
+        Self { line, column, len }
+    }
+
+    // A location where only the line is known, e.g. from a chunk's line table
+    pub fn line(line: usize) -> Self {
+        Self::new(line, 0, 0)
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.column {
+            0 => write!(f, "{}", self.line),
+            column => write!(f, "{}:{}", self.line, column),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     token_type: TokenType,
@@ -88,15 +131,12 @@ pub struct Token {
     literal: TokenLiteral, // parsed value of lexeme
     line: usize,           // line number where token was found
     position: usize,       // character position within line
+    len: usize,            // length in graphemes, counted once for every span
 }
 
 impl Token {
     pub fn new(token_type: TokenType, lexeme: String, line: usize, position: usize) -> Token {
         let tl = match token_type {
-            TokenType::String => {
-                let string_literal = lexeme.trim_matches('"').into();
-                TokenLiteral::Str(string_literal)
-            }
             TokenType::Number => {
                 // TODO fix the unwrap() here. Add new error type for token generation failures
                 let number = lexeme.parse::<f64>().unwrap();
@@ -111,6 +151,7 @@ impl Token {
 
         Token {
             token_type,
+            len: lexeme.graphemes(true).count(),
             lexeme,
             literal: tl,
             line,
@@ -118,6 +159,25 @@ impl Token {
         }
     }
 
+    // Strings and their parts keep their source in the lexeme,
+    // and the text with escape sequences replaced in the literal
+    pub fn string(
+        token_type: TokenType,
+        lexeme: String,
+        value: String,
+        line: usize,
+        position: usize,
+    ) -> Token {
+        Token {
+            token_type,
+            len: lexeme.graphemes(true).count(),
+            lexeme,
+            literal: TokenLiteral::Str(value),
+            line,
+            position,
+        }
+    }
+
     pub fn token_type(&self) -> TokenType {
         self.token_type
     }
@@ -129,6 +189,14 @@ impl Token {
     pub fn lexeme(&self) -> &str {
         &self.lexeme
     }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> Span {
+        Span::new(self.line, self.position, self.len)
+    }
 }
 
 impl Display for Token {
@@ -0,0 +1,124 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Text handling behind the built-in string methods of both interpreters.
+//
+// Strings are measured and indexed in grapheme clusters, the same units the
+// scanner reads source code in and for-in loops step through, so an emoji or
+// an accented letter counts as one. Each interpreter checks the arguments
+// and wraps the results in its own values.
+
+use crate::scanner::graphemes;
+
+// The longest string `repeat` will build, in bytes
+pub const MAX_REPEAT_LEN: usize = 1 << 30;
+
+pub fn length(text: &str) -> usize {
+    graphemes(text).count()
+}
+
+// The graphemes from `start` up to, but not including, `end`
+pub fn substring(text: &str, start: usize, end: usize) -> &str {
+    if start >= end {
+        return "";
+    }
+    let offset = |position| byte_offset(text, position);
+    &text[offset(start)..offset(end)]
+}
+
+// Position of the first grapheme where `needle` starts
+pub fn index_of(text: &str, needle: &str) -> Option<usize> {
+    let offset = text.find(needle)?;
+    Some(length(&text[..offset]))
+}
+
+// Splitting on an empty separator gives each grapheme
+pub fn split<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    match separator {
+        "" => graphemes(text).collect(),
+        _ => text.split(separator).collect(),
+    }
+}
+
+// Numbers are written the way Lox source writes them, with an optional sign
+// and surrounding whitespace
+pub fn parse_number(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let (whole, fraction) = match digits.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (digits, None),
+    };
+
+    let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    match is_digits(whole) && fraction.map_or(true, is_digits) {
+        true => text.parse().ok(),
+        false => None,
+    }
+}
+
+// `count` copies of `text`, or None when that would be more than
+// MAX_REPEAT_LEN bytes, which is almost certainly a mistake
+pub fn repeat(text: &str, count: usize) -> Option<String> {
+    match text.len().checked_mul(count) {
+        Some(len) if len <= MAX_REPEAT_LEN => Some(text.repeat(count)),
+        _ => None,
+    }
+}
+
+fn byte_offset(text: &str, position: usize) -> usize {
+    graphemes(text)
+        .take(position)
+        .map(|grapheme| grapheme.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn graphemes_count_as_one() {
+        let text = "he\u{301}llo 👋🏽!";
+        assert_eq!(length(text), 8);
+        assert_eq!(substring(text, 1, 5), "e\u{301}llo");
+        assert_eq!(substring(text, 6, 7), "👋🏽");
+        assert_eq!(substring(text, 3, 3), "");
+        assert_eq!(index_of(text, "!"), Some(7));
+        assert_eq!(index_of(text, "x"), None);
+        assert_eq!(split("a👋🏽b", ""), ["a", "👋🏽", "b"]);
+        assert_eq!(split("a, b, c", ", "), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn repeat_is_bounded() {
+        assert_eq!(repeat("ab", 3).as_deref(), Some("ababab"));
+        assert_eq!(repeat("", usize::MAX).as_deref(), Some(""));
+        assert_eq!(repeat("ab", MAX_REPEAT_LEN / 2 + 1), None);
+        assert_eq!(repeat("ab", usize::MAX), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("42"), Some(42.0));
+        assert_eq!(parse_number(" -3.5\n"), Some(-3.5));
+        for text in [
+            "", "-", "1.", ".5", "1e3", "inf", "NaN", "+1", "1 2", "0x10",
+        ] {
+            assert_eq!(parse_number(text), None, "{text}");
+        }
+    }
+}
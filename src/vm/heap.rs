@@ -0,0 +1,679 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Objects owned by the VM.
+//
+// Every string, function, closure, class, instance, list and map lives in the heap and is
+// referred to by an `ObjRef` handle. Strings are interned, so two equal strings always
+// share a handle and can be compared by reference.
+//
+// The heap is garbage collected with a tri-color mark-and-sweep collector.
+// The VM marks its roots, then `trace` drains the gray worklist, blackening
+// each object by marking everything it refers to. `sweep` frees whatever is
+// left unmarked and puts its slot on the free list for reuse.
+//
+// The heap only tracks how much it has allocated, it never decides to collect
+// by itself. The VM checks `should_collect` before allocating, because only the
+// VM knows its roots.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::mem;
+use std::rc::Rc;
+
+use super::methods::Method;
+use super::value::Value;
+use super::Vm;
+use crate::chunk::Function;
+use crate::errors::{Result, RloxError, RuntimeError};
+use crate::ordmap::OrderedMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value>;
+
+// A function loaded into the VM.
+// Constants are converted into values once, when the function is loaded.
+pub struct ObjFunction {
+    pub proto: Rc<Function>,
+    pub constants: Rc<[Value]>,
+}
+
+// A function together with the variables it captured.
+// Every function the VM calls is wrapped in a closure, even if it captures nothing.
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Rc<[ObjRef]>,
+}
+
+// A captured variable. While the variable is still in scope the upvalue points
+// at its stack slot, once the scope ends the value is moved into the upvalue.
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct ObjNative {
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+// A method accessed from an instance, remembering its receiver.
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// A built-in method accessed from a value, such as a list's `push`
+pub struct ObjNativeMethod {
+    pub receiver: Value,
+    pub arity: u8,
+    pub function: Method,
+}
+
+// The values that can be used as keys of a map.
+// Numbers are keyed by their bits, with -0 folded into 0 since they're equal,
+// and strings by their handle since they're interned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(ObjRef),
+}
+
+impl Key {
+    pub fn new(heap: &Heap, value: Value) -> Result<Self> {
+        match value {
+            Value::Nil => Ok(Key::Nil),
+            Value::Bool(v) => Ok(Key::Bool(v)),
+            Value::Number(0.0) => Ok(Key::Number(0f64.to_bits())),
+            Value::Number(n) => Ok(Key::Number(n.to_bits())),
+            Value::Obj(obj) if matches!(heap.get(obj), Obj::String(_)) => Ok(Key::String(obj)),
+            other => Err(RloxError::Interpret(
+                RuntimeError::UnhashableKey(heap.display(other).to_string()),
+                None,
+                Vec::new(),
+            )),
+        }
+    }
+
+    pub fn value(&self) -> Value {
+        match self {
+            Key::Nil => Value::Nil,
+            Key::Bool(v) => Value::Bool(*v),
+            Key::Number(bits) => Value::Number(f64::from_bits(*bits)),
+            Key::String(obj) => Value::Obj(*obj),
+        }
+    }
+}
+
+// Steps through a list's elements, a map's keys or a range of numbers,
+// returning nil once it runs out
+pub enum ObjIterator {
+    Elements { source: Value, position: usize },
+    Range { next: f64, end: f64 },
+}
+
+pub enum Obj {
+    String(Rc<str>),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Native(ObjNative),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    List(Vec<Value>),
+    Map(OrderedMap<Key, Value>),
+    NativeMethod(ObjNativeMethod),
+    Iterator(ObjIterator),
+}
+
+// Tunables for the garbage collector
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    // Bytes allocated before the first collection
+    pub initial_threshold: usize,
+    // After a collection, the next one happens once the heap grows by this factor
+    pub growth_factor: f64,
+    // Collect before every allocation, to shake out missing roots
+    pub stress: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            initial_threshold: 1024 * 1024,
+            growth_factor: 2.0,
+            stress: false,
+        }
+    }
+}
+
+struct Entry {
+    obj: Obj,
+    marked: bool,
+    size: usize, // bytes accounted for this object when it was allocated
+}
+
+#[derive(Default)]
+pub struct Heap {
+    objects: Vec<Option<Entry>>,
+    free: Vec<usize>, // empty slots in `objects`
+    strings: HashMap<Rc<str>, ObjRef>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    config: GcConfig,
+}
+
+impl Heap {
+    pub fn with_config(config: GcConfig) -> Self {
+        Self {
+            next_gc: config.initial_threshold,
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = size_of_obj(&obj);
+        self.bytes_allocated += size;
+
+        let entry = Some(Entry {
+            obj,
+            marked: false,
+            size,
+        });
+
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = entry;
+                ObjRef(index)
+            }
+            None => {
+                self.objects.push(entry);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    // Returns the existing handle if the string has been seen before.
+    pub fn intern(&mut self, string: &str) -> ObjRef {
+        if let Some(obj) = self.find_string(string) {
+            return obj;
+        }
+
+        let string: Rc<str> = Rc::from(string);
+        let obj = self.alloc(Obj::String(Rc::clone(&string)));
+        self.strings.insert(string, obj);
+        obj
+    }
+
+    pub fn find_string(&self, string: &str) -> Option<ObjRef> {
+        self.strings.get(string).copied()
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        match &self.objects[obj.0] {
+            Some(entry) => &entry.obj,
+            None => unreachable!("object was used after being freed"),
+        }
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        match &mut self.objects[obj.0] {
+            Some(entry) => &mut entry.obj,
+            None => unreachable!("object was used after being freed"),
+        }
+    }
+
+    // Number of live objects
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    //
+    // Garbage collection
+    //
+
+    pub fn should_collect(&self) -> bool {
+        self.config.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    // Marked objects are gray until `trace` visits what they refer to
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        let entry = match &mut self.objects[obj.0] {
+            Some(entry) => entry,
+            None => unreachable!("freed object is still reachable"),
+        };
+
+        if entry.marked {
+            return;
+        }
+        entry.marked = true;
+        self.gray.push(obj);
+    }
+
+    // Blacken gray objects until everything reachable is marked
+    pub fn trace(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            self.blacken(obj);
+        }
+    }
+
+    fn blacken(&mut self, obj: ObjRef) {
+        // Collect the references first, marking needs the heap mutably
+        let mut values: Vec<Value> = vec![];
+        let mut objects: Vec<ObjRef> = vec![];
+
+        match self.get(obj) {
+            Obj::String(_) | Obj::Native(_) => (),
+            Obj::Function(function) => values.extend(function.constants.iter()),
+            Obj::Closure(closure) => {
+                objects.push(closure.function);
+                objects.extend(closure.upvalues.iter());
+            }
+            // Open upvalues point into the stack, which is already a root
+            Obj::Upvalue(ObjUpvalue::Open(_)) => (),
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => values.push(*value),
+            Obj::Class(class) => {
+                objects.push(class.name);
+                for (name, method) in &class.methods {
+                    objects.push(*name);
+                    objects.push(*method);
+                }
+            }
+            Obj::Instance(instance) => {
+                objects.push(instance.class);
+                for (name, value) in &instance.fields {
+                    objects.push(*name);
+                    values.push(*value);
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                values.push(bound.receiver);
+                objects.push(bound.method);
+            }
+            Obj::List(items) => values.extend(items.iter()),
+            Obj::Map(map) => {
+                for (key, value) in map.iter() {
+                    values.push(key.value());
+                    values.push(*value);
+                }
+            }
+            Obj::NativeMethod(method) => values.push(method.receiver),
+            Obj::Iterator(ObjIterator::Elements { source, .. }) => values.push(*source),
+            Obj::Iterator(ObjIterator::Range { .. }) => (),
+        }
+
+        for value in values {
+            self.mark_value(value);
+        }
+        for obj in objects {
+            self.mark_object(obj);
+        }
+    }
+
+    // Free every unmarked object, and reset the marks for the next collection.
+    // Returns the number of bytes freed.
+    pub fn sweep(&mut self) -> usize {
+        let before = self.bytes_allocated;
+
+        // The string table holds weak references, drop the ones about to be freed
+        let objects = &self.objects;
+        self.strings
+            .retain(|_, obj| matches!(&objects[obj.0], Some(entry) if entry.marked));
+
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => entry.marked = false,
+                Some(entry) => {
+                    self.bytes_allocated -= entry.size;
+                    *slot = None;
+                    self.free.push(index);
+                }
+                None => (),
+            }
+        }
+
+        self.next_gc = ((self.bytes_allocated as f64 * self.config.growth_factor) as usize)
+            .max(self.config.initial_threshold);
+
+        before - self.bytes_allocated
+    }
+
+    // Convenience accessors for when the compiler guarantees the type.
+
+    pub fn string(&self, obj: ObjRef) -> &str {
+        match self.get(obj) {
+            Obj::String(s) => s,
+            _ => unreachable!("expected a string object"),
+        }
+    }
+
+    pub fn function(&self, obj: ObjRef) -> &ObjFunction {
+        match self.get(obj) {
+            Obj::Function(function) => function,
+            _ => unreachable!("expected a function object"),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &ObjClosure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            _ => unreachable!("expected a closure object"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, obj: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => unreachable!("expected an upvalue object"),
+        }
+    }
+
+    pub fn class(&self, obj: ObjRef) -> &ObjClass {
+        match self.get(obj) {
+            Obj::Class(class) => class,
+            _ => unreachable!("expected a class object"),
+        }
+    }
+
+    pub fn class_mut(&mut self, obj: ObjRef) -> &mut ObjClass {
+        match self.get_mut(obj) {
+            Obj::Class(class) => class,
+            _ => unreachable!("expected a class object"),
+        }
+    }
+
+    pub fn list(&self, obj: ObjRef) -> &Vec<Value> {
+        match self.get(obj) {
+            Obj::List(items) => items,
+            _ => unreachable!("expected a list object"),
+        }
+    }
+
+    pub fn list_mut(&mut self, obj: ObjRef) -> &mut Vec<Value> {
+        match self.get_mut(obj) {
+            Obj::List(items) => items,
+            _ => unreachable!("expected a list object"),
+        }
+    }
+
+    pub fn map(&self, obj: ObjRef) -> &OrderedMap<Key, Value> {
+        match self.get(obj) {
+            Obj::Map(map) => map,
+            _ => unreachable!("expected a map object"),
+        }
+    }
+
+    pub fn map_mut(&mut self, obj: ObjRef) -> &mut OrderedMap<Key, Value> {
+        match self.get_mut(obj) {
+            Obj::Map(map) => map,
+            _ => unreachable!("expected a map object"),
+        }
+    }
+
+    // Display a value the same way jlox does
+    pub fn display(&self, value: Value) -> ValueDisplay<'_> {
+        ValueDisplay {
+            heap: self,
+            value,
+            element: false,
+        }
+    }
+
+    // Display a value the way it's written inside a list or map
+    pub fn display_element(&self, value: Value) -> ValueDisplay<'_> {
+        ValueDisplay {
+            heap: self,
+            value,
+            element: true,
+        }
+    }
+}
+
+// Lists and maps nested deeper than this are printed as [...] or {...},
+// rather than recursing until the native stack runs out
+const DISPLAY_DEPTH: usize = 100;
+
+pub struct ValueDisplay<'a> {
+    heap: &'a Heap,
+    value: Value,
+    element: bool,
+}
+
+impl Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.element {
+            true => self.write_element(f, self.value, &mut Vec::new()),
+            false => self.write(f, self.value, &mut Vec::new()),
+        }
+    }
+}
+
+impl ValueDisplay<'_> {
+    // Inside a list or map strings are quoted
+    fn write_element(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        value: Value,
+        seen: &mut Vec<ObjRef>,
+    ) -> fmt::Result {
+        match value {
+            Value::Obj(obj) if matches!(self.heap.get(obj), Obj::String(_)) => {
+                write!(f, "\"{}\"", self.heap.string(obj))
+            }
+            value => self.write(f, value, seen),
+        }
+    }
+
+    // `seen` holds the lists and maps being written,
+    // one that contains itself, or is nested too deep, is printed as [...] or {...}
+    fn write(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        value: Value,
+        seen: &mut Vec<ObjRef>,
+    ) -> fmt::Result {
+        let obj = match value {
+            Value::Nil => return write!(f, "nil"),
+            Value::Bool(v) => return write!(f, "{v}"),
+            Value::Number(n) => return write!(f, "{n}"),
+            Value::Obj(obj) => obj,
+        };
+
+        match self.heap.get(obj) {
+            Obj::String(s) => write!(f, "{s}"),
+            Obj::Function(function) => write_function(f, &function.proto),
+            Obj::Closure(closure) => write_function(f, &self.heap.function(closure.function).proto),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::Class(class) => write!(f, "<class {}>", self.heap.string(class.name)),
+            Obj::Instance(instance) => {
+                let class = self.heap.class(instance.class);
+                write!(f, "<{} instance>", self.heap.string(class.name))
+            }
+            Obj::BoundMethod(bound) => {
+                let closure = self.heap.closure(bound.method);
+                write_function(f, &self.heap.function(closure.function).proto)
+            }
+            Obj::NativeMethod(_) | Obj::Iterator(_) => write!(f, "<native fn>"),
+            Obj::List(_) if seen.contains(&obj) || seen.len() >= DISPLAY_DEPTH => {
+                write!(f, "[...]")
+            }
+            Obj::List(items) => {
+                seen.push(obj);
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.write_element(f, *item, seen)?;
+                }
+                seen.pop();
+                write!(f, "]")
+            }
+            Obj::Map(_) if seen.contains(&obj) || seen.len() >= DISPLAY_DEPTH => {
+                write!(f, "{{...}}")
+            }
+            Obj::Map(map) => {
+                seen.push(obj);
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.write_element(f, key.value(), seen)?;
+                    write!(f, ": ")?;
+                    self.write_element(f, *value, seen)?;
+                }
+                seen.pop();
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// An estimate of the memory owned by an object, used to schedule collections
+fn size_of_obj(obj: &Obj) -> usize {
+    let payload = match obj {
+        Obj::String(s) => s.len(),
+        Obj::Function(function) => {
+            function.constants.len() * mem::size_of::<Value>() + function.proto.chunk.code.len()
+        }
+        Obj::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+        Obj::Class(class) => class.methods.capacity() * mem::size_of::<(ObjRef, ObjRef)>(),
+        Obj::Instance(instance) => instance.fields.capacity() * mem::size_of::<(ObjRef, Value)>(),
+        Obj::List(items) => items.capacity() * mem::size_of::<Value>(),
+        Obj::Map(map) => map.len() * mem::size_of::<(Key, Value, Key, usize)>(),
+        Obj::Upvalue(_)
+        | Obj::Native(_)
+        | Obj::BoundMethod(_)
+        | Obj::NativeMethod(_)
+        | Obj::Iterator(_) => 0,
+    };
+
+    mem::size_of::<Entry>() + payload
+}
+
+fn write_function(f: &mut fmt::Formatter<'_>, function: &Function) -> fmt::Result {
+    if function.name.is_empty() {
+        write!(f, "<script>")
+    } else {
+        write!(f, "<fn {}#{}()>", function.name, function.arity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_interned() {
+        let mut heap = Heap::with_config(GcConfig::default());
+        let a = heap.intern("lox");
+        let b = heap.intern("lox");
+        let c = heap.intern("rlox");
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(heap.string(c), "rlox");
+    }
+
+    #[test]
+    fn sweeping_unreachable_objects() {
+        let mut heap = Heap::with_config(GcConfig::default());
+        let kept = heap.intern("kept");
+        let name = heap.intern("Point");
+        let class = heap.alloc(Obj::Class(ObjClass {
+            name,
+            methods: HashMap::new(),
+        }));
+        let garbage = heap.intern("garbage");
+        let allocated = heap.bytes_allocated();
+
+        // The class keeps its name alive
+        heap.mark_object(kept);
+        heap.mark_object(class);
+        heap.trace();
+        assert!(heap.sweep() > 0);
+
+        assert_eq!(heap.len(), 3);
+        assert!(heap.bytes_allocated() < allocated);
+        assert_eq!(heap.find_string("Point"), Some(name));
+        assert_eq!(heap.find_string("garbage"), None);
+
+        // Freed slots are reused
+        let reused = heap.intern("new");
+        assert_eq!(reused, garbage);
+        assert_eq!(heap.len(), 4);
+    }
+
+    #[test]
+    fn displaying_values() {
+        let mut heap = Heap::with_config(GcConfig::default());
+        let name = heap.intern("Point");
+        let class = heap.alloc(Obj::Class(ObjClass {
+            name,
+            methods: HashMap::new(),
+        }));
+        let instance = heap.alloc(Obj::Instance(ObjInstance {
+            class,
+            fields: HashMap::new(),
+        }));
+
+        assert_eq!(heap.display(Value::Number(2.5)).to_string(), "2.5");
+        assert_eq!(heap.display(Value::Nil).to_string(), "nil");
+        assert_eq!(heap.display(Value::Obj(name)).to_string(), "Point");
+        assert_eq!(heap.display(Value::Obj(class)).to_string(), "<class Point>");
+        assert_eq!(
+            heap.display(Value::Obj(instance)).to_string(),
+            "<Point instance>"
+        );
+
+        // Deeply nested lists are cut short
+        let mut list = heap.alloc(Obj::List(vec![]));
+        for _ in 0..100_000 {
+            list = heap.alloc(Obj::List(vec![Value::Obj(list)]));
+        }
+        let text = heap.display(Value::Obj(list)).to_string();
+        assert_eq!(text, format!("{}[...]{}", "[".repeat(100), "]".repeat(100)));
+    }
+}
@@ -0,0 +1,407 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Built-in methods of lists, maps and strings.
+//
+// Methods are looked up by name when they're accessed on a list, map or string.
+// Invoking one calls it directly, while accessing it creates a native method
+// object that remembers the receiver, so it can be called later.
+
+use std::rc::Rc;
+
+use super::heap::{Heap, Key, Obj, ObjIterator};
+use super::value::Value;
+use super::Vm;
+use crate::errors::{Result, RloxError, RuntimeError};
+use crate::scanner;
+use crate::strings;
+
+// Called with the receiver and the arguments, which are still on the stack
+pub type Method = fn(&mut Vm, Value, &[Value]) -> Result<Value>;
+
+// The arity and function of the list method called `name`
+pub fn list_method(name: &str) -> Option<(u8, Method)> {
+    let method: (u8, Method) = match name {
+        "length" => (0, length),
+        "push" => (1, push),
+        "pop" => (0, pop),
+        "insert" => (2, insert),
+        "remove" => (1, remove),
+        "slice" => (2, slice),
+        "iterator" => (0, iterator),
+        _ => return None,
+    };
+    Some(method)
+}
+
+// The arity and function of the map method called `name`
+pub fn map_method(name: &str) -> Option<(u8, Method)> {
+    let method: (u8, Method) = match name {
+        "length" => (0, map_length),
+        "has" => (1, has),
+        "remove" => (1, map_remove),
+        "keys" => (0, keys),
+        "values" => (0, values),
+        "entries" => (0, entries),
+        "iterator" => (0, iterator),
+        _ => return None,
+    };
+    Some(method)
+}
+
+// The arity and function of the string method called `name`
+pub fn string_method(name: &str) -> Option<(u8, Method)> {
+    let method: (u8, Method) = match name {
+        "length" => (0, string_length),
+        "substring" => (2, substring),
+        "index_of" => (1, index_of),
+        "split" => (1, split),
+        "join" => (1, join),
+        "replace" => (2, replace),
+        "trim" => (0, trim),
+        "upper" => (0, upper),
+        "lower" => (0, lower),
+        "starts_with" => (1, starts_with),
+        "ends_with" => (1, ends_with),
+        "repeat" => (1, repeat),
+        "to_number" => (0, to_number),
+        _ => return None,
+    };
+    Some(method)
+}
+
+// Indices are whole numbers below `limit`, which is the length for reading
+// an element and one past it for positions between elements
+pub fn to_index(heap: &Heap, value: Value, len: usize, limit: usize) -> Result<usize> {
+    match value {
+        Value::Number(n) if n.fract() == 0.0 => match n >= 0.0 && n < limit as f64 {
+            true => Ok(n as usize),
+            false => Err(error(RuntimeError::IndexOutOfRange(
+                heap.display(value).to_string(),
+                len,
+            ))),
+        },
+        other => Err(error(RuntimeError::InvalidIndex(
+            heap.display(other).to_string(),
+        ))),
+    }
+}
+
+pub fn undefined_key(heap: &Heap, key: Key) -> RloxError {
+    error(RuntimeError::UndefinedKey(
+        heap.display_element(key.value()).to_string(),
+    ))
+}
+
+// The next value of an iterator, or nil once there are none left
+pub fn next(vm: &mut Vm, iterator: Value) -> Value {
+    let (source, position) = match vm.heap.get_mut(vm.obj_ref(iterator)) {
+        Obj::Iterator(ObjIterator::Elements { source, position }) => {
+            *position += 1;
+            (*source, *position - 1)
+        }
+        Obj::Iterator(ObjIterator::Range { next, end }) => {
+            let current = *next;
+            *next += 1.0;
+            return match current < *end {
+                true => Value::Number(current),
+                false => Value::Nil,
+            };
+        }
+        _ => unreachable!("expected an iterator object"),
+    };
+
+    element(vm, source, position).map_or(Value::Nil, |(element, _)| element)
+}
+
+// The element of a list, key of a map or grapheme of a string at `position`,
+// and the position after it. Strings are stepped through by byte offset.
+// Nothing else has elements.
+pub fn element(vm: &mut Vm, source: Value, position: usize) -> Option<(Value, usize)> {
+    let source = match source {
+        Value::Obj(obj) => obj,
+        _ => return None,
+    };
+    let text = match vm.heap.get(source) {
+        Obj::List(items) => return items.get(position).map(|&item| (item, position + 1)),
+        Obj::Map(map) => {
+            return map
+                .get_index(position)
+                .map(|(key, _)| (key.value(), position + 1))
+        }
+        Obj::String(text) => Rc::clone(text),
+        _ => return None,
+    };
+
+    let grapheme = scanner::graphemes(text.get(position..)?).next()?;
+    let string = vm.intern(grapheme);
+    Some((Value::Obj(string), position + grapheme.len()))
+}
+
+//
+// Lists
+//
+
+fn length(vm: &mut Vm, list: Value, _: &[Value]) -> Result<Value> {
+    let list = vm.obj_ref(list);
+    Ok(Value::Number(vm.heap.list(list).len() as f64))
+}
+
+fn push(vm: &mut Vm, list: Value, arguments: &[Value]) -> Result<Value> {
+    let list = vm.obj_ref(list);
+    vm.heap.list_mut(list).push(arguments[0]);
+    Ok(Value::Nil)
+}
+
+fn pop(vm: &mut Vm, list: Value, _: &[Value]) -> Result<Value> {
+    let list = vm.obj_ref(list);
+    vm.heap
+        .list_mut(list)
+        .pop()
+        .ok_or_else(|| error(RuntimeError::EmptyList))
+}
+
+// Inserting at the length appends
+fn insert(vm: &mut Vm, list: Value, arguments: &[Value]) -> Result<Value> {
+    let list = vm.obj_ref(list);
+    let len = vm.heap.list(list).len();
+    let index = to_index(&vm.heap, arguments[0], len, len + 1)?;
+    vm.heap.list_mut(list).insert(index, arguments[1]);
+    Ok(Value::Nil)
+}
+
+fn remove(vm: &mut Vm, list: Value, arguments: &[Value]) -> Result<Value> {
+    let list = vm.obj_ref(list);
+    let len = vm.heap.list(list).len();
+    let index = to_index(&vm.heap, arguments[0], len, len)?;
+    Ok(vm.heap.list_mut(list).remove(index))
+}
+
+// A new list from `start` up to, but not including, `end`
+fn slice(vm: &mut Vm, list: Value, arguments: &[Value]) -> Result<Value> {
+    let list = vm.obj_ref(list);
+    let len = vm.heap.list(list).len();
+    let start = to_index(&vm.heap, arguments[0], len, len + 1)?;
+    let end = to_index(&vm.heap, arguments[1], len, len + 1)?;
+
+    // The elements are still reachable through the original list
+    let items = match start < end {
+        true => vm.heap.list(list)[start..end].to_vec(),
+        false => Vec::new(),
+    };
+    Ok(Value::Obj(vm.alloc(Obj::List(items))))
+}
+
+// Each call to the iterator returns the next element of a list, or key of a map
+fn iterator(vm: &mut Vm, source: Value, _: &[Value]) -> Result<Value> {
+    let iterator = vm.alloc(Obj::Iterator(ObjIterator::Elements {
+        source,
+        position: 0,
+    }));
+    Ok(Value::Obj(iterator))
+}
+
+//
+// Maps
+//
+
+fn map_length(vm: &mut Vm, map: Value, _: &[Value]) -> Result<Value> {
+    let map = vm.obj_ref(map);
+    Ok(Value::Number(vm.heap.map(map).len() as f64))
+}
+
+fn has(vm: &mut Vm, map: Value, arguments: &[Value]) -> Result<Value> {
+    let map = vm.obj_ref(map);
+    let key = Key::new(&vm.heap, arguments[0])?;
+    Ok(Value::Bool(vm.heap.map(map).contains_key(&key)))
+}
+
+fn map_remove(vm: &mut Vm, map: Value, arguments: &[Value]) -> Result<Value> {
+    let map = vm.obj_ref(map);
+    let key = Key::new(&vm.heap, arguments[0])?;
+    match vm.heap.map_mut(map).remove(&key) {
+        Some(value) => Ok(value),
+        None => Err(undefined_key(&vm.heap, key)),
+    }
+}
+
+// The keys and values are still reachable through the map
+fn keys(vm: &mut Vm, map: Value, _: &[Value]) -> Result<Value> {
+    let map = vm.obj_ref(map);
+    let keys = vm.heap.map(map).keys().map(Key::value).collect();
+    Ok(Value::Obj(vm.alloc(Obj::List(keys))))
+}
+
+fn values(vm: &mut Vm, map: Value, _: &[Value]) -> Result<Value> {
+    let map = vm.obj_ref(map);
+    let values = vm.heap.map(map).values().copied().collect();
+    Ok(Value::Obj(vm.alloc(Obj::List(values))))
+}
+
+// Each entry as a [key, value] list.
+// The pairs wait on the stack until the outer list owns them.
+fn entries(vm: &mut Vm, map: Value, _: &[Value]) -> Result<Value> {
+    let map = vm.obj_ref(map);
+    let base = vm.stack.len();
+
+    for position in 0..vm.heap.map(map).len() {
+        let (key, value) = match vm.heap.map(map).get_index(position) {
+            Some((key, value)) => (key.value(), *value),
+            None => unreachable!("position is below the map's length"),
+        };
+        let pair = vm.alloc(Obj::List(vec![key, value]));
+        vm.push(Value::Obj(pair));
+    }
+
+    let entries = vm.alloc(Obj::List(vm.stack[base..].to_vec()));
+    vm.stack.truncate(base);
+    Ok(Value::Obj(entries))
+}
+
+//
+// Strings
+//
+
+fn string_length(vm: &mut Vm, text: Value, _: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    Ok(Value::Number(strings::length(&text) as f64))
+}
+
+// The graphemes from `start` up to, but not including, `end`
+fn substring(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    let len = strings::length(&text);
+    let start = to_index(&vm.heap, arguments[0], len, len + 1)?;
+    let end = to_index(&vm.heap, arguments[1], len, len + 1)?;
+    Ok(new_string(vm, strings::substring(&text, start, end)))
+}
+
+fn index_of(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    let needle = string_argument(vm, arguments[0])?;
+    let index = strings::index_of(&text, &needle);
+    Ok(Value::Number(index.map_or(-1.0, |index| index as f64)))
+}
+
+// The parts wait on the stack until the list owns them
+fn split(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    let separator = string_argument(vm, arguments[0])?;
+    let base = vm.stack.len();
+
+    for part in strings::split(&text, &separator) {
+        let part = new_string(vm, part);
+        vm.push(part);
+    }
+
+    let parts = vm.alloc(Obj::List(vm.stack[base..].to_vec()));
+    vm.stack.truncate(base);
+    Ok(Value::Obj(parts))
+}
+
+// The elements of a list, turned into strings, with this one between them
+fn join(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    let items: Vec<String> = match arguments[0] {
+        Value::Obj(list) if matches!(vm.heap.get(list), Obj::List(_)) => vm
+            .heap
+            .list(list)
+            .iter()
+            .map(|&item| vm.heap.display(item).to_string())
+            .collect(),
+        other => return Err(invalid_argument(&vm.heap, "a list", other)),
+    };
+    Ok(new_string(vm, &items.join(&text)))
+}
+
+fn replace(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    let from = string_argument(vm, arguments[0])?;
+    let to = string_argument(vm, arguments[1])?;
+    Ok(new_string(vm, &text.replace(&*from, &to)))
+}
+
+fn trim(vm: &mut Vm, text: Value, _: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    Ok(new_string(vm, text.trim()))
+}
+
+fn upper(vm: &mut Vm, text: Value, _: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    Ok(new_string(vm, &text.to_uppercase()))
+}
+
+fn lower(vm: &mut Vm, text: Value, _: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    Ok(new_string(vm, &text.to_lowercase()))
+}
+
+fn starts_with(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    let prefix = string_argument(vm, arguments[0])?;
+    Ok(Value::Bool(text.starts_with(&*prefix)))
+}
+
+fn ends_with(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    let suffix = string_argument(vm, arguments[0])?;
+    Ok(Value::Bool(text.ends_with(&*suffix)))
+}
+
+fn repeat(vm: &mut Vm, text: Value, arguments: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    match arguments[0] {
+        Value::Number(n) if n.fract() == 0.0 && n >= 0.0 => {
+            match strings::repeat(&text, n as usize) {
+                Some(repeated) => Ok(new_string(vm, &repeated)),
+                None => Err(invalid_argument(&vm.heap, "a smaller count", arguments[0])),
+            }
+        }
+        other => Err(invalid_argument(&vm.heap, "a whole number count", other)),
+    }
+}
+
+// nil when the string isn't a number
+fn to_number(vm: &mut Vm, text: Value, _: &[Value]) -> Result<Value> {
+    let text = string_argument(vm, text)?;
+    Ok(strings::parse_number(&text).map_or(Value::Nil, Value::Number))
+}
+
+// The text of a string value, which stays usable while allocating
+fn string_argument(vm: &Vm, value: Value) -> Result<Rc<str>> {
+    match value {
+        Value::Obj(obj) => match vm.heap.get(obj) {
+            Obj::String(text) => Ok(Rc::clone(text)),
+            _ => Err(invalid_argument(&vm.heap, "a string", value)),
+        },
+        _ => Err(invalid_argument(&vm.heap, "a string", value)),
+    }
+}
+
+fn new_string(vm: &mut Vm, text: &str) -> Value {
+    Value::Obj(vm.intern(text))
+}
+
+fn invalid_argument(heap: &Heap, expected: &str, value: Value) -> RloxError {
+    error(RuntimeError::InvalidArgument(
+        expected.to_string(),
+        heap.display(value).to_string(),
+    ))
+}
+
+fn error(err: RuntimeError) -> RloxError {
+    RloxError::Interpret(err, None, Vec::new())
+}
@@ -0,0 +1,2016 @@
+// rlox: Lox interpreter/compiler in Rust.
+// Copyright (C) 2023  James Smyle <j@mes.sh>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// clox's stack-based virtual machine.
+//
+// The VM runs the bytecode produced by `compiler`. Each function call pushes a
+// CallFrame whose locals live in a window of the shared value stack, starting
+// with the callee (or the receiver, for methods) in slot 0.
+//
+// Functions are always called through a closure. A captured variable stays in
+// its stack slot while it's in scope, with an open upvalue pointing at it; when
+// the scope ends the value is moved into the upvalue, which closes it. Every
+// closure that captures the same variable shares the same upvalue.
+//
+// All allocation goes through `Vm::alloc` and `Vm::intern`, which may run the
+// garbage collector first. Any object that isn't reachable from a root when
+// allocating may be freed, so intermediate objects are kept on the stack
+// until they're stored somewhere reachable.
+//
+// Each try statement installs a handler, remembering how deep the call and
+// value stacks were. A runtime error or `throw` unwinds both stacks to the
+// innermost handler, which starts with the error value on top of the stack.
+
+mod heap;
+mod methods;
+mod value;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use crate::chunk::{Constant, Function, OpCode};
+use crate::errors::{BytecodeError, Frame, Result, RloxError, RuntimeError};
+use crate::ordmap::OrderedMap;
+use crate::scanner::Span;
+use heap::{
+    Heap, Key, NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance,
+    ObjIterator, ObjNative, ObjNativeMethod, ObjRef, ObjUpvalue,
+};
+use methods::Method;
+use value::Value;
+
+pub use heap::GcConfig;
+
+// Maximum call depth before we report a stack overflow
+const FRAMES_MAX: usize = 1024;
+
+struct CallFrame {
+    closure: ObjRef,
+    proto: Rc<Function>,
+    constants: Rc<[Value]>,
+    upvalues: Rc<[ObjRef]>,
+    ip: usize,
+    slots: usize, // stack index of the frame's slot 0
+}
+
+// Where to resume after an error inside a try statement
+struct Handler {
+    frames: usize, // calls active when the try started, the last one holds it
+    stack_len: usize,
+    ip: usize,
+}
+
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<ObjRef, Value>,
+    open_upvalues: Vec<ObjRef>, // upvalues still pointing into the stack
+    handlers: Vec<Handler>,
+    thrown: Option<Value>, // the value of the `throw` being unwound
+    caught: Option<(Value, RloxError)>, // the last error caught, to throw again
+    heap: Heap,
+    init_string: ObjRef,
+    iterator_string: ObjRef,
+    error_class: ObjRef, // runtime errors are caught as its instances
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_gc_config(GcConfig::default())
+    }
+
+    pub fn with_gc_config(config: GcConfig) -> Self {
+        let mut heap = Heap::with_config(config);
+        let init_string = heap.intern("init");
+        let iterator_string = heap.intern("iterator");
+        let error_name = heap.intern("Error");
+        let error_class = heap.alloc(Obj::Class(ObjClass {
+            name: error_name,
+            methods: HashMap::new(),
+        }));
+
+        let mut vm = Self {
+            stack: Vec::with_capacity(256),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            globals: HashMap::new(),
+            open_upvalues: vec![],
+            handlers: vec![],
+            thrown: None,
+            caught: None,
+            heap,
+            init_string,
+            iterator_string,
+            error_class,
+        };
+
+        vm.globals.insert(error_name, Value::Obj(error_class));
+
+        vm.define_native("clock", 0, clock);
+        vm.define_native("print", 1, print);
+        vm.define_native("range", 2, range);
+        vm.define_native("str", 1, str);
+        vm
+    }
+
+    // Run a compiled script.
+    // Globals are kept between calls, which lets the REPL build up state.
+    pub fn interpret(&mut self, script: Function) -> Result<()> {
+        let function = self.load_function(Rc::new(script));
+        self.push(Value::Obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Rc::new([]),
+        }));
+        self.pop();
+        self.push(Value::Obj(closure));
+        self.call_closure(closure, 0)?;
+
+        // Bytecode only knows lines, so runtime errors point at the whole line
+        let result = self.run().map_err(|err| {
+            let trace = self.backtrace();
+            match trace.first() {
+                Some(frame) => err.at(Span::line(frame.line)).with_trace(|_| trace),
+                None => err,
+            }
+        });
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+            self.handlers.clear();
+            self.thrown = None;
+            self.caught = None;
+        }
+        result
+    }
+
+    fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let ident = self.intern(name);
+        self.push(Value::Obj(ident));
+
+        let native = self.alloc(Obj::Native(ObjNative { arity, function }));
+        self.globals.insert(ident, Value::Obj(native));
+        self.pop();
+    }
+
+    // Move a function prototype, and every function nested in it, onto the heap
+    fn load_function(&mut self, proto: Rc<Function>) -> ObjRef {
+        // Loaded constants wait on the stack until the function owns them
+        let base = self.stack.len();
+        for constant in &proto.chunk.constants {
+            let value = match constant {
+                Constant::Number(n) => Value::Number(*n),
+                Constant::String(s) => Value::Obj(self.intern(s)),
+                Constant::Function(function) => Value::Obj(self.load_function(Rc::clone(function))),
+            };
+            self.push(value);
+        }
+
+        let constants: Rc<[Value]> = self.stack[base..].into();
+        let function = self.alloc(Obj::Function(ObjFunction { proto, constants }));
+        self.stack.truncate(base);
+        function
+    }
+
+    //
+    // Memory management
+    //
+
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    fn intern(&mut self, string: &str) -> ObjRef {
+        if let Some(obj) = self.heap.find_string(string) {
+            return obj;
+        }
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(string)
+    }
+
+    fn collect_garbage(&mut self) {
+        let before = self.heap.bytes_allocated();
+
+        self.mark_roots();
+        self.heap.trace();
+        let freed = self.heap.sweep();
+
+        log::debug!(
+            "gc: collected {} bytes (from {} to {}), {} objects live",
+            freed,
+            before,
+            self.heap.bytes_allocated(),
+            self.heap.len()
+        );
+    }
+
+    fn mark_roots(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+
+        // A bound method's closure is only referenced by its frame,
+        // since the receiver replaces it in slot 0.
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+
+        for (name, value) in &self.globals {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(*value);
+        }
+
+        self.heap.mark_object(self.init_string);
+        self.heap.mark_object(self.iterator_string);
+        self.heap.mark_object(self.error_class);
+        if let Some(thrown) = self.thrown {
+            self.heap.mark_value(thrown);
+        }
+        if let Some((caught, _)) = self.caught {
+            self.heap.mark_value(caught);
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        loop {
+            match self.dispatch() {
+                Err(err @ RloxError::Interpret(..)) if !self.handlers.is_empty() => self.catch(err),
+                result => return result,
+            }
+        }
+    }
+
+    // Unwind to the innermost handler, and give it the error as a value:
+    // whatever was thrown, or an Error describing a runtime error
+    fn catch(&mut self, err: RloxError) {
+        // Locate the error now, in case it's thrown again
+        let trace = self.backtrace();
+        let line = trace[0].line;
+        let err = err.at(Span::line(line)).with_trace(|_| trace);
+
+        let error = match self.thrown.take() {
+            Some(value) => value,
+            None => self.error_instance(&err, line),
+        };
+        self.caught = Some((error, err));
+
+        let handler = self.handlers.pop().unwrap();
+        self.frames.truncate(handler.frames);
+        self.close_upvalues(handler.stack_len);
+        self.stack.truncate(handler.stack_len);
+        self.push(error);
+        self.frame_mut().ip = handler.ip;
+    }
+
+    // An Error with the message, and the line it happened on
+    fn error_instance(&mut self, err: &RloxError, line: usize) -> Value {
+        let instance = self.alloc(Obj::Instance(ObjInstance {
+            class: self.error_class,
+            fields: HashMap::new(),
+        }));
+        self.push(Value::Obj(instance));
+        let message = self.intern(&err.to_string());
+        self.push(Value::Obj(message));
+
+        let fields = [
+            ("message", Value::Obj(message)),
+            ("line", Value::Number(line as f64)),
+        ];
+        for (name, value) in fields {
+            let name = self.intern(name);
+            if let Obj::Instance(instance) = self.heap.get_mut(instance) {
+                instance.fields.insert(name, value);
+            }
+        }
+
+        self.pop();
+        self.pop()
+    }
+
+    // Throwing the error that was caught again keeps where it came from
+    fn throw(&mut self, value: Value) -> RloxError {
+        self.thrown = Some(value);
+        match self.caught.take() {
+            Some((caught, err)) if caught == value => err,
+            caught => {
+                self.caught = caught;
+                let message = self.describe_thrown(value);
+                RloxError::Interpret(RuntimeError::Thrown(message), None, Vec::new())
+            }
+        }
+    }
+
+    // An uncaught throw reports the thrown error's message, or the value itself
+    fn describe_thrown(&self, value: Value) -> String {
+        if let Value::Obj(obj) = value {
+            let message = match (self.heap.get(obj), self.heap.find_string("message")) {
+                (Obj::Instance(instance), Some(name)) => instance.fields.get(&name),
+                _ => None,
+            };
+            if let Some(message) = message {
+                return self.heap.display(*message).to_string();
+            }
+        }
+        self.heap.display(value).to_string()
+    }
+
+    fn dispatch(&mut self) -> Result<()> {
+        loop {
+            let byte = self.read_byte();
+            let op = OpCode::from_byte(byte).expect("compiler only emits valid opcodes");
+
+            match op {
+                OpCode::Constant => {
+                    let constant = self.read_constant();
+                    self.push(constant);
+                }
+                OpCode::Nil => self.push(Value::Nil),
+                OpCode::True => self.push(Value::Bool(true)),
+                OpCode::False => self.push(Value::Bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frame().slots;
+                    self.push(self.stack[base + slot]);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frame().slots;
+                    self.stack[base + slot] = self.peek(0);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    match self.globals.get(&name) {
+                        Some(value) => self.push(*value),
+                        None => {
+                            return Err(RloxError::Interpret(
+                                RuntimeError::UndefinedVariable(self.heap.string(name).to_string()),
+                                None,
+                                Vec::new(),
+                            ))
+                        }
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    self.globals.insert(name, self.peek(0));
+                    self.pop();
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    if !self.globals.contains_key(&name) {
+                        return Err(RloxError::Interpret(
+                            RuntimeError::UndefinedVariableAssignment(
+                                self.heap.string(name).to_string(),
+                            ),
+                            None,
+                            Vec::new(),
+                        ));
+                    }
+                    // Assignment is an expression, leave the value on the stack
+                    self.globals.insert(name, self.peek(0));
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame().upvalues[index];
+                    let value = match self.heap.get(upvalue) {
+                        Obj::Upvalue(ObjUpvalue::Open(slot)) => self.stack.get(*slot).copied(),
+                        Obj::Upvalue(ObjUpvalue::Closed(value)) => Some(*value),
+                        _ => unreachable!("expected an upvalue object"),
+                    };
+                    self.push(value.ok_or_else(unexpected_value)?);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame().upvalues[index];
+                    let value = self.peek(0);
+                    match self.heap.upvalue_mut(upvalue) {
+                        ObjUpvalue::Open(slot) => match self.stack.get_mut(*slot) {
+                            Some(open) => *open = value,
+                            None => return Err(unexpected_value()),
+                        },
+                        ObjUpvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    if let Some((arity, function)) = self.native_method(self.peek(0), name)? {
+                        self.bind_native_method(arity, function);
+                        continue;
+                    }
+                    let instance = self.instance_ref(self.peek(0))?;
+
+                    let field = match self.heap.get(instance) {
+                        Obj::Instance(instance) => instance.fields.get(&name).copied(),
+                        _ => unreachable!(),
+                    };
+
+                    match field {
+                        Some(value) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => {
+                            let class = self.class_of(instance);
+                            self.bind_method(class, name)?;
+                        }
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let instance = self.instance_ref(self.peek(1))?;
+
+                    let value = self.pop();
+                    if let Obj::Instance(instance) = self.heap.get_mut(instance) {
+                        instance.fields.insert(name, value);
+                    }
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = self.pop();
+                    let superclass = self.class_ref(superclass)?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Equal => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    // Strings are interned, so every value compares by identity
+                    self.push(Value::Bool(left == right));
+                }
+                OpCode::Greater => self.compare(|a, b| a > b)?,
+                OpCode::Less => self.compare(|a, b| a < b)?,
+                OpCode::Add => self.add()?,
+                OpCode::Subtract => self.arithmetic(|a, b| a - b, "Cannot subtract types")?,
+                OpCode::Multiply => self.arithmetic(|a, b| a * b, "Cannot multiply types")?,
+                OpCode::Divide => self.arithmetic(|a, b| a / b, "Cannot divide types")?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    if let Value::Nil = value {
+                        return Err(unary_error("Bang"));
+                    }
+                    if self.is_string(value) {
+                        return Err(unary_error("Bang"));
+                    }
+                    self.push(Value::Bool(!value.is_truthy()));
+                }
+                OpCode::Negate => match self.pop() {
+                    Value::Number(n) => self.push(Value::Number(-n)),
+                    Value::Bool(v) => self.push(Value::Bool(!v)),
+                    _ => return Err(unary_error("Minus")),
+                },
+                OpCode::Jump => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16() as usize;
+                    if !self.peek(0).is_truthy() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u16() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call => {
+                    let argc = self.read_byte() as usize;
+                    self.call_value(self.peek(argc), argc)?;
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let argc = self.read_byte() as usize;
+                    self.invoke(name, argc)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let argc = self.read_byte() as usize;
+                    let superclass = self.pop();
+                    let superclass = self.class_ref(superclass)?;
+                    self.invoke_from_class(superclass, name, argc)?;
+                }
+                OpCode::Closure => {
+                    let function = self.read_constant();
+                    let function = self.obj_ref(function);
+                    let count = self.heap.function(function).proto.upvalue_count;
+
+                    let mut upvalues = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = match is_local {
+                            true => self.capture_upvalue(self.frame().slots + index),
+                            false => self.frame().upvalues[index],
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    // The upvalues are all reachable, either as open upvalues or
+                    // through the enclosing closure, while the closure is allocated.
+                    let closure = self.alloc(Obj::Closure(ObjClosure {
+                        function,
+                        upvalues: upvalues.into(),
+                    }));
+                    self.push(Value::Obj(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Stringify => {
+                    let value = self.peek(0);
+                    if !self.is_string(value) {
+                        let text = self.heap.display(value).to_string();
+                        let string = self.intern(&text);
+                        self.pop();
+                        self.push(Value::Obj(string));
+                    }
+                }
+                OpCode::PushHandler => {
+                    let offset = self.read_u16() as usize;
+                    let handler = Handler {
+                        frames: self.frames.len(),
+                        stack_len: self.stack.len(),
+                        ip: self.frame().ip + offset,
+                    };
+                    self.handlers.push(handler);
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    return Err(self.throw(value));
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+
+                    if self.frames.is_empty() {
+                        // Pop the script function itself
+                        self.pop();
+                        return Ok(());
+                    }
+
+                    self.stack.truncate(frame.slots);
+                    self.push(result);
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let class = self.alloc(Obj::Class(ObjClass {
+                        name,
+                        methods: HashMap::new(),
+                    }));
+                    self.push(Value::Obj(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Obj(obj) if matches!(self.heap.get(obj), Obj::Class(_)) => obj,
+                        other => {
+                            return Err(RloxError::Interpret(
+                                RuntimeError::SuperclassMustBeClass(
+                                    self.heap.display(other).to_string(),
+                                ),
+                                None,
+                                Vec::new(),
+                            ));
+                        }
+                    };
+                    let subclass = self.class_ref(self.peek(0))?;
+
+                    // Copy-down inheritance: methods are fixed once the class is declared.
+                    let methods = self.heap.class(superclass).methods.clone();
+                    self.heap.class_mut(subclass).methods.extend(methods);
+                    self.pop();
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    let method = match self.peek(0) {
+                        Value::Obj(obj) if matches!(self.heap.get(obj), Obj::Closure(_)) => obj,
+                        _ => return Err(unexpected_value()),
+                    };
+                    let class = self.class_ref(self.peek(1))?;
+
+                    self.heap.class_mut(class).methods.insert(name, method);
+                    self.pop();
+                }
+                OpCode::BuildList => {
+                    // The elements stay on the stack until the list owns them
+                    let count = self.read_u16() as usize;
+                    let start = self.stack.len() - count;
+                    let list = self.alloc(Obj::List(self.stack[start..].to_vec()));
+                    self.stack.truncate(start);
+                    self.push(Value::Obj(list));
+                }
+                OpCode::BuildMap => {
+                    // The entries stay on the stack, as key/value pairs, until the map owns them
+                    let count = self.read_u16() as usize;
+                    let start = self.stack.len() - 2 * count;
+
+                    let mut map = OrderedMap::new();
+                    for entry in self.stack[start..].chunks(2) {
+                        map.insert(Key::new(&self.heap, entry[0])?, entry[1]);
+                    }
+                    let map = self.alloc(Obj::Map(map));
+                    self.stack.truncate(start);
+                    self.push(Value::Obj(map));
+                }
+                OpCode::GetIndex => {
+                    let value = self.get_index(self.peek(1), self.peek(0))?;
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::SetIndex => {
+                    // Assignment is an expression, leave the value on the stack
+                    let value = self.peek(0);
+                    self.set_index(self.peek(2), self.peek(1), value)?;
+                    self.pop();
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::GetIterator => self.get_iterator()?,
+                OpCode::ForNext => {
+                    let offset = self.read_u16() as usize;
+                    self.for_next(offset)?;
+                }
+                OpCode::ForCheck => {
+                    // An iterator returning nil ends the loop
+                    let offset = self.read_u16() as usize;
+                    if let Value::Nil = self.peek(0) {
+                        self.pop();
+                        self.frame_mut().ip += offset;
+                    }
+                }
+            }
+        }
+    }
+
+    //
+    // Calling functions and methods
+    //
+
+    fn call_value(&mut self, callee: Value, argc: usize) -> Result<()> {
+        // Copy out what we need so the heap isn't borrowed during the call
+        enum Callee {
+            Closure,
+            Native(u8, NativeFn),
+            Class(Option<ObjRef>),
+            BoundMethod(Value, ObjRef),
+            NativeMethod(Value, u8, Method),
+            Iterator,
+        }
+
+        let obj = match callee {
+            Value::Obj(obj) => obj,
+            other => return Err(not_callable(&self.heap, other)),
+        };
+
+        let kind = match self.heap.get(obj) {
+            Obj::Closure(_) => Callee::Closure,
+            Obj::Native(native) => Callee::Native(native.arity, native.function),
+            Obj::Class(class) => Callee::Class(class.methods.get(&self.init_string).copied()),
+            Obj::BoundMethod(bound) => Callee::BoundMethod(bound.receiver, bound.method),
+            Obj::NativeMethod(method) => {
+                Callee::NativeMethod(method.receiver, method.arity, method.function)
+            }
+            Obj::Iterator(_) => Callee::Iterator,
+            _ => return Err(not_callable(&self.heap, callee)),
+        };
+
+        let callee_slot = self.stack.len() - argc - 1;
+        match kind {
+            Callee::Closure => self.call_closure(obj, argc),
+            Callee::Native(arity, function) => {
+                check_arity(arity, argc)?;
+
+                let arguments = self.stack[callee_slot + 1..].to_vec();
+                let result = function(self, &arguments)?;
+                self.stack.truncate(callee_slot);
+                self.push(result);
+                Ok(())
+            }
+            Callee::Class(init) => {
+                // The new instance replaces the class on the stack,
+                // and becomes `this` for the initializer.
+                let instance = self.alloc(Obj::Instance(ObjInstance {
+                    class: obj,
+                    fields: HashMap::new(),
+                }));
+                self.stack[callee_slot] = Value::Obj(instance);
+
+                match init {
+                    Some(init) => self.call_closure(init, argc),
+                    None => check_arity(0, argc),
+                }
+            }
+            Callee::BoundMethod(receiver, method) => {
+                self.stack[callee_slot] = receiver;
+                self.call_closure(method, argc)
+            }
+            Callee::NativeMethod(receiver, arity, function) => {
+                self.call_method(receiver, arity, function, argc)
+            }
+            Callee::Iterator => {
+                check_arity(0, argc)?;
+
+                let next = methods::next(self, callee);
+                self.stack.truncate(callee_slot);
+                self.push(next);
+                Ok(())
+            }
+        }
+    }
+
+    // Call a built-in method, the receiver stays reachable from the callee's slot
+    fn call_method(
+        &mut self,
+        receiver: Value,
+        arity: u8,
+        function: Method,
+        argc: usize,
+    ) -> Result<()> {
+        check_arity(arity, argc)?;
+
+        let callee_slot = self.stack.len() - argc - 1;
+        let arguments = self.stack[callee_slot + 1..].to_vec();
+        let result = function(self, receiver, &arguments)?;
+        self.stack.truncate(callee_slot);
+        self.push(result);
+        Ok(())
+    }
+
+    fn call_closure(&mut self, closure: ObjRef, argc: usize) -> Result<()> {
+        let (proto, constants, upvalues) = {
+            let closure = self.heap.closure(closure);
+            let function = self.heap.function(closure.function);
+            (
+                Rc::clone(&function.proto),
+                Rc::clone(&function.constants),
+                Rc::clone(&closure.upvalues),
+            )
+        };
+
+        check_arity(proto.arity, argc)?;
+
+        if self.frames.len() >= FRAMES_MAX {
+            return Err(RloxError::Interpret(
+                RuntimeError::StackOverflow,
+                None,
+                Vec::new(),
+            ));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            proto,
+            constants,
+            upvalues,
+            ip: 0,
+            slots: self.stack.len() - argc - 1,
+        });
+        Ok(())
+    }
+
+    // Call a method directly off of an instance, without creating a bound method.
+    fn invoke(&mut self, name: ObjRef, argc: usize) -> Result<()> {
+        let receiver = self.peek(argc);
+        if let Some((arity, function)) = self.native_method(receiver, name)? {
+            return self.call_method(receiver, arity, function, argc);
+        }
+
+        let instance = self.instance_ref(receiver)?;
+
+        let (class, field) = match self.heap.get(instance) {
+            Obj::Instance(instance) => (instance.class, instance.fields.get(&name).copied()),
+            _ => unreachable!(),
+        };
+
+        // Fields shadow methods, and may hold any callable value
+        if let Some(field) = field {
+            let callee_slot = self.stack.len() - argc - 1;
+            self.stack[callee_slot] = field;
+            return self.call_value(field, argc);
+        }
+
+        self.invoke_from_class(class, name, argc)
+    }
+
+    // Call a method of `class`, with the receiver already in the callee's slot
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, argc: usize) -> Result<()> {
+        match self.heap.class(class).methods.get(&name).copied() {
+            Some(method) => self.call_closure(method, argc),
+            None => Err(undefined_property(&self.heap, name)),
+        }
+    }
+
+    // Replace the receiver on top of the stack with one of `class`'s methods
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<()> {
+        let method = match self.heap.class(class).methods.get(&name).copied() {
+            Some(method) => method,
+            None => return Err(undefined_property(&self.heap, name)),
+        };
+
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod {
+            receiver: self.peek(0),
+            method,
+        }));
+        self.pop();
+        self.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    // Replace the list, map or string on top of the stack with one of its methods
+    fn bind_native_method(&mut self, arity: u8, function: Method) {
+        let method = self.alloc(Obj::NativeMethod(ObjNativeMethod {
+            receiver: self.peek(0),
+            arity,
+            function,
+        }));
+        self.pop();
+        self.push(Value::Obj(method));
+    }
+
+    // Lists, maps and strings have built-in methods, other values have none
+    fn native_method(&self, receiver: Value, name: ObjRef) -> Result<Option<(u8, Method)>> {
+        let lookup = match receiver {
+            Value::Obj(obj) => match self.heap.get(obj) {
+                Obj::List(_) => methods::list_method,
+                Obj::Map(_) => methods::map_method,
+                Obj::String(_) => methods::string_method,
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+
+        match lookup(self.heap.string(name)) {
+            Some(method) => Ok(Some(method)),
+            None => Err(undefined_property(&self.heap, name)),
+        }
+    }
+
+    //
+    // Iterating in for-in loops
+    //
+
+    // A for-in loop keeps two hidden locals: what it's iterating over, and its
+    // position. Lists, maps and strings are stepped through directly, with
+    // the position counting up from zero. Anything else that can be called is
+    // an iterator, called for each value, and has no position.
+    fn get_iterator(&mut self) -> Result<()> {
+        let iterable = self.peek(0);
+        let obj = match iterable {
+            Value::Obj(obj) => obj,
+            other => return Err(not_iterable(&self.heap, other)),
+        };
+
+        match self.heap.get(obj) {
+            Obj::List(_) | Obj::Map(_) | Obj::String(_) => self.push(Value::Number(0.0)),
+            Obj::Closure(_)
+            | Obj::Native(_)
+            | Obj::BoundMethod(_)
+            | Obj::NativeMethod(_)
+            | Obj::Iterator(_) => self.push(Value::Nil),
+            // Instances are iterated through what their `iterator` method returns.
+            // It replaces the instance, then this instruction runs again on it.
+            Obj::Instance(instance) => {
+                let name = self.iterator_string;
+                let has_iterator = instance.fields.contains_key(&name)
+                    || self.heap.class(instance.class).methods.contains_key(&name);
+                if !has_iterator {
+                    return Err(not_iterable(&self.heap, iterable));
+                }
+
+                self.frame_mut().ip -= 1;
+                self.invoke(name, 0)?;
+            }
+            _ => return Err(not_iterable(&self.heap, iterable)),
+        }
+        Ok(())
+    }
+
+    // Push the loop's next value, or jump `offset` forward when it's done.
+    // Iterators are called instead, and the ForCheck that follows looks at
+    // what they return. Lists, maps and strings skip over it, since their
+    // elements may be nil.
+    fn for_next(&mut self, offset: usize) -> Result<()> {
+        let (iterable, position) = (self.peek(1), self.peek(0));
+        let position = match position {
+            Value::Number(position) => position as usize,
+            _ => {
+                self.push(iterable);
+                return self.call_value(iterable, 0);
+            }
+        };
+
+        match methods::element(self, iterable, position) {
+            Some((element, next)) => {
+                let top = self.stack.len() - 1;
+                self.stack[top] = Value::Number(next as f64);
+                self.push(element);
+
+                // Skip the ForCheck and its operand
+                self.frame_mut().ip += 3;
+            }
+            None => self.frame_mut().ip += offset,
+        }
+        Ok(())
+    }
+
+    //
+    // Indexing lists and maps
+    //
+
+    fn get_index(&self, object: Value, index: Value) -> Result<Value> {
+        match object {
+            Value::Obj(obj) => match self.heap.get(obj) {
+                Obj::List(items) => {
+                    let index = methods::to_index(&self.heap, index, items.len(), items.len())?;
+                    Ok(items[index])
+                }
+                Obj::Map(map) => {
+                    let key = Key::new(&self.heap, index)?;
+                    map.get(&key)
+                        .copied()
+                        .ok_or_else(|| methods::undefined_key(&self.heap, key))
+                }
+                _ => Err(not_indexable(&self.heap, object)),
+            },
+            _ => Err(not_indexable(&self.heap, object)),
+        }
+    }
+
+    fn set_index(&mut self, object: Value, index: Value, value: Value) -> Result<()> {
+        let obj = match object {
+            Value::Obj(obj) => obj,
+            _ => return Err(not_indexable(&self.heap, object)),
+        };
+
+        match self.heap.get(obj) {
+            Obj::List(items) => {
+                let index = methods::to_index(&self.heap, index, items.len(), items.len())?;
+                self.heap.list_mut(obj)[index] = value;
+            }
+            Obj::Map(_) => {
+                let key = Key::new(&self.heap, index)?;
+                self.heap.map_mut(obj).insert(key, value);
+            }
+            _ => return Err(not_indexable(&self.heap, object)),
+        }
+        Ok(())
+    }
+
+    //
+    // Upvalues
+    //
+
+    // Closures capturing the same variable share its upvalue
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        for upvalue in &self.open_upvalues {
+            if let Obj::Upvalue(ObjUpvalue::Open(open)) = self.heap.get(*upvalue) {
+                if *open == slot {
+                    return *upvalue;
+                }
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    // Move every variable at or above `last` off of the stack into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        let heap = &mut self.heap;
+
+        self.open_upvalues.retain(|upvalue| {
+            let upvalue = heap.upvalue_mut(*upvalue);
+            match *upvalue {
+                // Only malformed bytecode pops a captured slot without closing it
+                ObjUpvalue::Open(slot) if slot >= last => {
+                    *upvalue = ObjUpvalue::Closed(stack.get(slot).copied().unwrap_or(Value::Nil));
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    //
+    // Operators
+    //
+
+    fn add(&mut self) -> Result<()> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.pop();
+                self.pop();
+                self.push(Value::Number(a + b));
+                Ok(())
+            }
+            (Value::Obj(a), Value::Obj(b))
+                if self.is_string(Value::Obj(a)) && self.is_string(Value::Obj(b)) =>
+            {
+                let string = [self.heap.string(a), self.heap.string(b)].concat();
+                let string = self.intern(&string);
+                self.pop();
+                self.pop();
+                self.push(Value::Obj(string));
+                Ok(())
+            }
+            _ => Err(RloxError::Interpret(
+                RuntimeError::Concatenation("Cannot concatenate types".into()),
+                None,
+                Vec::new(),
+            )),
+        }
+    }
+
+    fn arithmetic(&mut self, op: fn(f64, f64) -> f64, message: &str) -> Result<()> {
+        match (self.pop(), self.pop()) {
+            (Value::Number(b), Value::Number(a)) => {
+                self.push(Value::Number(op(a, b)));
+                Ok(())
+            }
+            _ => Err(RloxError::Interpret(
+                RuntimeError::Arithmetic(message.into()),
+                None,
+                Vec::new(),
+            )),
+        }
+    }
+
+    fn compare(&mut self, op: fn(f64, f64) -> bool) -> Result<()> {
+        match (self.pop(), self.pop()) {
+            (Value::Number(b), Value::Number(a)) => {
+                self.push(Value::Bool(op(a, b)));
+                Ok(())
+            }
+            _ => Err(RloxError::Interpret(
+                RuntimeError::TypeComparison("Cannot compare types".into()),
+                None,
+                Vec::new(),
+            )),
+        }
+    }
+
+    //
+    // Helpers
+    //
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.proto.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let value = frame.proto.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_u16() as usize;
+        self.frame().constants[index]
+    }
+
+    // Names of globals, properties, classes and methods are always string constants
+    fn read_string(&mut self) -> ObjRef {
+        let constant = self.read_constant();
+        self.obj_ref(constant)
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn obj_ref(&self, value: Value) -> ObjRef {
+        match value {
+            Value::Obj(obj) => obj,
+            _ => unreachable!("expected an object"),
+        }
+    }
+
+    fn is_string(&self, value: Value) -> bool {
+        matches!(value, Value::Obj(obj) if matches!(self.heap.get(obj), Obj::String(_)))
+    }
+
+    fn class_of(&self, instance: ObjRef) -> ObjRef {
+        match self.heap.get(instance) {
+            Obj::Instance(instance) => instance.class,
+            _ => unreachable!("expected an instance object"),
+        }
+    }
+
+    // The compiler only ever gives class instructions classes
+    fn class_ref(&self, value: Value) -> Result<ObjRef> {
+        match value {
+            Value::Obj(obj) if matches!(self.heap.get(obj), Obj::Class(_)) => Ok(obj),
+            _ => Err(unexpected_value()),
+        }
+    }
+
+    fn instance_ref(&self, value: Value) -> Result<ObjRef> {
+        match value {
+            Value::Obj(obj) if matches!(self.heap.get(obj), Obj::Instance(_)) => Ok(obj),
+            other => Err(RloxError::Interpret(
+                RuntimeError::NotAnInstance(self.heap.display(other).to_string()),
+                None,
+                Vec::new(),
+            )),
+        }
+    }
+
+    // Where each active call was when the error happened, innermost first
+    fn backtrace(&self) -> Vec<Frame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = match frame.proto.name.as_str() {
+                    "" => "<script>",
+                    name => name,
+                };
+                Frame::new(
+                    function,
+                    frame.proto.chunk.lines[frame.ip.saturating_sub(1)],
+                )
+            })
+            .collect()
+    }
+}
+
+fn check_arity(arity: u8, argc: usize) -> Result<()> {
+    if arity as usize != argc {
+        return Err(RloxError::Interpret(
+            RuntimeError::MismatchedArguments(format!(
+                "Expected {arity} arguments, but got {argc}."
+            )),
+            None,
+            Vec::new(),
+        ));
+    }
+    Ok(())
+}
+
+fn not_callable(heap: &Heap, value: Value) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::NotACallableType(heap.display(value).to_string()),
+        None,
+        Vec::new(),
+    )
+}
+
+fn not_iterable(heap: &Heap, value: Value) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::NotIterable(heap.display(value).to_string()),
+        None,
+        Vec::new(),
+    )
+}
+
+fn not_indexable(heap: &Heap, value: Value) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::NotIndexable(heap.display(value).to_string()),
+        None,
+        Vec::new(),
+    )
+}
+
+fn undefined_property(heap: &Heap, name: ObjRef) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::UndefinedProperty(heap.string(name).to_string()),
+        None,
+        Vec::new(),
+    )
+}
+
+// Loading checks the shape of the bytecode, but not the types of the values
+// it works on, so instructions that rely on those report them instead.
+fn unexpected_value() -> RloxError {
+    RloxError::Bytecode(BytecodeError::UnexpectedValue)
+}
+
+fn unary_error(operator: &str) -> RloxError {
+    RloxError::Interpret(
+        RuntimeError::TypeComparison(format!(
+            "Cannot apply unary operator \"{operator}\" to expression."
+        )),
+        None,
+        Vec::new(),
+    )
+}
+
+//
+// Native functions
+//
+
+fn clock(_: &mut Vm, _: &[Value]) -> Result<Value> {
+    Ok(Value::Number(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as f64,
+    ))
+}
+
+fn print(vm: &mut Vm, arguments: &[Value]) -> Result<Value> {
+    println!("{}", vm.heap.display(arguments[0]));
+    Ok(Value::Nil)
+}
+
+// range(start, end) counts up from `start` to just before `end`
+fn range(vm: &mut Vm, arguments: &[Value]) -> Result<Value> {
+    let (next, end) = match arguments {
+        [Value::Number(start), Value::Number(end)] => (*start, *end),
+        [Value::Number(_), other] | [other, _] => {
+            return Err(RloxError::Interpret(
+                RuntimeError::InvalidRange(vm.heap.display(*other).to_string()),
+                None,
+                Vec::new(),
+            ))
+        }
+        _ => unreachable!("arity is checked before calling"),
+    };
+
+    let iterator = vm.alloc(Obj::Iterator(ObjIterator::Range { next, end }));
+    Ok(Value::Obj(iterator))
+}
+
+// Any value as the string print would show
+fn str(vm: &mut Vm, arguments: &[Value]) -> Result<Value> {
+    match arguments[0] {
+        value if vm.is_string(value) => Ok(value),
+        value => {
+            let text = vm.heap.display(value).to_string();
+            Ok(Value::Obj(vm.intern(&text)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk;
+    use crate::compiler;
+    use crate::parser::ast::Stmt;
+    use crate::scanner::Scanner;
+
+    fn run(source: &str) -> Result<Vm> {
+        run_with_gc(source, GcConfig::default())
+    }
+
+    fn run_with_gc(source: &str, config: GcConfig) -> Result<Vm> {
+        let mut scanner = Scanner::new();
+        scanner.scan_tokens(source)?;
+        let program: Result<Vec<Stmt>> = scanner.into_parser().parse().into_iter().collect();
+
+        let mut vm = Vm::with_gc_config(config);
+        vm.interpret(compiler::compile(&program?)?)?;
+        Ok(vm)
+    }
+
+    // Collect before every allocation, to catch objects the VM forgot to root
+    fn run_stressed(source: &str) -> Result<Vm> {
+        let stress = GcConfig {
+            stress: true,
+            ..GcConfig::default()
+        };
+        run_with_gc(source, stress)
+    }
+
+    fn global(vm: &mut Vm, name: &str) -> String {
+        let name = vm.heap.intern(name);
+        let value = vm.globals[&name];
+        vm.heap.display(value).to_string()
+    }
+
+    #[test]
+    fn arithmetic_and_strings() {
+        let mut vm =
+            run("var a = (1 + 2) * 3 - 4 / 2; var b = \"lo\" + \"x\"; var c = !(a > 5);").unwrap();
+        assert_eq!(global(&mut vm, "a"), "7");
+        assert_eq!(global(&mut vm, "b"), "lox");
+        assert_eq!(global(&mut vm, "c"), "false");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn string_interpolation() {
+        // Interpolated values are turned into strings while they're on the stack
+        let mut vm = run_stressed(
+            r#"
+            var name = "Ada";
+            var greeting = "Hello, ${name}!\tBorn ${1815}";
+            var nested = "${"[${[1, "two"]}]"}";
+            var escaped = "\${name} \"\u{1F600}\"";
+            "#,
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "greeting"), "Hello, Ada!\tBorn 1815");
+        assert_eq!(global(&mut vm, "nested"), "[[1, \"two\"]]");
+        assert_eq!(global(&mut vm, "escaped"), "${name} \"😀\"");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn string_methods() {
+        // New strings and split parts are allocated while the receiver is on the stack
+        let mut vm = run_stressed(
+            r#"
+            var text = "  Héllo, wörld 👋🏽  ".trim();
+            var length = text.length();
+            var sub = text.substring(7, 14);
+            var at = text.index_of("wörld");
+            var missing = text.index_of("x");
+            var parts = "a,b,,c".split(",");
+            var letters = "é👋🏽".split("");
+            var joined = ", ".join([1, "two", nil, [3]]);
+            var replaced = "a-b-c".replace("-", "+");
+            var cases = text.upper() + " " + text.lower();
+            var affixes = [text.starts_with("Hé"), text.ends_with("!")];
+            var repeated = "ab".repeat(3) + "x".repeat(0);
+            var numbers = ["42".to_number(), " -1.5 ".to_number(), "1e3".to_number()];
+            var strs = str(1.5) + str(nil) + str([true]) + str("s");
+            var upper = "abc".upper;
+            var bound = upper();
+            "#,
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "length"), "14");
+        assert_eq!(global(&mut vm, "sub"), "wörld 👋🏽");
+        assert_eq!(global(&mut vm, "at"), "7");
+        assert_eq!(global(&mut vm, "missing"), "-1");
+        assert_eq!(global(&mut vm, "parts"), "[\"a\", \"b\", \"\", \"c\"]");
+        assert_eq!(global(&mut vm, "letters"), "[\"é\", \"👋🏽\"]");
+        assert_eq!(global(&mut vm, "joined"), "1, two, nil, [3]");
+        assert_eq!(global(&mut vm, "replaced"), "a+b+c");
+        assert_eq!(global(&mut vm, "cases"), "HÉLLO, WÖRLD 👋🏽 héllo, wörld 👋🏽");
+        assert_eq!(global(&mut vm, "affixes"), "[true, false]");
+        assert_eq!(global(&mut vm, "repeated"), "ababab");
+        assert_eq!(global(&mut vm, "numbers"), "[42, -1.5, nil]");
+        assert_eq!(global(&mut vm, "strs"), "1.5nil[true]s");
+        assert_eq!(global(&mut vm, "bound"), "ABC");
+        assert!(vm.stack.is_empty());
+
+        assert!(matches!(
+            run("\"abc\".substring(2, 4);"),
+            Err(RloxError::Interpret(RuntimeError::IndexOutOfRange(..), ..))
+        ));
+        assert!(matches!(
+            run("\"abc\".index_of(1);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidArgument(expected, _), ..)) if expected == "a string"
+        ));
+        assert!(matches!(
+            run("\"ab\".repeat(-1);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidArgument(..), ..))
+        ));
+        assert!(matches!(
+            run("\"ab\".repeat(10000000000000000000);"),
+            Err(RloxError::Interpret(RuntimeError::InvalidArgument(expected, _), ..)) if expected == "a smaller count"
+        ));
+        assert!(matches!(
+            run("\"abc\".reverse();"),
+            Err(RloxError::Interpret(RuntimeError::UndefinedProperty(_), ..))
+        ));
+    }
+
+    #[test]
+    fn control_flow() {
+        let mut vm = run(r#"
+            var total = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                if (i == 5 or i == 7) total = total + 100;
+                else total = total + i;
+            }
+            var short = nil and undefined;
+            "#)
+        .unwrap();
+        assert_eq!(global(&mut vm, "total"), "233");
+        assert_eq!(global(&mut vm, "short"), "nil");
+    }
+
+    #[test]
+    fn recursive_functions() {
+        let mut vm = run(r#"
+            fun fib(n) {
+                if (n <= 1) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            var result = fib(15);
+            "#)
+        .unwrap();
+        assert_eq!(global(&mut vm, "result"), "610");
+    }
+
+    #[test]
+    fn classes_and_methods() {
+        let mut vm = run(r#"
+            class A {
+                name() { return "A"; }
+            }
+            class B < A {
+                init(x) { this.x = x; }
+                get() { return this.x; }
+            }
+            var b = B(10);
+            var x = b.get();
+            var bound = b.get;
+            var y = bound();
+            var inherited = b.name();
+            "#)
+        .unwrap();
+        assert_eq!(global(&mut vm, "x"), "10");
+        assert_eq!(global(&mut vm, "y"), "10");
+        assert_eq!(global(&mut vm, "inherited"), "A");
+        assert_eq!(global(&mut vm, "b"), "<B instance>");
+        assert_eq!(global(&mut vm, "bound"), "<fn get#0()>");
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let mut vm = run(r#"
+            fun makeCounter() {
+                var i = 0;
+                fun increment() { i = i + 1; return i; }
+                fun get() { return i; }
+                var counter = Counter();
+                counter.increment = increment;
+                counter.get = get;
+                return counter;
+            }
+            class Counter {}
+
+            var a = makeCounter();
+            var b = makeCounter();
+            a.increment();
+            a.increment();
+            b.increment();
+            var countA = a.get();
+            var countB = b.get();
+
+            // Each iteration's block gets its own variable
+            var first;
+            var second;
+            for (var i = 0; i < 2; i = i + 1) {
+                var j = i;
+                fun capture() { return j; }
+                if (i == 0) first = capture; else second = capture;
+            }
+            var firstJ = first();
+            var secondJ = second();
+
+            fun outer() {
+                var x = "outer";
+                fun middle() {
+                    fun inner() { return x; }
+                    return inner;
+                }
+                return middle();
+            }
+            var nested = outer()();
+            "#)
+        .unwrap();
+        assert_eq!(global(&mut vm, "countA"), "2");
+        assert_eq!(global(&mut vm, "countB"), "1");
+        assert_eq!(global(&mut vm, "firstJ"), "0");
+        assert_eq!(global(&mut vm, "secondJ"), "1");
+        assert_eq!(global(&mut vm, "nested"), "outer");
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn super_calls() {
+        let mut vm = run(r#"
+            class A {
+                method() { return "A method"; }
+            }
+            class B < A {
+                method() { return "B method"; }
+                direct() { return super.method(); }
+                bound() { var m = super.method; return m(); }
+                nested() {
+                    fun f() { return super.method() + " from " + this.method(); }
+                    return f();
+                }
+            }
+            var b = B();
+            var direct = b.direct();
+            var bound = b.bound();
+            var nested = b.nested();
+            "#)
+        .unwrap();
+        assert_eq!(global(&mut vm, "direct"), "A method");
+        assert_eq!(global(&mut vm, "bound"), "A method");
+        assert_eq!(global(&mut vm, "nested"), "A method from B method");
+    }
+
+    #[test]
+    fn break_and_continue() {
+        // Breaking out of nested scopes pops their locals
+        let mut vm = run(r#"
+            var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                if (i == 2) continue;
+                if (i == 5) break;
+                sum = sum + i;
+            }
+            var steps = 0;
+            while (true) {
+                var inner = steps;
+                steps = steps + 1;
+                { var nested = inner; if (nested >= 3) break; }
+            }
+            var after = "after";
+            "#)
+        .unwrap();
+        assert_eq!(global(&mut vm, "sum"), "8");
+        assert_eq!(global(&mut vm, "steps"), "4");
+        assert_eq!(global(&mut vm, "after"), "after");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn for_in_loops() {
+        // The hidden locals keep what's being iterated alive
+        let mut vm = run_stressed(
+            r#"
+            var list = [];
+            for (x in [1, nil, 3]) list.push(x);
+            var keys = "";
+            for (key in {"a": 1, "b": 2}) keys = keys + key;
+            var graphemes = [];
+            for (c in "é👋🏽") graphemes.push(c);
+            var sum = 0;
+            for (i in range(0, 10)) {
+                if (i == 2) continue;
+                if (i == 5) break;
+                sum = sum + i;
+            }
+
+            fun countdown(n) {
+                return fun () {
+                    n = n - 1;
+                    if (n < 0) return nil;
+                    return n;
+                };
+            }
+            var counted = [];
+            for (n in countdown(3)) counted.push(n);
+
+            class Bag {
+                init() { this.items = ["x", "y"]; }
+                iterator() { return this.items; }
+            }
+            var bagged = [];
+            for (item in Bag()) bagged.push(item);
+
+            var closures = [];
+            for (x in ["a", "b"]) closures.push(fun () { return x; });
+            var captured = closures[0]() + closures[1]();
+
+            fun first(items) {
+                for (item in items) return item;
+            }
+            var found = first(["found"]);
+            "#,
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "list"), "[1, nil, 3]");
+        assert_eq!(global(&mut vm, "keys"), "ab");
+        assert_eq!(global(&mut vm, "graphemes"), "[\"é\", \"👋🏽\"]");
+        assert_eq!(global(&mut vm, "sum"), "8");
+        assert_eq!(global(&mut vm, "counted"), "[2, 1, 0]");
+        assert_eq!(global(&mut vm, "bagged"), "[\"x\", \"y\"]");
+        assert_eq!(global(&mut vm, "captured"), "ab");
+        assert_eq!(global(&mut vm, "found"), "found");
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(
+            run("for (x in 3) x;").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::NotIterable("3".to_string()),
+                Some(Span::line(1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        // Caught errors are built while unwinding, and must survive collection
+        let mut vm = run_stressed(
+            r#"
+            var message;
+            var line;
+            try {
+                nil.field;
+            } catch (e) {
+                message = e.message;
+                line = e.line;
+            }
+
+            fun risky(value) {
+                var local = "kept";
+                try {
+                    if (value) throw value;
+                    return local;
+                } finally {
+                    cleaned = cleaned + 1;
+                }
+            }
+            var cleaned = 0;
+            var caught;
+            try { risky("thrown"); } catch (e) { caught = e; }
+            var returned = risky(nil);
+
+            var visited = [];
+            for (i in range(0, 3)) {
+                var before = i;
+                try {
+                    if (i == 1) continue;
+                    visited.push(before);
+                } finally {
+                    visited.push("f");
+                }
+            }
+
+            fun deep(n) {
+                if (n == 0) return [].pop();
+                return deep(n - 1);
+            }
+            var unwound;
+            try { deep(50); } catch (e) { unwound = e.message; }
+            var after = [1, 2].pop();
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            global(&mut vm, "message"),
+            "Only instances have properties, not nil."
+        );
+        assert_eq!(global(&mut vm, "line"), "5");
+        assert_eq!(global(&mut vm, "caught"), "thrown");
+        assert_eq!(global(&mut vm, "returned"), "kept");
+        assert_eq!(global(&mut vm, "cleaned"), "2");
+        assert_eq!(global(&mut vm, "visited"), "[0, \"f\", \"f\", 2, \"f\"]");
+        assert_eq!(global(&mut vm, "unwound"), "Can't pop from an empty list.");
+        assert_eq!(global(&mut vm, "after"), "2");
+        assert!(vm.handlers.is_empty());
+
+        assert_eq!(
+            run("throw 42;").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::Thrown("42".to_string()),
+                Some(Span::line(1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+
+        // Throwing a caught error again keeps where it happened
+        assert_eq!(
+            run("try {\n  [].pop();\n} catch (e) {\n  throw e;\n}").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::EmptyList,
+                Some(Span::line(2)),
+                vec![Frame::new("<script>", 2)]
+            ))
+        );
+    }
+
+    #[test]
+    fn anonymous_functions() {
+        let mut vm = run(r#"
+            fun apply(f, x) { return f(x); }
+            var doubled = apply(fun (n) { return n * 2; }, 21);
+            fun adder(n) { return fun (x) { return x + n; }; }
+            var added = adder(5)(10);
+            var immediate = fun () { return "called"; }();
+            var lambda = fun () {};
+            fun (unused) {};
+            "#)
+        .unwrap();
+        assert_eq!(global(&mut vm, "doubled"), "42");
+        assert_eq!(global(&mut vm, "added"), "15");
+        assert_eq!(global(&mut vm, "immediate"), "called");
+        assert_eq!(global(&mut vm, "lambda"), "<fn lambda@7:26#0()>");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn lists() {
+        // Lists keep their elements alive, even when collecting constantly
+        let mut vm = run_stressed(
+            r#"
+            var xs = [1, 2, 3];
+            xs[1] = "two";
+            xs.push([4]);
+            var popped = xs.pop();
+            xs.insert(0, nil);
+            var removed = xs.remove(1);
+            var sliced = xs.slice(1, 3);
+            var length = xs.length();
+            var nested = popped[0];
+            var empty = xs.slice(2, 1);
+
+            var next = sliced.iterator();
+            var first = next();
+            var second = next();
+            var done = next();
+
+            var push = xs.push;
+            push("late" + "r");
+            var same = xs == xs;
+            var different = [] == [];
+            "#,
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "xs"), "[nil, \"two\", 3, \"later\"]");
+        assert_eq!(global(&mut vm, "popped"), "[4]");
+        assert_eq!(global(&mut vm, "removed"), "1");
+        assert_eq!(global(&mut vm, "sliced"), "[\"two\", 3]");
+        assert_eq!(global(&mut vm, "length"), "3");
+        assert_eq!(global(&mut vm, "nested"), "4");
+        assert_eq!(global(&mut vm, "empty"), "[]");
+        assert_eq!(global(&mut vm, "first"), "two");
+        assert_eq!(global(&mut vm, "second"), "3");
+        assert_eq!(global(&mut vm, "done"), "nil");
+        assert_eq!(global(&mut vm, "same"), "true");
+        assert_eq!(global(&mut vm, "different"), "false");
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(
+            run("var xs = [1, 2];\nxs[2];").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::IndexOutOfRange("2".to_string(), 2),
+                Some(Span::line(2)),
+                vec![Frame::new("<script>", 2)]
+            ))
+        );
+    }
+
+    #[test]
+    fn maps() {
+        // Maps keep their keys and values alive, even when collecting constantly
+        let mut vm = run_stressed(
+            r#"
+            var ages = {"ada": 36, "alan": 41};
+            ages["grace"] = 85;
+            ages["ada"] = 37;
+            var ada = ages["ada"];
+            var length = ages.length();
+            var has = ages.has("alan");
+            var removed = ages.remove("alan");
+            var keys = ages.keys();
+            var values = ages.values();
+            var entries = ages.entries();
+
+            var next = ages.iterator();
+            var first = next();
+            var second = next();
+            var done = next();
+
+            var mixed = {nil: 1, true: 2, 0: 3, "0": 4};
+            var zero = mixed[-0];
+            var nested = {"list": [1, {"deep": true}], "empty": {}};
+            var different = {} == {};
+            "#,
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "ages"), "{\"ada\": 37, \"grace\": 85}");
+        assert_eq!(global(&mut vm, "ada"), "37");
+        assert_eq!(global(&mut vm, "length"), "3");
+        assert_eq!(global(&mut vm, "has"), "true");
+        assert_eq!(global(&mut vm, "removed"), "41");
+        assert_eq!(global(&mut vm, "keys"), "[\"ada\", \"grace\"]");
+        assert_eq!(global(&mut vm, "values"), "[37, 85]");
+        assert_eq!(
+            global(&mut vm, "entries"),
+            "[[\"ada\", 37], [\"grace\", 85]]"
+        );
+        assert_eq!(global(&mut vm, "first"), "ada");
+        assert_eq!(global(&mut vm, "second"), "grace");
+        assert_eq!(global(&mut vm, "done"), "nil");
+        assert_eq!(
+            global(&mut vm, "mixed"),
+            "{nil: 1, true: 2, 0: 3, \"0\": 4}"
+        );
+        assert_eq!(global(&mut vm, "zero"), "3");
+        assert_eq!(
+            global(&mut vm, "nested"),
+            "{\"list\": [1, {\"deep\": true}], \"empty\": {}}"
+        );
+        assert_eq!(global(&mut vm, "different"), "false");
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(
+            run("var m = {\"a\": 1};\nm[\"b\"];").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::UndefinedKey("\"b\"".to_string()),
+                Some(Span::line(2)),
+                vec![Frame::new("<script>", 2)]
+            ))
+        );
+        assert!(matches!(
+            run("var m = {}; m[[1]] = 2;"),
+            Err(RloxError::Interpret(RuntimeError::UnhashableKey(_), ..))
+        ));
+    }
+
+    #[test]
+    fn runtime_errors() {
+        assert_eq!(
+            run("undefined;").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::UndefinedVariable("undefined".to_string()),
+                Some(Span::line(1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+        assert_eq!(
+            run("fun f(a) {} f();").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::MismatchedArguments("Expected 1 arguments, but got 0.".to_string()),
+                Some(Span::line(1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+        assert_eq!(
+            run("1 + \"a\";").err(),
+            Some(RloxError::Interpret(
+                RuntimeError::Concatenation("Cannot concatenate types".to_string()),
+                Some(Span::line(1)),
+                vec![Frame::new("<script>", 1)]
+            ))
+        );
+
+        let err = run("fun f() { f(); } f();").err().unwrap();
+        assert!(matches!(
+            err,
+            RloxError::Interpret(RuntimeError::StackOverflow, Some(_), _)
+        ));
+        assert_eq!(err.trace().len(), FRAMES_MAX);
+        assert_eq!(err.trace()[0], Frame::new("f", 1));
+        assert_eq!(err.trace().last(), Some(&Frame::new("<script>", 1)));
+        assert!(err
+            .render("test.lox", "")
+            .contains("    at f (test.lox:1)\n    ... 1004 more calls\n    at f (test.lox:1)\n"));
+    }
+
+    #[test]
+    fn runtime_error_backtrace() {
+        let err =
+            run("fun inner(x) {\n  return x + nil;\n}\nfun outer() {\n  inner(1);\n}\nouter();")
+                .err()
+                .unwrap();
+        assert_eq!(
+            err.trace(),
+            &[
+                Frame::new("inner", 2),
+                Frame::new("outer", 5),
+                Frame::new("<script>", 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn stress_gc_keeps_live_objects() {
+        let mut vm = run_stressed(
+            r#"
+            class Node {
+                init(value, next) {
+                    this.value = value;
+                    this.next = next;
+                }
+                describe() { return "node " + this.value; }
+            }
+            var list = nil;
+            var i = 0;
+            while (i < 50) {
+                list = Node("v", list);
+                var garbage = "temp" + "orary";
+                i = i + 1;
+            }
+            var description = list.next.describe();
+            var method = list.describe;
+            var bound = method();
+
+            fun makeAdder(n) {
+                fun add(x) { return x + n; }
+                return add;
+            }
+            var adder = nil;
+            var j = 0;
+            while (j < 20) {
+                adder = makeAdder("!" + "?");
+                var garbage = makeAdder("garbage");
+                j = j + 1;
+            }
+            var added = adder("o" + "k");
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(global(&mut vm, "description"), "node v");
+        assert_eq!(global(&mut vm, "bound"), "node v");
+        assert_eq!(global(&mut vm, "added"), "ok!?");
+    }
+
+    #[test]
+    fn malformed_bytecode_is_an_error() {
+        // Loading can't tell that a method is being added to nil
+        let mut script = Function::default();
+        let name = script.chunk.add_constant(Constant::String("method".into()));
+        script.chunk.write_op(OpCode::Nil, 1);
+        script.chunk.write_op(OpCode::Nil, 1);
+        script.chunk.write_op(OpCode::Method, 1);
+        script.chunk.write_u16(name as u16, 1);
+        script.chunk.write_op(OpCode::Return, 1);
+        let script = chunk::deserialize(&chunk::serialize(&script)).unwrap();
+
+        let err = Vm::new().interpret(script).err().unwrap();
+        assert_eq!(err, RloxError::Bytecode(BytecodeError::UnexpectedValue));
+    }
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let config = GcConfig {
+            initial_threshold: 0,
+            ..GcConfig::default()
+        };
+        let vm = run_with_gc(
+            r#"
+            class Garbage {}
+            var i = 0;
+            while (i < 1000) {
+                var garbage = Garbage();
+                garbage.field = Garbage();
+                i = i + 1;
+            }
+            "#,
+            config,
+        )
+        .unwrap();
+
+        // Only a handful of objects survive: the script's strings, the class and natives
+        assert!(
+            vm.heap.len() < 50,
+            "{} objects are still live",
+            vm.heap.len()
+        );
+    }
+}